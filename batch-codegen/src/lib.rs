@@ -6,16 +6,18 @@
 
 #![doc(html_root_url = "https://docs.rs/batch-codegen/0.1.0")]
 #![deny(missing_debug_implementations)]
-#![recursion_limit = "128"]
+#![recursion_limit = "256"]
 
 extern crate proc_macro;
 extern crate proc_macro2;
 #[macro_use]
 extern crate quote;
+#[macro_use]
 extern crate syn;
 
 use proc_macro::TokenStream as StdTokenStream;
 use proc_macro2::{Span, TokenStream};
+use syn::spanned::Spanned;
 use syn::{DeriveInput, Ident, Lit, Meta};
 
 /// Macros 1.1 implementation of `#[derive(Job)]`
@@ -25,6 +27,11 @@ use syn::{DeriveInput, Ident, Lit, Meta};
 /// * `job_name`: a unique ID for the job.
 ///   e.g: `#[job_name = "batch-rs:send-confirmation-email"]`
 ///   **default value**: The derived struct name
+/// * `job_aliases`: Comma-separated list of the former names of the job. Workers also execute
+///   the jobs published with these names, so that renaming a job doesn't orphan the jobs already
+///   in the queues.
+///   e.g: `#[job_aliases = "batch-rs:send-confirmation-email-v1"]`
+///   **default value**: `""`
 /// * `job_exchange`: the exchange this job will be published to.
 ///   e.g: `#[job_exchange = "batch.example"]`
 ///   **default value**: `""`
@@ -33,52 +40,127 @@ use syn::{DeriveInput, Ident, Lit, Meta};
 /// * `job_timeout`: Number of seconds available for the job to execute. If the time limit is
 ///   exceeded, the job's process is killed and the job is marked as failed.
 ///   e.g: `#[job_timeout = "120"]`
-///   **default value**: `900` (15 minutes)
+///   **default value**: the default timeout of the queue the job is consumed from, or `900`
+///   (15 minutes)
 /// * `job_retries`: Number of times the job should be retried in case of error.
 ///   e.g: `#[job_retries = "5"]`
-///   **default value**: `2`
-/// * `job_priority`: The priority associated to the job
-///   e.g: `#[job_priority = "critical"]`
+///   **default value**: the default retries of the queue the job is consumed from, or `2`
+/// * `job_priority`: The priority associated to the job, either a named priority or a number
+///   between 0 and 255.
+///   e.g: `#[job_priority = "critical"]` or `#[job_priority = "10"]`
 ///   **default value**: `"normal"`
+/// * `job_env`: Comma-separated list of environment variables required by the job. When set,
+///   the job's process only receives these variables, plus a baseline such as `PATH`, `HOME`,
+///   `TMPDIR`, `LD_LIBRARY_PATH` & `RUST_LOG`.
+///   e.g: `#[job_env = "AWS_REGION, AWS_ACCESS_KEY_ID"]`
+///   **default value**: `""` (the job's process inherits the worker's environment)
+///
+/// * `job_redact`: Comma-separated list of fields holding sensitive data, masked when the job is
+///   logged with `batch::redact` or by a worker. Fields are named as in Rust, and the serde
+///   `rename` and `rename_all` attributes are taken into account.
+///   e.g: `#[job_redact = "password, api_token"]`
+///   **default value**: `""`
+/// * `job_sandbox`: Comma-separated list of the restrictions applied to the job's process on
+///   Linux, see `batch::Sandbox`. The only restriction available is `no_network`, forbidding the
+///   creation of sockets other than Unix domain sockets.
+///   e.g: `#[job_sandbox = "no_network"]`
+///   **default value**: `""` (the job's process isn't sandboxed)
+///
+/// The name, aliases, exchange & routing key can contain a `{prefix}` placeholder, replaced at
+/// runtime with the value of the `BATCH_PREFIX` environment variable, see `batch::interpolate`.
+///
+/// Invalid attributes are reported at once, each at its location, along with the closest valid
+/// name when one is misspelled.
+///
+/// Jobs are deserialized from the broker's messages in the worker, so they can't borrow data:
+/// deriving `Job` on a struct with lifetime parameters or reference fields fails with an error
+/// pointing at the offending field, and suggesting an owned type (e.g. `String` for `&str`,
+/// `Vec<u8>` for `&[u8]`).
+///
+/// Jobs can have type parameters, e.g. for a type only used by their handler and skipped when
+/// serializing them. All the instantiations of a generic job share the same name, so workers
+/// can't tell them apart: generic jobs must set `job_name` explicitly, and instantiations with
+/// different payloads (e.g. `Index<String>` & `Index<u64>`) need distinct types. The
+/// implementation of `Job` requires them to be serializable & deserializable.
 #[proc_macro_derive(
-    Job, attributes(job_name, job_exchange, job_routing_key, job_timeout, job_retries, job_priority)
+    Job,
+    attributes(
+        job_name,
+        job_aliases,
+        job_exchange,
+        job_routing_key,
+        job_timeout,
+        job_retries,
+        job_priority,
+        job_env,
+        job_redact,
+        job_sandbox
+    )
 )]
 pub fn task_derive(input: StdTokenStream) -> StdTokenStream {
     let input: DeriveInput = syn::parse(input.into()).unwrap();
+    let mut errors = Errors::default();
+    check_attributes(&input, &mut errors);
+    check_borrows(&input, &mut errors);
+    check_generic_name(&input, &mut errors);
+    check_redacted_fields(&input, &mut errors);
+    if !errors.is_empty() {
+        return errors.into_tokens().into();
+    }
     let job_name = get_derive_name_attr(&input);
+    let job_aliases = get_list_attr_by_name(&input.attrs, "job_aliases");
     let job_exchange = get_derive_exchange_attr(&input);
     let job_routing_key = get_derive_routing_key_attr(&input);
     let job_timeout = get_derive_timeout_attr(&input);
     let job_retries = get_derive_retries_attr(&input);
     let job_priority = get_derive_priority_attr(&input);
+    let job_env = get_derive_env_attr(&input);
+    let job_redact = get_derive_redact_attr(&input);
+    let job_sandbox = get_derive_sandbox_attr(&input);
+    let overrides_timeout = get_str_attr_by_name(&input.attrs, "job_timeout").is_some();
+    let overrides_retries = get_str_attr_by_name(&input.attrs, "job_retries").is_some();
     let name = &input.ident;
     let impl_block_name = gen_derive_impl_block_name(name.to_string());
+    let generics = gen_derive_generics(&input);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
     let expanded = quote! {
         #[allow(non_upper_case_globals)]
         const #impl_block_name: () =
         {
             extern crate batch as _batch;
+            extern crate serde as _serde;
 
             use ::std::string::String;
             use ::std::option::Option;
             use ::std::time::Duration;
+            use ::std::vec::Vec;
 
             lazy_static! {
-                static ref _BATCH_JOB_NAME: String = #job_name.replace("::", ".");
+                static ref _BATCH_JOB_NAME: String = _batch::interpolate(#job_name)
+                    .replace("::", ".");
+                static ref _BATCH_JOB_ALIASES: Vec<String> = vec![
+                    #(_batch::interpolate(#job_aliases).replace("::", ".")),*
+                ];
+                static ref _BATCH_JOB_EXCHANGE: String = _batch::interpolate(#job_exchange);
+                static ref _BATCH_JOB_ROUTING_KEY: String = _batch::interpolate(#job_routing_key);
             }
 
-            impl _batch::Job for #name {
+            impl #impl_generics _batch::Job for #name #ty_generics #where_clause {
                 fn name() -> &'static str {
                     _BATCH_JOB_NAME.as_ref()
                 }
 
                 fn exchange() -> &'static str {
-                    #job_exchange
+                    _BATCH_JOB_EXCHANGE.as_ref()
                 }
 
                 fn routing_key() -> &'static str {
-                    #job_routing_key
+                    _BATCH_JOB_ROUTING_KEY.as_ref()
+                }
+
+                fn aliases() -> &'static [String] {
+                    &_BATCH_JOB_ALIASES
                 }
 
                 fn timeout() -> Option<Duration> {
@@ -92,12 +174,297 @@ pub fn task_derive(input: StdTokenStream) -> StdTokenStream {
                 fn priority() -> _batch::Priority {
                     #job_priority
                 }
+
+                fn overrides_queue_timeout() -> bool {
+                    #overrides_timeout
+                }
+
+                fn overrides_queue_retries() -> bool {
+                    #overrides_retries
+                }
+
+                fn environment() -> &'static [&'static str] {
+                    #job_env
+                }
+
+                fn redacted_fields() -> &'static [&'static str] {
+                    #job_redact
+                }
+
+                fn sandbox() -> _batch::Sandbox {
+                    #job_sandbox
+                }
             }
         };
     };
     expanded.into()
 }
 
+/// Names of the attributes supported by `#[derive(Job)]`.
+const ATTRIBUTES: &[&str] = &[
+    "job_name",
+    "job_aliases",
+    "job_exchange",
+    "job_routing_key",
+    "job_timeout",
+    "job_retries",
+    "job_priority",
+    "job_env",
+    "job_redact",
+    "job_sandbox",
+];
+
+/// Names of the priorities accepted by the `job_priority` attribute.
+const PRIORITIES: &[&str] = &["trivial", "low", "normal", "high", "critical"];
+
+/// Names of the restrictions accepted by the `job_sandbox` attribute.
+const RESTRICTIONS: &[&str] = &["no_network"];
+
+/// The problems found in a derived struct, all reported at once.
+#[derive(Debug, Default)]
+struct Errors(Vec<TokenStream>);
+
+impl Errors {
+    /// Report an error at the given span.
+    fn push(&mut self, span: Span, message: String) {
+        self.0.push(quote_spanned! { span =>
+            compile_error!(#message);
+        });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn into_tokens(self) -> TokenStream {
+        let errors = self.0;
+        quote! { #(#errors)* }
+    }
+}
+
+/// Report the misspelled attributes of the given struct, and the invalid values of its attributes.
+fn check_attributes(input: &DeriveInput, errors: &mut Errors) {
+    let mut seen = Vec::new();
+    for attr in &input.attrs {
+        let meta = match attr.parse_meta() {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        let ident = match meta.path().get_ident() {
+            Some(ident) => ident.clone(),
+            None => continue,
+        };
+        let name = ident.to_string();
+        if !ATTRIBUTES.contains(&name.as_ref()) {
+            if let Some(suggestion) = suggest(&name, ATTRIBUTES) {
+                let message =
+                    format!("Unknown attribute `{}`, did you mean `{}`?", name, suggestion);
+                errors.push(ident.span(), message);
+            } else if name.starts_with("job_") {
+                let message = format!(
+                    "Unknown attribute `{}`, expected one of: {}",
+                    name,
+                    ATTRIBUTES.join(", ")
+                );
+                errors.push(ident.span(), message);
+            }
+            continue;
+        }
+        if seen.contains(&name) {
+            errors.push(ident.span(), format!("Duplicate attribute `{}`", name));
+            continue;
+        }
+        seen.push(name.clone());
+        let value = match meta {
+            Meta::NameValue(syn::MetaNameValue {
+                lit: Lit::Str(ref value),
+                ..
+            }) => value.clone(),
+            _ => {
+                let message = format!("Expected a string, e.g. `#[{} = \"...\"]`", name);
+                errors.push(ident.span(), message);
+                continue;
+            }
+        };
+        if let Some(message) = check_attribute_value(&name, &value.value()) {
+            errors.push(value.span(), message);
+        }
+    }
+    if get_str_attr_by_name(&input.attrs, "job_routing_key").is_none() {
+        let message = format!(
+            "Job `{}` needs a routing key, e.g. `#[job_routing_key = \"...\"]`",
+            input.ident
+        );
+        errors.push(input.ident.span(), message);
+    }
+}
+
+/// Return the error message describing why the value of the given attribute is invalid, if it
+/// is.
+fn check_attribute_value(name: &str, value: &str) -> Option<String> {
+    match name {
+        "job_timeout" if value.parse::<u64>().is_err() => Some(format!(
+            "Invalid timeout `{}`, expected a number of seconds",
+            value
+        )),
+        "job_retries" if value.parse::<u32>().is_err() => Some(format!(
+            "Invalid number of retries `{}`, expected an unsigned integer",
+            value
+        )),
+        "job_priority" => {
+            let priority = value.to_lowercase();
+            if PRIORITIES.contains(&priority.as_ref()) || priority.parse::<u8>().is_ok() {
+                return None;
+            }
+            let message = match suggest(&priority, PRIORITIES) {
+                Some(suggestion) => format!(
+                    "Invalid priority `{}`, did you mean `{}`?",
+                    value, suggestion
+                ),
+                None => format!(
+                    "Invalid priority `{}`, must be one of: {}, or a number between 0 and 255",
+                    value,
+                    PRIORITIES.join(", ")
+                ),
+            };
+            Some(message)
+        }
+        "job_sandbox" => {
+            let restriction = value
+                .split(',')
+                .map(str::trim)
+                .filter(|restriction| !restriction.is_empty())
+                .find(|restriction| !RESTRICTIONS.contains(restriction))?;
+            let message = match suggest(restriction, RESTRICTIONS) {
+                Some(suggestion) => format!(
+                    "Invalid sandbox restriction `{}`, did you mean `{}`?",
+                    restriction, suggestion
+                ),
+                None => format!(
+                    "Invalid sandbox restriction `{}`, must be one of: {}",
+                    restriction,
+                    RESTRICTIONS.join(", ")
+                ),
+            };
+            Some(message)
+        }
+        _ => None,
+    }
+}
+
+/// Report the fields of the given struct borrowing data, which jobs can't do.
+fn check_borrows(input: &DeriveInput, errors: &mut Errors) {
+    if let syn::Data::Struct(ref data) = input.data {
+        for field in data.fields.iter() {
+            if let syn::Type::Reference(ref reference) = field.ty {
+                let name = field
+                    .ident
+                    .as_ref()
+                    .map_or_else(|| "A field".to_string(), |ident| format!("Field `{}`", ident));
+                let message = format!(
+                    "{} of job `{}` can't borrow data, use an owned type instead (e.g. {})",
+                    name,
+                    input.ident,
+                    owned_suggestion(&reference.elem)
+                );
+                errors.push(field.ty.span(), message);
+            }
+        }
+    }
+    for lifetime in input.generics.lifetimes() {
+        let message = format!(
+            "Job `{}` can't have lifetime parameters, use owned types for its fields",
+            input.ident
+        );
+        errors.push(lifetime.span(), message);
+    }
+}
+
+/// Report the type parameters of a job without an explicit name, which all its instantiations
+/// would share without noticing.
+fn check_generic_name(input: &DeriveInput, errors: &mut Errors) {
+    if get_raw_attr_by_name(&input.attrs, "job_name").is_some() {
+        return;
+    }
+    if let Some(param) = input.generics.type_params().next() {
+        let message = format!(
+            "Job `{}` has type parameters, and all its instantiations share the same name: set it \
+             with `#[job_name = \"...\"]`, or use a distinct type per payload",
+            input.ident
+        );
+        errors.push(param.span(), message);
+    }
+}
+
+/// Report the redacted fields which aren't named fields of the given struct.
+fn check_redacted_fields(input: &DeriveInput, errors: &mut Errors) {
+    let names = match input.data {
+        syn::Data::Struct(ref data) => data
+            .fields
+            .iter()
+            .filter_map(|field| field.ident.as_ref().map(|ident| ident.to_string()))
+            .collect::<Vec<_>>(),
+        _ => Vec::new(),
+    };
+    let span = match get_raw_attr_by_name(&input.attrs, "job_redact") {
+        Some(lit) => lit.span(),
+        None => return,
+    };
+    for field in get_list_attr_by_name(&input.attrs, "job_redact") {
+        if names.contains(&field) {
+            continue;
+        }
+        let candidates = names.iter().map(String::as_ref).collect::<Vec<_>>();
+        let message = match suggest(&field, &candidates) {
+            Some(suggestion) => format!(
+                "Job `{}` has no field `{}` to redact, did you mean `{}`?",
+                input.ident, field, suggestion
+            ),
+            None => format!("Job `{}` has no field `{}` to redact", input.ident, field),
+        };
+        errors.push(span, message);
+    }
+}
+
+/// Return the candidate closest to the given misspelled name, if any is close enough.
+fn suggest<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|candidate| (distance(name, candidate), *candidate))
+        .filter(|&(distance, candidate)| distance <= (candidate.len() + 2) / 3)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Return the Levenshtein distance between two strings.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..b.len() + 1).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + if a == *b { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Suggest an owned type to replace a reference to the given type.
+fn owned_suggestion(elem: &syn::Type) -> String {
+    match *elem {
+        syn::Type::Path(ref path) if quote! { #path }.to_string() == "str" => {
+            "`String`".to_string()
+        }
+        syn::Type::Slice(ref slice) => {
+            let elem = &slice.elem;
+            format!("`Vec<{}>`", quote! { #elem })
+        }
+        _ => "a `String`, `Vec<T>` or `Box<T>`".to_string(),
+    }
+}
+
 fn get_derive_name_attr(input: &DeriveInput) -> TokenStream {
     if let Some(raw) = get_str_attr_by_name(&input.attrs, "job_name") {
         quote! { #raw }
@@ -120,7 +487,7 @@ fn get_derive_exchange_attr(input: &DeriveInput) -> TokenStream {
 fn get_derive_routing_key_attr(input: &DeriveInput) -> TokenStream {
     let attr = {
         let raw = get_str_attr_by_name(&input.attrs, "job_routing_key");
-        raw.expect("job_routing_key is a mandatory attribute when deriving Job")
+        raw.unwrap_or_else(|| "".to_string())
     };
     quote! { #attr }
 }
@@ -130,8 +497,7 @@ fn get_derive_timeout_attr(input: &DeriveInput) -> TokenStream {
         let raw = get_str_attr_by_name(&input.attrs, "job_timeout");
         raw.unwrap_or_else(|| "900".to_string())
     };
-    let timeout = attr.parse::<u64>()
-        .expect("Couldn't parse timeout as an unsigned integer");
+    let timeout = attr.parse::<u64>().unwrap_or(900);
     quote! {
         Option::Some(Duration::from_secs(#timeout))
     }
@@ -142,8 +508,7 @@ fn get_derive_retries_attr(input: &DeriveInput) -> TokenStream {
         let raw = get_str_attr_by_name(&input.attrs, "job_retries");
         raw.unwrap_or_else(|| "2".to_string())
     };
-    let retries = attr.parse::<u32>()
-        .expect("Couldn't parse retries as an unsigned integer");
+    let retries = attr.parse::<u32>().unwrap_or(2);
     quote! {
         #retries
     }
@@ -160,10 +525,101 @@ fn get_derive_priority_attr(input: &DeriveInput) -> TokenStream {
         "normal" => quote! { _batch::Priority::Normal },
         "high" => quote! { _batch::Priority::High },
         "critical" => quote! { _batch::Priority::Critical },
-        _ => {
-            panic!("Invalid priority, must be one of: trivial, low, normal, high, critical.");
+        other => match other.parse::<u8>() {
+            Ok(priority) => quote! { _batch::Priority::Custom(#priority) },
+            Err(_) => quote! { _batch::Priority::Normal },
+        },
+    }
+}
+
+fn get_derive_env_attr(input: &DeriveInput) -> TokenStream {
+    let vars = get_list_attr_by_name(&input.attrs, "job_env");
+    quote! {
+        &[#(#vars),*]
+    }
+}
+
+fn get_derive_redact_attr(input: &DeriveInput) -> TokenStream {
+    let fields = get_list_attr_by_name(&input.attrs, "job_redact")
+        .into_iter()
+        .map(|field| get_serialized_field_name(input, &field));
+    quote! {
+        &[#(#fields),*]
+    }
+}
+
+/// Return the name under which serde serializes the given field, honoring its `rename` and the
+/// `rename_all` of its struct.
+fn get_serialized_field_name(input: &DeriveInput, name: &str) -> String {
+    if let syn::Data::Struct(ref data) = input.data {
+        let field = data.fields.iter().find(|field| match field.ident {
+            Some(ref ident) => ident == name,
+            None => false,
+        });
+        let rename = field.and_then(|field| get_serde_attr_by_name(&field.attrs, "rename"));
+        if let Some(rename) = rename {
+            return rename;
         }
     }
+    match get_serde_attr_by_name(&input.attrs, "rename_all") {
+        Some(rule) => rename_field(name, &rule),
+        None => name.to_string(),
+    }
+}
+
+/// Apply a serde `rename_all` rule to a field name, as serde does.
+fn rename_field(name: &str, rule: &str) -> String {
+    let pascal = || {
+        name.split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new(),
+                }
+            })
+            .collect::<String>()
+    };
+    match rule {
+        "UPPERCASE" | "SCREAMING_SNAKE_CASE" => name.to_ascii_uppercase(),
+        "PascalCase" => pascal(),
+        "camelCase" => {
+            let pascal = pascal();
+            let mut chars = pascal.chars();
+            match chars.next() {
+                Some(first) => first.to_lowercase().chain(chars).collect(),
+                None => pascal,
+            }
+        }
+        "kebab-case" => name.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => name.replace('_', "-").to_ascii_uppercase(),
+        _ => name.to_string(),
+    }
+}
+
+fn get_derive_sandbox_attr(input: &DeriveInput) -> TokenStream {
+    let restrictions = get_list_attr_by_name(&input.attrs, "job_sandbox")
+        .into_iter()
+        .map(|restriction| match restriction.as_ref() {
+            "no_network" => quote! { .deny_network() },
+            _ => quote! {},
+        });
+    quote! {
+        _batch::Sandbox::new()#(#restrictions)*
+    }
+}
+
+/// Return the generics of the `Job` implementation, bounding generic jobs to be serializable.
+fn gen_derive_generics(input: &DeriveInput) -> syn::Generics {
+    let mut generics = input.generics.clone();
+    if generics.type_params().next().is_some() {
+        let name = &input.ident;
+        let (_, ty_generics, _) = input.generics.split_for_impl();
+        generics.make_where_clause().predicates.push(parse_quote! {
+            #name #ty_generics: _serde::Serialize + _serde::de::DeserializeOwned
+        });
+    }
+    generics
 }
 
 fn gen_derive_impl_block_name(name: String) -> TokenStream {
@@ -183,21 +639,91 @@ fn get_str_attr_by_name(haystack: &[syn::Attribute], needle: &str) -> Option<Str
     })
 }
 
+/// Gets the comma-separated values of an attribute by its name.
+fn get_list_attr_by_name(haystack: &[syn::Attribute], needle: &str) -> Vec<String> {
+    let attr = get_str_attr_by_name(haystack, needle).unwrap_or_else(|| "".to_string());
+    attr.split(',')
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+        .collect()
+}
+
 /// Gets the raw value of an attribute by its name.
 fn get_raw_attr_by_name(haystack: &[syn::Attribute], needle: &str) -> Option<Lit> {
     for attr in haystack {
-        let meta = match attr.interpret_meta() {
-            Some(meta) => meta,
-            None => continue,
+        let meta = match attr.parse_meta() {
+            Ok(meta) => meta,
+            Err(_) => continue,
         };
         let nv = match meta {
             Meta::NameValue(nv) => nv,
             _ => continue,
         };
-        if nv.ident != needle {
+        if !nv.path.is_ident(needle) {
             continue;
         }
         return Some(nv.lit.clone());
     }
     None
 }
+
+/// Gets the serialization value of a `#[serde(...)]` attribute by its name, either set with
+/// `name = "value"` or `name(serialize = "value")`.
+fn get_serde_attr_by_name(haystack: &[syn::Attribute], needle: &str) -> Option<String> {
+    let mut value = None;
+    for attr in haystack.iter().filter(|attr| attr.path.is_ident("serde")) {
+        let list = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list,
+            _ => continue,
+        };
+        for nested in list.nested {
+            let lit = match nested {
+                syn::NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.path.is_ident(needle) => {
+                    nv.lit.clone()
+                }
+                syn::NestedMeta::Meta(Meta::List(ref list)) if list.path.is_ident(needle) => {
+                    let serialize = list.nested.iter().filter_map(|nested| match *nested {
+                        syn::NestedMeta::Meta(Meta::NameValue(ref nv))
+                            if nv.path.is_ident("serialize") =>
+                        {
+                            Some(nv.lit.clone())
+                        }
+                        _ => None,
+                    });
+                    match serialize.last() {
+                        Some(lit) => lit,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            if let Lit::Str(literal) = lit {
+                value = Some(literal.value());
+            }
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misspelled_names_are_suggested() {
+        assert_eq!(suggest("with_priority", ATTRIBUTES), Some("job_priority"));
+        assert_eq!(suggest("job_retry", ATTRIBUTES), Some("job_retries"));
+        assert_eq!(suggest("hihg", PRIORITIES), Some("high"));
+        assert_eq!(suggest("serde", ATTRIBUTES), None);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn renamed_fields_are_resolved() {
+        assert_eq!(rename_field("api_token", "camelCase"), "apiToken");
+        assert_eq!(rename_field("api_token", "PascalCase"), "ApiToken");
+        assert_eq!(rename_field("api_token", "SCREAMING-KEBAB-CASE"), "API-TOKEN");
+        assert_eq!(rename_field("api_token", "snake_case"), "api_token");
+    }
+}
@@ -34,6 +34,7 @@ fn main() {
 
             job(SayHello { to }).exchange("batch.example").send(&client)
         })
+        .map(|handle| println!("Sent job {}", handle.id()))
         .map_err(|e| eprintln!("An error occured in the client: {}", e));
     tokio::run(future::lazy(|| {
         tokio::spawn(send);
@@ -0,0 +1,126 @@
+//! Execution of jobs in batches.
+//!
+//! The deliveries of the jobs registered with `WorkerBuilder::batch_job` are accumulated by job,
+//! and executed together once a batch is full or once its first delivery waited long enough.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::Future;
+use tokio_executor;
+
+use clock::Clock;
+use worker::{Received, Supervisor};
+
+/// Settings of a `Job` executed in batches, see `WorkerBuilder::batch_job`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BatchConfig {
+    pub max_size: usize,
+    pub max_wait: Duration,
+}
+
+/// Deliveries of a batched job waiting for their batch to be executed.
+struct PendingBatch {
+    generation: usize,
+    deliveries: Vec<Received>,
+}
+
+/// Accumulates the deliveries of the jobs registered with `WorkerBuilder::batch_job`, and
+/// executes them in batches.
+#[derive(Clone)]
+pub(crate) struct Batcher {
+    configs: Arc<HashMap<String, BatchConfig>>,
+    pending: Arc<Mutex<HashMap<String, PendingBatch>>>,
+    generation: Arc<AtomicUsize>,
+    clock: Arc<Clock>,
+    supervisor: Supervisor,
+}
+
+impl Batcher {
+    pub fn new(
+        configs: Arc<HashMap<String, BatchConfig>>,
+        clock: &Arc<Clock>,
+        supervisor: &Supervisor,
+    ) -> Self {
+        Batcher {
+            configs,
+            pending: Arc::default(),
+            generation: Arc::default(),
+            clock: Arc::clone(clock),
+            supervisor: supervisor.clone(),
+        }
+    }
+
+    /// Return `true` if the given job is executed in batches.
+    pub fn handles(&self, job: &str) -> bool {
+        self.configs.contains_key(job)
+    }
+
+    /// Add a delivery to the batch of its job, executing the batch if it is full.
+    pub fn push(&self, received: Received) {
+        let name = received.0.task().to_string();
+        let config = self.configs[&name];
+        let (full, first) = {
+            let mut pending = self.pending.lock().unwrap();
+            let generation = &self.generation;
+            let batch = pending.entry(name.clone()).or_insert_with(|| PendingBatch {
+                generation: generation.fetch_add(1, Ordering::SeqCst),
+                deliveries: Vec::new(),
+            });
+            batch.deliveries.push(received);
+            let first = if batch.deliveries.len() == 1 {
+                Some(batch.generation)
+            } else {
+                None
+            };
+            if batch.deliveries.len() >= config.max_size {
+                (pending.remove(&name), first)
+            } else {
+                (None, first)
+            }
+        };
+        match (full, first) {
+            (Some(batch), _) => self.supervisor.execute_batch(batch.deliveries),
+            (None, Some(generation)) => {
+                let batcher = self.clone();
+                let task = self.clock
+                    .delay(self.clock.now() + config.max_wait)
+                    .then(move |_| {
+                        batcher.flush(&name, Some(generation));
+                        Ok(())
+                    });
+                tokio_executor::spawn(task);
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Execute the pending batch of the given job, if it is of the given generation.
+    fn flush(&self, name: &str, generation: Option<usize>) {
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            let matches = pending.get(name).map_or(false, |batch| {
+                generation.map_or(true, |generation| generation == batch.generation)
+            });
+            if matches {
+                pending.remove(name)
+            } else {
+                None
+            }
+        };
+        if let Some(batch) = batch {
+            debug!("Executing batch of {} `{}' jobs", batch.deliveries.len(), name);
+            self.supervisor.execute_batch(batch.deliveries);
+        }
+    }
+
+    /// Execute all the pending batches, e.g. before the worker shuts down.
+    pub fn flush_all(&self) {
+        let names = self.pending.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            self.flush(&name, None);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Blocking calls made away from the reactor.
+//!
+//! The stores implemented by users, e.g. a `StatusTracker` writing to a database, are called
+//! through a synchronous API. Clients & workers run those calls on a dedicated thread instead of
+//! the thread polling their futures, which would stall every other connection & job meanwhile.
+
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures::sync::oneshot;
+use futures::{future, Future};
+
+use error::{Error, ErrorKind, Result};
+
+/// A call waiting to be run on the blocking thread.
+type Call = Box<FnMut() + Send>;
+
+/// A thread running blocking calls one at a time, in the order they were made.
+///
+/// The thread is started by the first call, and stops once every clone of its `Blocking` is
+/// dropped.
+#[derive(Clone)]
+pub(crate) struct Blocking {
+    name: &'static str,
+    sender: Arc<Mutex<Option<mpsc::Sender<Call>>>>,
+}
+
+impl fmt::Debug for Blocking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Blocking").field("name", &self.name).finish()
+    }
+}
+
+impl Blocking {
+    /// Create a new `Blocking` whose thread has the given name.
+    pub fn new(name: &'static str) -> Self {
+        Blocking {
+            name,
+            sender: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Run the given call on the blocking thread, without waiting for it.
+    pub fn spawn<F>(&self, call: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.submit(call) {
+            error!("Couldn't run blocking call: {}", e);
+        }
+    }
+
+    /// Run the given call on the blocking thread, resolving to its result.
+    pub fn run<F, T>(&self, call: F) -> Box<Future<Item = T, Error = Error> + Send>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        if let Err(e) = self.submit(move || {
+            let _ = sender.send(call());
+        }) {
+            return Box::new(future::err(ErrorKind::Reactor(e).into()));
+        }
+        let task = receiver.then(|result| match result {
+            Ok(result) => result,
+            Err(_) => {
+                let e = io::Error::new(io::ErrorKind::Other, "the blocking call panicked");
+                Err(ErrorKind::Reactor(e).into())
+            }
+        });
+        Box::new(task)
+    }
+
+    fn submit<F>(&self, call: F) -> io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut call = Some(call);
+        let call: Call = Box::new(move || {
+            if let Some(call) = call.take() {
+                call();
+            }
+        });
+        let mut started = self.sender.lock().unwrap();
+        let sender = match started.take() {
+            Some(sender) => sender,
+            None => self.start()?,
+        };
+        let result = sender.send(call).map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "the blocking thread stopped")
+        });
+        *started = Some(sender);
+        result
+    }
+
+    /// Start the blocking thread, returning the sender of its calls.
+    fn start(&self) -> io::Result<mpsc::Sender<Call>> {
+        let (sender, calls) = mpsc::channel::<Call>();
+        thread::Builder::new()
+            .name(self.name.to_string())
+            .spawn(move || {
+                for mut call in calls {
+                    // A panicking call doesn't prevent the next ones from running.
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| call()));
+                }
+            })?;
+        Ok(sender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_run_in_order() {
+        let blocking = Blocking::new("batch-test");
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..10 {
+            let calls = Arc::clone(&calls);
+            blocking.spawn(move || calls.lock().unwrap().push(i));
+        }
+        let calls = Arc::clone(&calls);
+        let result = blocking.run(move || Ok(calls.lock().unwrap().clone())).wait();
+        assert_eq!(result.unwrap(), (0..10).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,95 @@
+//! Pausing consumption after consecutive job failures.
+//!
+//! A worker configured with `WorkerBuilder::circuit_breaker` stops fetching jobs for a while once
+//! too many of them failed in a row, e.g. because a service they depend on is down, instead of
+//! failing all the jobs of its queues. Each group of queues consumed together has its own circuit
+//! breaker, so that a failing queue doesn't pause the others.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Tracks consecutive job failures to pause consumption when too many occur.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    queues: Vec<String>,
+    threshold: usize,
+    cooldown: Duration,
+    failures: AtomicUsize,
+    trips: AtomicUsize,
+    paused: AtomicBool,
+}
+
+impl CircuitBreaker {
+    pub fn new(queues: Vec<String>, threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            queues,
+            threshold: threshold.max(1) as usize,
+            cooldown,
+            failures: AtomicUsize::new(0),
+            trips: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Return the names of the queues consumption of which the circuit breaker pauses.
+    pub fn queues(&self) -> &[String] {
+        &self.queues
+    }
+
+    /// Return whether consumption is paused until the end of a cooldown.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Mark the end of the cooldown started when the circuit was opened.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+        self.trips.store(0, Ordering::SeqCst);
+    }
+
+    pub fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Open the circuit if the failure threshold is reached, returning the cooldown duration.
+    ///
+    /// After the cooldown, a single failure is enough to open the circuit again.
+    pub fn open(&self) -> Option<Duration> {
+        if self.failures.load(Ordering::SeqCst) < self.threshold {
+            return None;
+        }
+        self.failures.store(self.threshold - 1, Ordering::SeqCst);
+        self.paused.store(true, Ordering::SeqCst);
+        let trips = self.trips.fetch_add(1, Ordering::SeqCst).min(6) as u32;
+        Some(self.cooldown * 2u32.pow(trips))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(vec!["default".into()], 3, Duration::from_secs(1));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.open(), None);
+        assert!(!breaker.is_paused());
+        breaker.record_failure();
+        assert_eq!(breaker.open(), Some(Duration::from_secs(1)));
+        assert!(breaker.is_paused());
+        breaker.resume();
+        assert!(!breaker.is_paused());
+        assert_eq!(breaker.open(), None);
+        breaker.record_failure();
+        assert_eq!(breaker.open(), Some(Duration::from_secs(2)));
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.open(), None);
+    }
+}
@@ -0,0 +1,108 @@
+//! Cancellation of queued & running jobs.
+//!
+//! Cancellations are broadcast to the workers through a dedicated exchange. Each worker keeps
+//! track of the recently cancelled jobs to skip them, and marks the running ones as cancelled.
+//! Executor processes are told about the cancellation of their jobs with an instruction written
+//! to their standard input, and mark them in turn.
+
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Name of the exchange cancellations are published to.
+pub(crate) const EXCHANGE: &str = "batch.cancellations";
+
+/// Maximum number of cancelled job IDs remembered by a worker.
+const CAPACITY: usize = 10_000;
+
+/// The running jobs of the current process marked as cancelled.
+static MARKED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Mark the given running job as cancelled, for `Execution::is_cancelled`.
+pub(crate) fn mark(job_id: &str) {
+    MARKED.lock().unwrap().insert(job_id.to_string());
+}
+
+/// Return true if the given running job was marked as cancelled.
+pub(crate) fn is_marked(job_id: &str) -> bool {
+    MARKED.lock().unwrap().contains(job_id)
+}
+
+/// The jobs cancelled & running on a worker.
+#[derive(Debug, Default)]
+pub(crate) struct Cancellations {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: HashSet<String>,
+    order: VecDeque<String>,
+    running: HashSet<String>,
+}
+
+impl Cancellations {
+    /// Record the cancellation of a job, marking it as cancelled if it is running.
+    pub fn cancel(&self, job_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.running.contains(job_id) {
+            mark(job_id);
+        }
+        if inner.cancelled.insert(job_id.to_string()) {
+            inner.order.push_back(job_id.to_string());
+        }
+        while inner.order.len() > CAPACITY {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.cancelled.remove(&oldest);
+            }
+        }
+    }
+
+    /// Return true if the given job was cancelled.
+    pub fn is_cancelled(&self, job_id: &str) -> bool {
+        self.inner.lock().unwrap().cancelled.contains(job_id)
+    }
+
+    /// Record that the given job started running.
+    pub fn start(&self, job_id: &str) {
+        self.inner.lock().unwrap().running.insert(job_id.to_string());
+    }
+
+    /// Return the IDs of the running jobs, sorted.
+    pub fn running(&self) -> Vec<String> {
+        let mut running = self.inner
+            .lock()
+            .unwrap()
+            .running
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        running.sort();
+        running
+    }
+
+    /// Record that the given job stopped running, removing its cancellation mark if any.
+    pub fn finish(&self, job_id: &str) {
+        self.inner.lock().unwrap().running.remove(job_id);
+        MARKED.lock().unwrap().remove(job_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_jobs_are_marked() {
+        let cancellations = Cancellations::default();
+        let job_id = format!("cancellation-test-{}", ::std::process::id());
+        cancellations.start(&job_id);
+        assert_eq!(cancellations.running(), vec![job_id.clone()]);
+        assert!(!cancellations.is_cancelled(&job_id));
+        cancellations.cancel(&job_id);
+        assert!(cancellations.is_cancelled(&job_id));
+        assert!(is_marked(&job_id));
+        cancellations.finish(&job_id);
+        assert!(!is_marked(&job_id));
+        assert!(cancellations.running().is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+//! Compatibility with Celery's message protocol (version 2).
+//!
+//! Celery messages share the headers used by batch (`lang`, `task`, `id`, `timelimit`, etc.), but
+//! their body is a JSON array made of the task's positional arguments, its keyword arguments and
+//! some workflow metadata: `[args, kwargs, embed]`. Jobs serialized as JSON objects are mapped to
+//! keyword arguments, jobs serialized as JSON arrays are mapped to positional arguments.
+//!
+//! Celery messages are told apart from the messages of batch by their headers: Celery producers
+//! set `lang` to `py` and describe the arguments of the task in `argsrepr` & `kwargsrepr`.
+
+use lapin::channel::BasicProperties;
+use lapin::types::{AMQPValue, FieldTable};
+use serde::de::Error as DeError;
+use serde::Serialize;
+use serde_json::{self, Map, Value};
+
+use rabbitmq::Delivery;
+use ser;
+
+/// Return `true` if the message with the given properties follows Celery's message protocol.
+pub fn is_celery(properties: &BasicProperties) -> bool {
+    let headers = match properties.headers {
+        Some(ref headers) => headers,
+        None => return false,
+    };
+    let is_python = match headers.get("lang") {
+        Some(&AMQPValue::LongString(ref lang)) => lang == "py",
+        _ => false,
+    };
+    is_python || headers.contains_key("argsrepr") || headers.contains_key("kwargsrepr")
+}
+
+/// Serialize a job into a Celery message body, adding the headers of Celery messages to the
+/// given properties.
+pub fn to_vec<T>(job: &T, properties: &mut BasicProperties) -> serde_json::Result<Vec<u8>>
+where
+    T: Serialize,
+{
+    let (args, kwargs) = match serde_json::to_value(job)? {
+        Value::Object(kwargs) => (Value::Array(vec![]), Value::Object(kwargs)),
+        Value::Array(args) => (Value::Array(args), Value::Object(Default::default())),
+        Value::Null => (Value::Array(vec![]), Value::Object(Default::default())),
+        other => (Value::Array(vec![other]), Value::Object(Default::default())),
+    };
+    let headers = properties.headers.get_or_insert_with(FieldTable::new);
+    headers.insert("argsrepr".to_string(), AMQPValue::LongString(args.to_string()));
+    headers.insert("kwargsrepr".to_string(), AMQPValue::LongString(kwargs.to_string()));
+    headers.entry("eta".to_string()).or_insert(AMQPValue::Void);
+    headers.entry("expires".to_string()).or_insert(AMQPValue::Void);
+    let embed = ["callbacks", "errbacks", "chain", "chord"]
+        .iter()
+        .map(|key| (key.to_string(), Value::Null))
+        .collect();
+    let embed = Value::Object(embed);
+    ser::to_vec(&Value::Array(vec![args, kwargs, embed]))
+}
+
+/// Extract a job's serialized payload from a Celery message body.
+///
+/// Returns `None` if the message with the given properties isn't a Celery message, and an error
+/// if its body isn't a valid Celery message body or if it has both positional & keyword
+/// arguments, which can't be mapped to a single job.
+pub fn from_slice(
+    properties: &BasicProperties,
+    data: &[u8],
+) -> serde_json::Result<Option<Vec<u8>>> {
+    if !is_celery(properties) {
+        return Ok(None);
+    }
+    let (args, kwargs, _): (Vec<Value>, Map<String, Value>, Value) = serde_json::from_slice(data)?;
+    let payload = match (args.len(), kwargs.is_empty()) {
+        (0, true) => Value::Null,
+        (0, false) => Value::Object(kwargs),
+        (1, true) => args.into_iter().next().unwrap(),
+        (_, true) => Value::Array(args),
+        (_, false) => {
+            return Err(serde_json::Error::custom(
+                "Celery messages with both positional & keyword arguments aren't supported",
+            ))
+        }
+    };
+    ser::to_vec(&payload).map(Some)
+}
+
+/// Return the job's serialized payload of the given delivery, extracted from its body if it is a
+/// Celery message.
+pub fn payload(delivery: &Delivery) -> serde_json::Result<Vec<u8>> {
+    let payload = from_slice(delivery.properties(), delivery.data())?;
+    Ok(payload.unwrap_or_else(|| delivery.data().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Add {
+        x: i32,
+        y: i32,
+    }
+
+    fn python() -> BasicProperties {
+        let mut headers = FieldTable::new();
+        headers.insert("lang".to_string(), AMQPValue::LongString("py".to_string()));
+        BasicProperties {
+            headers: Some(headers),
+            ..BasicProperties::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let job = Add { x: 1, y: 2 };
+        let mut properties = BasicProperties::default();
+        let body = to_vec(&job, &mut properties).unwrap();
+        assert!(is_celery(&properties));
+        let payload = from_slice(&properties, &body).unwrap().unwrap();
+        assert_eq!(serde_json::from_slice::<Add>(&payload).unwrap(), job);
+    }
+
+    #[test]
+    fn not_celery() {
+        let body = br#"[[1, 2], {}, {}]"#;
+        assert_eq!(from_slice(&BasicProperties::default(), body).unwrap(), None);
+    }
+
+    #[test]
+    fn positional_arguments() {
+        let payload = from_slice(&python(), br#"[[1, 2], {}, {}]"#).unwrap();
+        assert_eq!(payload, Some(b"[1,2]".to_vec()));
+    }
+
+    #[test]
+    fn positional_and_keyword_arguments() {
+        assert!(from_slice(&python(), br#"[[1], {"y": 2}, {}]"#).is_err());
+        assert!(from_slice(&python(), br#"{"x": 1, "y": 2}"#).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Fault injection, to check how jobs behave when things go wrong.
+
+use std::time::Duration;
+
+use rand;
+
+/// The faults a `Worker` injects while running, see
+/// [`WorkerBuilder::chaos`](struct.WorkerBuilder.html#method.chaos).
+///
+/// Each fault happens randomly, with the given probability, between 0 (never) and 1 (always).
+/// This is meant to be used in tests & staging environments, to check that the retries &
+/// dead-letter exchanges of the jobs are configured as expected; it is only available with the
+/// `chaos` feature enabled.
+///
+/// # Example
+///
+/// ```
+/// use batch::ChaosConfig;
+/// use std::time::Duration;
+///
+/// let chaos = ChaosConfig::new()
+///     .delays(0.5, Duration::from_secs(2))
+///     .crashes(0.1)
+///     .dropped_acks(0.05);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ChaosConfig {
+    delay_probability: f64,
+    max_delay: Duration,
+    crash_probability: f64,
+    dropped_ack_probability: f64,
+}
+
+impl ChaosConfig {
+    /// Create a new `ChaosConfig` injecting no faults.
+    pub fn new() -> Self {
+        ChaosConfig::default()
+    }
+
+    /// Delay the execution of jobs by up to `max` with the given probability.
+    pub fn delays(mut self, probability: f64, max: Duration) -> Self {
+        self.delay_probability = probability;
+        self.max_delay = max;
+        self
+    }
+
+    /// Crash the processes executing jobs with the given probability, before the job's handler
+    /// is called.
+    pub fn crashes(mut self, probability: f64) -> Self {
+        self.crash_probability = probability;
+        self
+    }
+
+    /// Drop the acknowledgement of successful jobs with the given probability.
+    ///
+    /// The jobs are requeued instead, like the broker does when the connection of a worker is
+    /// lost before the acknowledgement reaches it, so they are delivered again.
+    pub fn dropped_acks(mut self, probability: f64) -> Self {
+        self.dropped_ack_probability = probability;
+        self
+    }
+
+    /// Return the delay to inject before executing a job, if any.
+    pub(crate) fn delay(&self) -> Option<Duration> {
+        if !roll(self.delay_probability) {
+            return None;
+        }
+        let max = self.max_delay.as_secs() as f64 * 1e9 + f64::from(self.max_delay.subsec_nanos());
+        let nanos = (max * rand::random::<f64>()) as u64;
+        Some(Duration::new(
+            nanos / 1_000_000_000,
+            (nanos % 1_000_000_000) as u32,
+        ))
+    }
+
+    /// Return `true` if the process executing a job should crash.
+    pub(crate) fn crash(&self) -> bool {
+        roll(self.crash_probability)
+    }
+
+    /// Return `true` if the acknowledgement of a job should be dropped.
+    pub(crate) fn drop_ack(&self) -> bool {
+        roll(self.dropped_ack_probability)
+    }
+}
+
+/// Return `true` with the given probability.
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && rand::random::<f64>() < probability
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_follow_their_probability() {
+        let chaos = ChaosConfig::new();
+        assert!(!chaos.crash() && !chaos.drop_ack());
+        assert_eq!(chaos.delay(), None);
+
+        let chaos = ChaosConfig::new()
+            .delays(1.0, Duration::from_millis(10))
+            .crashes(1.0)
+            .dropped_acks(1.0);
+        assert!(chaos.crash() && chaos.drop_ack());
+        assert!(chaos.delay().unwrap() <= Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,161 @@
+//! Sources of time used by workers.
+//!
+//! Workers read the time and wait through a `Clock`, which makes time-based behaviors (timeouts,
+//! circuit breaker cooldowns, shutdown deadlines) testable with a `MockClock` instead of waiting
+//! for real.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll};
+use tokio_timer::Delay;
+
+/// A source of time.
+///
+/// See [`WorkerBuilder::clock`](struct.WorkerBuilder.html#method.clock).
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Return the current instant, used to measure durations.
+    fn now(&self) -> Instant;
+
+    /// Return the current system time, used to timestamp events.
+    fn system_time(&self) -> SystemTime;
+
+    /// Return a `Future` that completes once the given instant is reached.
+    fn delay(&self, deadline: Instant) -> Box<Future<Item = (), Error = ()> + Send>;
+}
+
+/// The `Clock` of the operating system, used by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn delay(&self, deadline: Instant) -> Box<Future<Item = (), Error = ()> + Send> {
+        let task = Delay::new(deadline).map_err(|e| error!("Timer error: {}", e));
+        Box::new(task)
+    }
+}
+
+/// A `Clock` that only moves forward when told to.
+///
+/// Clones of a `MockClock` share the same time.
+///
+/// # Example
+///
+/// ```
+/// use batch::{Clock, MockClock};
+/// use std::time::Duration;
+///
+/// let clock = MockClock::new();
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(clock.now() - start, Duration::from_secs(60));
+/// ```
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    inner: Arc<Mutex<MockInner>>,
+}
+
+#[derive(Debug)]
+struct MockInner {
+    now: Instant,
+    system_time: SystemTime,
+    waiting: Vec<Task>,
+}
+
+impl MockClock {
+    /// Create a new `MockClock`, starting at the current time.
+    pub fn new() -> Self {
+        MockClock {
+            inner: Arc::new(Mutex::new(MockInner {
+                now: Instant::now(),
+                system_time: SystemTime::now(),
+                waiting: Vec::new(),
+            })),
+        }
+    }
+
+    /// Move the clock forward, completing the delays that are now over.
+    pub fn advance(&self, duration: Duration) {
+        let waiting = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.now += duration;
+            inner.system_time += duration;
+            inner.waiting.drain(..).collect::<Vec<_>>()
+        };
+        for task in waiting {
+            task.notify();
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.inner.lock().unwrap().system_time
+    }
+
+    fn delay(&self, deadline: Instant) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(MockDelay {
+            clock: self.clone(),
+            deadline,
+        })
+    }
+}
+
+/// A delay completing once a `MockClock` is advanced past its deadline.
+struct MockDelay {
+    clock: MockClock,
+    deadline: Instant,
+}
+
+impl Future for MockDelay {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut inner = self.clock.inner.lock().unwrap();
+        if inner.now >= self.deadline {
+            Ok(Async::Ready(()))
+        } else {
+            inner.waiting.push(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn mock_delay_completes_when_advanced() {
+        let clock = MockClock::new();
+        let mut delay = clock.delay(clock.now() + Duration::from_secs(10));
+        let mut poll = || future::lazy(|| Ok::<_, ()>(delay.poll())).wait().unwrap();
+        assert_eq!(poll(), Ok(Async::NotReady));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(poll(), Ok(Async::NotReady));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(poll(), Ok(Async::Ready(())));
+    }
+}
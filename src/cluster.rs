@@ -0,0 +1,161 @@
+//! Inventory of the running workers.
+//!
+//! Workers that enabled announcements publish a description of themselves to a dedicated
+//! exchange at regular intervals. Clients list the live workers by listening to the exchange for
+//! a little longer than this interval.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use tokio_executor;
+use tokio_timer::Delay;
+use uuid::Uuid;
+
+use client::Client;
+use de;
+use error::Error;
+use rabbitmq::{exchange, queue};
+
+/// Name of the exchange workers announce themselves to.
+pub(crate) const EXCHANGE: &str = "batch.workers";
+
+/// Number of seconds between two announcements of a worker.
+pub(crate) const INTERVAL_SECS: u64 = 5;
+
+/// Description of a running `Worker`, see [`Client::workers`](struct.Client.html#method.workers).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerInfo {
+    id: String,
+    hostname: String,
+    version: String,
+    jobs: Vec<String>,
+    queues: Vec<String>,
+    parallelism: u16,
+    #[serde(default)]
+    running: Vec<String>,
+    #[serde(default)]
+    tripped: Vec<String>,
+}
+
+impl WorkerInfo {
+    pub(crate) fn new(
+        id: &str,
+        hostname: &str,
+        jobs: Vec<String>,
+        queues: Vec<String>,
+        parallelism: u16,
+    ) -> Self {
+        WorkerInfo {
+            id: id.to_string(),
+            hostname: hostname.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            jobs,
+            queues,
+            parallelism,
+            running: Vec::new(),
+            tripped: Vec::new(),
+        }
+    }
+
+    /// Update the jobs running on the worker, the queues it stopped consuming after too many
+    /// failures, and its parallelism if it was changed.
+    pub(crate) fn update(
+        &mut self,
+        running: Vec<String>,
+        tripped: Vec<String>,
+        parallelism: Option<u16>,
+    ) {
+        self.running = running;
+        self.tripped = tripped;
+        if let Some(parallelism) = parallelism {
+            self.parallelism = parallelism;
+        }
+    }
+
+    /// Return the unique ID the worker got when it started.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return the name of the host the worker runs on.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Return the version of batch the worker runs.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Return the names of the jobs the worker has handlers for, sorted.
+    pub fn jobs(&self) -> &[String] {
+        &self.jobs
+    }
+
+    /// Return the names of the queues the worker consumes.
+    pub fn queues(&self) -> &[String] {
+        &self.queues
+    }
+
+    /// Return the number of jobs the worker executes in parallel.
+    pub fn parallelism(&self) -> u16 {
+        self.parallelism
+    }
+
+    /// Return the IDs of the jobs the worker was running when it announced itself, sorted.
+    pub fn running(&self) -> &[String] {
+        &self.running
+    }
+
+    /// Return the names of the queues the worker stopped consuming because their circuit breaker
+    /// tripped, sorted.
+    ///
+    /// See [`WorkerBuilder::circuit_breaker`](struct.WorkerBuilder.html#method.circuit_breaker).
+    pub fn tripped(&self) -> &[String] {
+        &self.tripped
+    }
+}
+
+/// Listen to the announcements of the workers, returning the workers heard from.
+pub(crate) fn list(client: &Client) -> Box<Future<Item = Vec<WorkerInfo>, Error = Error> + Send> {
+    let queue = queue(&format!("{}.{}", EXCHANGE, Uuid::new_v4()))
+        .bind(EXCHANGE, "")
+        .exclusive(true)
+        .auto_delete(true)
+        .build();
+    let wait = Duration::from_secs(INTERVAL_SECS + 1);
+    let task = client
+        .consumer(vec![exchange(EXCHANGE).build()], vec![queue], 16)
+        .and_then(move |consumer| {
+            let handle = consumer.handle();
+            let announcements = consumer.map(move |delivery| {
+                let ack = handle
+                    .ack(delivery.tag())
+                    .map_err(|e| error!("Couldn't acknowledge announcement: {}", e));
+                tokio_executor::spawn(ack);
+                match de::from_slice::<WorkerInfo>(delivery.data()) {
+                    Ok(info) => Some(info),
+                    Err(e) => {
+                        warn!("Invalid worker announcement: {}", e);
+                        None
+                    }
+                }
+            });
+            let deadline = Delay::new(Instant::now() + wait)
+                .then(|_| Ok::<_, Error>(None))
+                .into_stream();
+            announcements
+                .map(Some)
+                .select(deadline)
+                .take_while(|announcement| Ok(announcement.is_some()))
+                .fold(BTreeMap::new(), |mut workers, announcement| {
+                    if let Some(Some(info)) = announcement {
+                        workers.insert(info.id.clone(), info);
+                    }
+                    future::ok::<_, Error>(workers)
+                })
+        })
+        .map(|workers| workers.into_iter().map(|(_, info)| info).collect());
+    Box::new(task)
+}
@@ -0,0 +1,288 @@
+//! Configuration of workers & topologies loaded from a TOML file.
+//!
+//! Only the settings operators may want to tune without rebuilding the binaries are read from the
+//! file: the jobs themselves are still registered in code, and the settings are checked against
+//! them when applied to a `WorkerBuilder`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::LevelFilter;
+use toml;
+
+use error::{Error, ErrorKind, Result};
+use rabbitmq::{exchange, queue, Connection, ConnectionBuilder, ExchangeBuilder, QueueBuilder};
+
+/// Settings of a worker or a topology, usually loaded from a `batch.toml` file.
+///
+/// Every section & setting is optional:
+///
+/// ```toml
+/// exchanges = ["batch.example"]
+///
+/// [broker]
+/// url = "amqp://rabbitmq-1/%2f"
+/// failover = ["amqp://rabbitmq-2/%2f"]
+/// heartbeat = 30 # seconds
+/// timeout = 10 # seconds
+///
+/// [worker]
+/// parallelism = 8
+/// shutdown_timeout = 30 # seconds
+/// in_process = ["emails"]
+/// only = ["billing.*"]
+/// except = ["billing.refunds"]
+/// log_level = "info"
+///
+/// [[queues]]
+/// name = "emails"
+/// bindings = [{ exchange = "batch.example", routing_key = "emails" }]
+/// parallelism = 2
+/// retries = 5
+/// timeout = 300 # seconds
+/// max_priority = 10
+/// ```
+///
+/// This is only available with the `config` feature enabled.
+///
+/// # Example
+///
+/// ```
+/// use batch::{Config, Error, Worker, WorkerBuilder};
+///
+/// fn worker() -> Result<WorkerBuilder<()>, Error> {
+///     let config = Config::from_file("batch.toml")?;
+///     Worker::builder(())
+///         // Register the jobs here, before the configuration is applied.
+///         .config(&config)
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    broker: Option<BrokerConfig>,
+    worker: WorkerConfig,
+    exchanges: Vec<String>,
+    queues: Vec<QueueConfig>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BrokerConfig {
+    url: Option<String>,
+    failover: Vec<String>,
+    shuffle: bool,
+    heartbeat: Option<u64>,
+    frame_max: Option<u32>,
+    timeout: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct WorkerConfig {
+    parallelism: Option<u16>,
+    shutdown_timeout: Option<u64>,
+    in_process: Vec<String>,
+    only: Vec<String>,
+    except: Vec<String>,
+    log_level: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QueueConfig {
+    name: String,
+    bindings: Vec<BindingConfig>,
+    parallelism: Option<u16>,
+    retries: Option<u32>,
+    timeout: Option<u64>,
+    max_priority: Option<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BindingConfig {
+    exchange: String,
+    routing_key: String,
+}
+
+impl Config {
+    /// Load the configuration from the given TOML file.
+    ///
+    /// This fails with `ErrorKind::Io` if the file can't be read, and with
+    /// `ErrorKind::InvalidConfig` if it isn't a valid configuration.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(ErrorKind::Io)?;
+        contents.parse().map_err(|e: Error| match *e.kind() {
+            ErrorKind::InvalidConfig(ref message) => {
+                let message = format!("{}: {}", path.display(), message);
+                ErrorKind::InvalidConfig(message).into()
+            }
+            _ => e,
+        })
+    }
+
+    /// Return the settings used to connect to `RabbitMQ`, if the broker's URL is configured.
+    pub fn connection(&self) -> Option<ConnectionBuilder> {
+        let broker = self.broker.as_ref()?;
+        let mut builder = Connection::builder(broker.url.as_ref()?).shuffle(broker.shuffle);
+        for url in &broker.failover {
+            builder = builder.failover(url);
+        }
+        if let Some(heartbeat) = broker.heartbeat {
+            builder = builder.heartbeat(Duration::from_secs(heartbeat));
+        }
+        if let Some(frame_max) = broker.frame_max {
+            builder = builder.frame_max(frame_max);
+        }
+        if let Some(timeout) = broker.timeout {
+            builder = builder.timeout(Duration::from_secs(timeout));
+        }
+        Some(builder)
+    }
+
+    /// Return the exchanges to declare.
+    pub fn exchanges(&self) -> Vec<ExchangeBuilder> {
+        self.exchanges.iter().map(|name| exchange(name)).collect()
+    }
+
+    /// Return the queues to declare, with their bindings.
+    pub fn queues(&self) -> Vec<QueueBuilder> {
+        self.queues
+            .iter()
+            .map(|config| {
+                let mut builder = config
+                    .bindings
+                    .iter()
+                    .fold(queue(&config.name), |builder, binding| {
+                        builder.bind(&binding.exchange, &binding.routing_key)
+                    });
+                if let Some(retries) = config.retries {
+                    builder = builder.default_retries(retries);
+                }
+                if let Some(timeout) = config.timeout {
+                    builder = builder.default_timeout(Duration::from_secs(timeout));
+                }
+                if let Some(max_priority) = config.max_priority {
+                    builder = builder.max_priority(max_priority);
+                }
+                builder
+            })
+            .collect()
+    }
+
+    /// Return the number of jobs to execute in parallel, if configured.
+    pub fn parallelism(&self) -> Option<u16> {
+        self.worker.parallelism
+    }
+
+    /// Return the time given to running jobs to complete on shutdown, if configured.
+    pub fn shutdown_timeout(&self) -> Option<Duration> {
+        self.worker.shutdown_timeout.map(Duration::from_secs)
+    }
+
+    /// Return the queues whose jobs are executed in the worker process.
+    pub fn in_process_queues(&self) -> &[String] {
+        &self.worker.in_process
+    }
+
+    /// Return the patterns of the names of the jobs to execute, see `WorkerBuilder::only`.
+    pub fn only(&self) -> &[String] {
+        &self.worker.only
+    }
+
+    /// Return the patterns of the names of the jobs not to execute, see `WorkerBuilder::except`.
+    pub fn except(&self) -> &[String] {
+        &self.worker.except
+    }
+
+    /// Return the maximum level of the logged messages, if configured.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.worker
+            .log_level
+            .as_ref()
+            .and_then(|level| level.parse().ok())
+    }
+
+    /// Return the queues consumed separately, with their own parallelism.
+    pub fn queue_parallelism(&self) -> Vec<(&str, u16)> {
+        self.queues
+            .iter()
+            .filter_map(|queue| queue.parallelism.map(|parallelism| (&*queue.name, parallelism)))
+            .collect()
+    }
+
+    /// Check that the queues referenced by the configuration are declared by it.
+    fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for queue in &self.queues {
+            if queue.name.is_empty() {
+                return Err(invalid("queues must have a name"));
+            }
+            if !names.insert(&*queue.name) {
+                return Err(invalid(&format!("queue `{}' is declared twice", queue.name)));
+            }
+        }
+        if let Some(ref level) = self.worker.log_level {
+            if level.parse::<LevelFilter>().is_err() {
+                return Err(invalid(&format!("invalid log level `{}'", level)));
+            }
+        }
+        match self.worker.in_process.iter().find(|name| !names.contains(&***name)) {
+            Some(name) => Err(invalid(&format!("in-process queue `{}' isn't declared", name))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let config: Config = toml::from_str(s).map_err(|e| invalid(&e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    ErrorKind::InvalidConfig(message.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_are_configured() {
+        let config: Config = r#"
+            [worker]
+            in_process = ["emails"]
+
+            [[queues]]
+            name = "emails"
+            bindings = [{ exchange = "batch.example", routing_key = "emails" }]
+            parallelism = 2
+            retries = 5
+        "#.parse()
+            .unwrap();
+        assert!(config.connection().is_none());
+        assert_eq!(config.queue_parallelism(), vec![("emails", 2)]);
+        let queues = config.queues().into_iter().map(|q| q.build()).collect::<Vec<_>>();
+        assert_eq!(queues[0].name(), "emails");
+        assert_eq!(queues[0].default_retries(), Some(5));
+        assert!(queues[0].bindings().iter().any(|b| b.routing_key() == "emails"));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let unknown = "[worker]\nparalelism = 4".parse::<Config>().unwrap_err();
+        assert!(unknown.is_invalid_config());
+        let undeclared = "[worker]\nin_process = [\"emails\"]".parse::<Config>().unwrap_err();
+        assert!(undeclared.to_string().contains("emails"));
+    }
+}
@@ -0,0 +1,484 @@
+//! Controlling running workers: pausing & resuming queues, changing their parallelism, etc.
+//!
+//! Workers can be controlled in-process through a `ControlHandle`, or remotely through a dedicated
+//! exchange each worker binds its own control queue to. Remote commands are broadcast to all the
+//! workers, or targeted at a single one of them by its ID.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::task::AtomicTask;
+use futures::Future;
+use log::{self, LevelFilter};
+
+use client::Client;
+#[cfg(feature = "config")]
+use config::Config;
+use error::Error;
+use rabbitmq::Consumer;
+use router;
+
+/// Name of the exchange control commands are published to.
+pub(crate) const EXCHANGE: &str = "batch.control";
+
+/// A command sent to the workers through the control exchange.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub(crate) enum Command {
+    Pause { queue: String },
+    Resume { queue: String },
+    Parallelism { parallelism: u16 },
+    Jobs { only: Vec<String>, except: Vec<String> },
+    #[serde(rename = "log_level")]
+    LogLevel { level: String },
+    Dump,
+    Shutdown,
+}
+
+/// A command along with the ID of the worker it is meant for, if it isn't broadcast.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Message {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+impl Message {
+    /// Return `true` if the given worker should apply this command.
+    pub fn is_for(&self, worker_id: &str) -> bool {
+        self.worker.as_ref().map_or(true, |worker| worker == worker_id)
+    }
+}
+
+/// A remote control for the running workers, sending commands through the broker.
+///
+/// Commands are only received by the workers that enabled remote control, see
+/// [`WorkerBuilder::enable_remote_control`]. They are broadcast to all of them, unless the remote
+/// control is restricted to a single worker with [`RemoteControl::worker`]: the IDs of the
+/// running workers are listed by [`Client::workers`].
+///
+/// [`WorkerBuilder::enable_remote_control`]: struct.WorkerBuilder.html#method.enable_remote_control
+/// [`RemoteControl::worker`]: struct.RemoteControl.html#method.worker
+/// [`Client::workers`]: struct.Client.html#method.workers
+///
+/// # Example
+///
+/// ```
+/// extern crate batch;
+/// extern crate futures;
+/// extern crate tokio;
+///
+/// use batch::Client;
+/// use futures::Future;
+///
+/// # fn main() {
+/// let task = Client::builder()
+///     .build()
+///     .and_then(|client| {
+///         let all = client.remote_control();
+///         let one = all.worker("26d1c4e6-9e9f-4bc0-8a43-1ad3d29a3f13");
+///         all.pause("emails").join(one.set_parallelism(2))
+///     })
+///     .map(|_| ())
+///     .map_err(|e| eprintln!("Couldn't control workers: {}", e));
+/// # if false {
+/// tokio::run(task);
+/// # }
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RemoteControl {
+    client: Client,
+    worker: Option<String>,
+}
+
+impl RemoteControl {
+    pub(crate) fn new(client: Client) -> Self {
+        RemoteControl {
+            client,
+            worker: None,
+        }
+    }
+
+    /// Return a remote control sending its commands to the worker with the given ID only.
+    pub fn worker(&self, id: &str) -> Self {
+        RemoteControl {
+            client: self.client.clone(),
+            worker: Some(id.to_string()),
+        }
+    }
+
+    /// Stop fetching new jobs from the given queue, see
+    /// [`ControlHandle::pause`](struct.ControlHandle.html#method.pause).
+    pub fn pause(&self, queue: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.send(Command::Pause {
+            queue: queue.to_string(),
+        })
+    }
+
+    /// Fetch new jobs from the given queue again after it was paused.
+    pub fn resume(&self, queue: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.send(Command::Resume {
+            queue: queue.to_string(),
+        })
+    }
+
+    /// Change the number of jobs the workers execute in parallel, see
+    /// [`ControlHandle::set_parallelism`](struct.ControlHandle.html#method.set_parallelism).
+    pub fn set_parallelism(
+        &self,
+        parallelism: u16,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.send(Command::Parallelism { parallelism })
+    }
+
+    /// Change the jobs the workers execute, see
+    /// [`ControlHandle::set_job_filter`](struct.ControlHandle.html#method.set_job_filter).
+    pub fn set_job_filter(
+        &self,
+        only: &[&str],
+        except: &[&str],
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.send(Command::Jobs {
+            only: only.iter().map(|pattern| pattern.to_string()).collect(),
+            except: except.iter().map(|pattern| pattern.to_string()).collect(),
+        })
+    }
+
+    /// Change the maximum level of the messages logged by the workers, see
+    /// [`ControlHandle::set_log_level`](struct.ControlHandle.html#method.set_log_level).
+    pub fn set_log_level(
+        &self,
+        level: LevelFilter,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.send(Command::LogLevel {
+            level: level.to_string(),
+        })
+    }
+
+    /// Make the workers log the IDs of the jobs they are running.
+    ///
+    /// Workers that enabled announcements also include them in their description, see
+    /// [`WorkerInfo::running`](struct.WorkerInfo.html#method.running).
+    pub fn dump(&self) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.send(Command::Dump)
+    }
+
+    /// Stop fetching new jobs, and let the running ones complete before exiting, see
+    /// [`ShutdownHandle::quiet`](struct.ShutdownHandle.html#method.quiet).
+    pub fn shutdown(&self) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.send(Command::Shutdown)
+    }
+
+    fn send(&self, command: Command) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.client.control(&Message {
+            worker: self.worker.clone(),
+            command,
+        })
+    }
+}
+
+/// A handle to pause & resume the consumption of queues on a running `Worker`.
+///
+/// See [`Worker::control_handle`](struct.Worker.html#method.control_handle).
+#[derive(Clone, Debug)]
+pub struct ControlHandle {
+    inner: Arc<Control>,
+}
+
+impl ControlHandle {
+    pub(crate) fn new(inner: Arc<Control>) -> Self {
+        ControlHandle { inner }
+    }
+
+    /// Stop fetching new jobs from the given queue.
+    ///
+    /// Running jobs aren't interrupted, and jobs already delivered to the worker are still
+    /// executed. Pausing a queue that is already paused, or that isn't consumed by the worker,
+    /// has no effect.
+    pub fn pause(&self, queue: &str) {
+        info!("Pausing consumption of queue `{}'", queue);
+        self.inner.apply(&Command::Pause {
+            queue: queue.to_string(),
+        });
+    }
+
+    /// Fetch new jobs from the given queue again after it was paused.
+    pub fn resume(&self, queue: &str) {
+        info!("Resuming consumption of queue `{}'", queue);
+        self.inner.apply(&Command::Resume {
+            queue: queue.to_string(),
+        });
+    }
+
+    /// Change the number of jobs the worker executes in parallel.
+    ///
+    /// The new limit applies to the jobs fetched from now on, and can't exceed the parallelism the
+    /// worker was started with. Workers using adaptive prefetching override it with their own
+    /// estimate as soon as the duration of the jobs changes.
+    pub fn set_parallelism(&self, parallelism: u16) {
+        info!("Changing parallelism to {}", parallelism);
+        self.inner.apply(&Command::Parallelism { parallelism });
+    }
+
+    /// Replace the patterns given to [`WorkerBuilder::only`] & [`WorkerBuilder::except`].
+    ///
+    /// The new patterns apply to the jobs delivered from now on: running jobs aren't interrupted.
+    ///
+    /// [`WorkerBuilder::only`]: struct.WorkerBuilder.html#method.only
+    /// [`WorkerBuilder::except`]: struct.WorkerBuilder.html#method.except
+    pub fn set_job_filter(&self, only: &[&str], except: &[&str]) {
+        info!("Changing job filter to {:?} except {:?}", only, except);
+        self.inner.apply(&Command::Jobs {
+            only: only.iter().map(|pattern| pattern.to_string()).collect(),
+            except: except.iter().map(|pattern| pattern.to_string()).collect(),
+        });
+    }
+
+    /// Change the maximum level of the messages logged by the process.
+    ///
+    /// This is the level checked by the `log` macros before handing messages to the logger: a
+    /// logger filtering messages on its own may still discard them.
+    pub fn set_log_level(&self, level: LevelFilter) {
+        info!("Changing log level to {}", level);
+        self.inner.apply(&Command::LogLevel {
+            level: level.to_string(),
+        });
+    }
+
+    /// Apply the settings of the given configuration that can change while the worker runs:
+    /// its parallelism, job filter & log level.
+    ///
+    /// The other settings, e.g. the queues, require restarting the worker. This is meant to be
+    /// called when the configuration file changes, e.g. from a `SIGHUP` handler: the worker
+    /// keeps its connection, and running jobs aren't interrupted. This method is only available
+    /// with the `config` feature enabled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::{Config, ControlHandle, Error};
+    ///
+    /// // Called when receiving `SIGHUP`, e.g. with `tokio-signal`.
+    /// fn reload(control: &ControlHandle) -> Result<(), Error> {
+    ///     let config = Config::from_file("batch.toml")?;
+    ///     control.reload(&config);
+    ///     Ok(())
+    /// }
+    /// ```
+    #[cfg(feature = "config")]
+    pub fn reload(&self, config: &Config) {
+        info!("Reloading configuration");
+        if let Some(parallelism) = config.parallelism() {
+            self.set_parallelism(parallelism);
+        }
+        let only = config.only().iter().map(String::as_str).collect::<Vec<_>>();
+        let except = config.except().iter().map(String::as_str).collect::<Vec<_>>();
+        self.set_job_filter(&only, &except);
+        if let Some(level) = config.log_level() {
+            self.set_log_level(level);
+        }
+    }
+
+    /// Return `true` if the given queue is paused.
+    pub fn is_paused(&self, queue: &str) -> bool {
+        self.inner.is_paused(queue)
+    }
+}
+
+/// The jobs a `Worker` executes, see `WorkerBuilder::only` & `WorkerBuilder::except`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct JobFilter {
+    pub only: Vec<String>,
+    pub except: Vec<String>,
+}
+
+impl JobFilter {
+    /// Return `true` if the job with the given name should be executed.
+    pub fn allows(&self, job_name: &str) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| router::score(pattern, job_name).is_some())
+        };
+        (self.only.is_empty() || matches(&self.only)) && !matches(&self.except)
+    }
+}
+
+/// The paused queues, parallelism & job filter of a worker, shared with its `ControlHandle`s.
+#[derive(Debug, Default)]
+pub(crate) struct Control {
+    paused: Mutex<HashSet<String>>,
+    parallelism: Mutex<Option<u16>>,
+    filter: Mutex<JobFilter>,
+    generation: AtomicUsize,
+    consumers: Mutex<Vec<Arc<AtomicTask>>>,
+}
+
+impl Control {
+    /// Create a new `Control`, executing the jobs allowed by the given filter.
+    pub fn with_filter(filter: JobFilter) -> Self {
+        Control {
+            filter: Mutex::new(filter),
+            ..Control::default()
+        }
+    }
+
+    /// Apply the given command, waking the consumers up so they pause or resume their queues.
+    pub fn apply(&self, command: &Command) {
+        {
+            let mut paused = self.paused.lock().unwrap();
+            match *command {
+                Command::Pause { ref queue } => {
+                    paused.insert(queue.clone());
+                }
+                Command::Resume { ref queue } => {
+                    paused.remove(queue);
+                }
+                Command::Parallelism { parallelism } => {
+                    *self.parallelism.lock().unwrap() = Some(parallelism);
+                    self.generation.fetch_add(1, Ordering::SeqCst);
+                }
+                Command::Jobs {
+                    ref only,
+                    ref except,
+                } => {
+                    *self.filter.lock().unwrap() = JobFilter {
+                        only: only.clone(),
+                        except: except.clone(),
+                    };
+                    return;
+                }
+                Command::LogLevel { ref level } => {
+                    match level.parse::<LevelFilter>() {
+                        Ok(level) => log::set_max_level(level),
+                        Err(_) => warn!("Invalid log level: {:?}", level),
+                    }
+                    return;
+                }
+                Command::Dump | Command::Shutdown => return,
+            }
+        }
+        for task in self.consumers.lock().unwrap().iter() {
+            task.notify();
+        }
+    }
+
+    /// Return `true` if the given queue is paused.
+    pub fn is_paused(&self, queue: &str) -> bool {
+        self.paused.lock().unwrap().contains(queue)
+    }
+
+    /// Return `true` if the job with the given name should be executed.
+    pub fn allows(&self, job_name: &str) -> bool {
+        self.filter.lock().unwrap().allows(job_name)
+    }
+
+    /// Return the parallelism the worker was last asked to use, if any.
+    pub fn parallelism(&self) -> Option<u16> {
+        *self.parallelism.lock().unwrap()
+    }
+
+    /// Return the parallelism to apply if it changed since the given generation, updating it.
+    pub fn parallelism_since(&self, seen: &AtomicUsize) -> Option<u16> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        if seen.swap(generation, Ordering::SeqCst) == generation {
+            return None;
+        }
+        self.parallelism()
+    }
+
+    /// Register a consumer, returning the task to wake it up with when a command is applied.
+    pub fn register(&self) -> Arc<AtomicTask> {
+        let task = Arc::new(AtomicTask::new());
+        self.consumers.lock().unwrap().push(Arc::clone(&task));
+        task
+    }
+
+    /// Pause & resume the queues of the given consumer according to the applied commands.
+    pub fn update(&self, consumer: &mut Consumer) {
+        let (paused, resumed) = {
+            let paused = self.paused.lock().unwrap();
+            consumer
+                .queues()
+                .into_iter()
+                .map(str::to_string)
+                .partition::<Vec<_>, _>(|queue| paused.contains(queue))
+        };
+        for queue in paused {
+            consumer.pause(&queue);
+        }
+        for queue in resumed {
+            consumer.resume(&queue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn commands_are_applied() {
+        let handle = ControlHandle::new(Arc::new(Control::default()));
+        handle.pause("emails");
+        assert!(handle.is_paused("emails"));
+        assert!(!handle.is_paused("reports"));
+        handle.resume("emails");
+        assert!(!handle.is_paused("emails"));
+    }
+
+    #[test]
+    fn commands_are_serialized_as_json_objects() {
+        let message = Message {
+            worker: None,
+            command: Command::Pause {
+                queue: "emails".to_string(),
+            },
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(json, r#"{"command":"pause","queue":"emails"}"#);
+        assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), message);
+        assert!(message.is_for("worker-1"));
+
+        let message = Message {
+            worker: Some("worker-1".to_string()),
+            command: Command::Shutdown,
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(json, r#"{"worker":"worker-1","command":"shutdown"}"#);
+        assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), message);
+        assert!(message.is_for("worker-1"));
+        assert!(!message.is_for("worker-2"));
+    }
+
+    #[test]
+    fn excluded_jobs_are_filtered_out() {
+        let filter = JobFilter {
+            only: vec!["billing.*".to_string(), "reports.generate".to_string()],
+            except: vec!["billing.refunds".to_string()],
+        };
+        assert!(filter.allows("billing.invoices"));
+        assert!(filter.allows("reports.generate"));
+        assert!(!filter.allows("billing.refunds"));
+        assert!(!filter.allows("reports.heavy"));
+        assert!(JobFilter::default().allows("reports.heavy"));
+        let control = Arc::new(Control::with_filter(filter));
+        ControlHandle::new(Arc::clone(&control)).set_job_filter(&[], &["billing.*"]);
+        assert!(!control.allows("billing.invoices"));
+        assert!(control.allows("reports.heavy"));
+    }
+
+    #[test]
+    fn parallelism_changes_are_applied_once() {
+        let control = Control::default();
+        let seen = AtomicUsize::new(0);
+        assert_eq!(control.parallelism_since(&seen), None);
+        control.apply(&Command::Parallelism { parallelism: 2 });
+        assert_eq!(control.parallelism_since(&seen), Some(2));
+        assert_eq!(control.parallelism_since(&seen), None);
+    }
+}
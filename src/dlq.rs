@@ -0,0 +1,337 @@
+//! Replaying the jobs of dead-letter queues.
+//!
+//! Workers dead-letter the jobs they give up on with headers describing the failure (see
+//! `QueueBuilder::dead_letter`), including the exchange & routing key the job was originally
+//! published with, so that the jobs can be published again once the cause of their failure is
+//! fixed.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Future, Stream};
+use lapin::channel::{BasicProperties, BasicPublishOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use tokio_executor;
+use tokio_timer::Delay;
+
+use client::Client;
+use error::Error;
+use rabbitmq::{queue, Delivery};
+
+/// Name of the header holding the exchange a dead-lettered job was published to.
+pub(crate) const EXCHANGE_HEADER: &str = "x-batch-exchange";
+
+/// Name of the header holding the routing key a dead-lettered job was published with.
+pub(crate) const ROUTING_KEY_HEADER: &str = "x-batch-routing-key";
+
+/// Time without delivery after which a dead-letter queue is considered replayed.
+const IDLE_TIMEOUT_MILLIS: u64 = 1000;
+
+/// Replay the jobs of a dead-letter queue, publishing them again to their original exchange.
+///
+/// The replayed jobs are published without the headers describing their failure, so they get
+/// all their attempts again, and removed from the dead-letter queue. The jobs not matching the
+/// filters of the `Replayer` are left in the queue.
+///
+/// # Example
+///
+/// ```
+/// extern crate batch;
+/// extern crate futures;
+/// extern crate tokio;
+///
+/// use batch::{Client, Replayer};
+/// use futures::Future;
+/// use std::time::{Duration, SystemTime};
+///
+/// # fn main() {
+/// let incident = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+/// let task = Client::builder()
+///     .build()
+///     .and_then(move |client| {
+///         Replayer::new("batch.failures")
+///             .job("send-email")
+///             .failed_after(incident)
+///             .error_containing("connection refused")
+///             .rate(50)
+///             .run(&client)
+///     })
+///     .map(|count| println!("Replayed {} job(s)", count))
+///     .map_err(|e| eprintln!("Couldn't replay jobs: {}", e));
+/// # if false {
+/// tokio::run(task);
+/// # }
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Replayer {
+    queue: String,
+    jobs: Vec<String>,
+    failed_after: Option<SystemTime>,
+    failed_before: Option<SystemTime>,
+    error: Option<String>,
+    rate: Option<u32>,
+}
+
+impl Replayer {
+    /// Create a new `Replayer` replaying all the jobs of the given queue, which must exist.
+    pub fn new(queue: &str) -> Self {
+        Replayer {
+            queue: queue.to_string(),
+            jobs: Vec::new(),
+            failed_after: None,
+            failed_before: None,
+            error: None,
+            rate: None,
+        }
+    }
+
+    /// Only replay the jobs with the given name. Can be called several times to replay the jobs
+    /// with any of the given names.
+    pub fn job(mut self, name: &str) -> Self {
+        self.jobs.push(name.to_string());
+        self
+    }
+
+    /// Only replay the jobs that failed at or after the given time.
+    pub fn failed_after(mut self, time: SystemTime) -> Self {
+        self.failed_after = Some(time);
+        self
+    }
+
+    /// Only replay the jobs that failed before the given time.
+    pub fn failed_before(mut self, time: SystemTime) -> Self {
+        self.failed_before = Some(time);
+        self
+    }
+
+    /// Only replay the jobs whose error message contains the given text.
+    pub fn error_containing(mut self, text: &str) -> Self {
+        self.error = Some(text.to_string());
+        self
+    }
+
+    /// Publish at most the given number of jobs per second, so as not to overwhelm the workers or
+    /// the services the jobs depend on.
+    pub fn rate(mut self, jobs_per_second: u32) -> Self {
+        self.rate = Some(jobs_per_second);
+        self
+    }
+
+    /// Replay the jobs of the queue, resolving to the number of replayed jobs.
+    ///
+    /// The queue is considered replayed once it delivered no job for a second. The jobs left in
+    /// the queue are delivered again to other consumers only once the returned `Future`
+    /// completes.
+    pub fn run(&self, client: &Client) -> Box<Future<Item = usize, Error = Error> + Send> {
+        let replayer = self.clone();
+        let client = client.clone();
+        let dead_letters = queue(&self.queue).passive(true).build();
+        // Jobs left in the queue stay unacknowledged until the end, so the prefetch is unlimited.
+        let task = client
+            .consumer(Vec::new(), vec![dead_letters], 0)
+            .and_then(move |consumer| {
+                let handle = Arc::new(consumer.handle());
+                let requeue_handle = consumer.handle();
+                consumer
+                    .until_idle(Duration::from_millis(IDLE_TIMEOUT_MILLIS))
+                    .fold((0, Vec::new()), move |(count, mut skipped), delivery| {
+                        let route = match original_route(delivery.properties()) {
+                            Some(route) => route,
+                            None => {
+                                warn!(
+                                    "[{}] Skipping job without its original exchange",
+                                    delivery.task_id()
+                                );
+                                skipped.push(delivery.tag());
+                                return future::Either::A(future::ok((count, skipped)));
+                            }
+                        };
+                        if !replayer.matches(&delivery) {
+                            skipped.push(delivery.tag());
+                            return future::Either::A(future::ok((count, skipped)));
+                        }
+                        info!(
+                            "[{}] Replaying job to exchange {:?} with routing key {:?}",
+                            delivery.task_id(),
+                            route.0,
+                            route.1
+                        );
+                        let mut properties = delivery.properties().clone();
+                        if let Some(ref mut headers) = properties.headers {
+                            strip_failure_headers(headers);
+                        }
+                        let pause = replayer.rate.map(|rate| Duration::from_secs(1) / rate.max(1));
+                        let handle = Arc::clone(&handle);
+                        let tag = delivery.tag();
+                        let task = client
+                            .send(
+                                &route.0,
+                                &route.1,
+                                delivery.data(),
+                                &BasicPublishOptions::default(),
+                                properties,
+                            )
+                            .and_then(move |_| handle.ack(tag))
+                            .and_then(move |_| match pause {
+                                Some(pause) => future::Either::A(
+                                    Delay::new(Instant::now() + pause).then(|_| Ok(())),
+                                ),
+                                None => future::Either::B(future::ok(())),
+                            })
+                            .map(move |_| (count + 1, skipped));
+                        future::Either::B(task)
+                    })
+                    .map(move |(count, skipped)| {
+                        if !skipped.is_empty() {
+                            debug!("Leaving {} job(s) in the dead-letter queue", skipped.len());
+                        }
+                        for tag in skipped {
+                            let task = requeue_handle
+                                .requeue(tag)
+                                .map_err(|e| error!("Couldn't requeue skipped job: {}", e));
+                            tokio_executor::spawn(task);
+                        }
+                        count
+                    })
+            });
+        Box::new(task)
+    }
+
+    /// Return `true` if the given dead-lettered job matches the filters of this `Replayer`.
+    fn matches(&self, delivery: &Delivery) -> bool {
+        if !self.jobs.is_empty() && !self.jobs.iter().any(|name| name == delivery.task()) {
+            return false;
+        }
+        let headers = delivery.properties().headers.as_ref();
+        if self.failed_after.is_some() || self.failed_before.is_some() {
+            let failed_at = match headers.and_then(failed_at) {
+                Some(failed_at) => failed_at,
+                None => return false,
+            };
+            if self.failed_after.map_or(false, |after| failed_at < after)
+                || self.failed_before.map_or(false, |before| failed_at >= before)
+            {
+                return false;
+            }
+        }
+        if let Some(ref text) = self.error {
+            match headers.and_then(|headers| headers.get("x-batch-error")) {
+                Some(&AMQPValue::LongString(ref error)) if error.contains(text.as_str()) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Return the exchange & routing key a dead-lettered job was originally published with.
+///
+/// They are read from the headers set by the worker that dead-lettered the job, or from the
+/// `x-death` header set by RabbitMQ when it dead-lettered the job itself.
+fn original_route(properties: &BasicProperties) -> Option<(String, String)> {
+    let headers = properties.headers.as_ref()?;
+    match (headers.get(EXCHANGE_HEADER), headers.get(ROUTING_KEY_HEADER)) {
+        (Some(&AMQPValue::LongString(ref exchange)), Some(&AMQPValue::LongString(ref key))) => {
+            return Some((exchange.clone(), key.clone()));
+        }
+        _ => (),
+    }
+    let death = match headers.get("x-death") {
+        Some(&AMQPValue::FieldArray(ref deaths)) => match deaths.first() {
+            Some(&AMQPValue::FieldTable(ref death)) => death,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let exchange = match death.get("exchange") {
+        Some(&AMQPValue::LongString(ref exchange)) => exchange.clone(),
+        _ => return None,
+    };
+    let routing_key = match death.get("routing-keys") {
+        Some(&AMQPValue::FieldArray(ref keys)) => match keys.first() {
+            Some(&AMQPValue::LongString(ref key)) => key.clone(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some((exchange, routing_key))
+}
+
+/// Return the time a dead-lettered job failed at, if known.
+fn failed_at(headers: &FieldTable) -> Option<SystemTime> {
+    let secs = match headers.get("x-batch-failed-at") {
+        Some(&AMQPValue::Timestamp(secs)) => secs,
+        _ => match headers.get("x-death") {
+            Some(&AMQPValue::FieldArray(ref deaths)) => match deaths.first() {
+                Some(&AMQPValue::FieldTable(ref death)) => match death.get("time") {
+                    Some(&AMQPValue::Timestamp(secs)) => secs,
+                    _ => return None,
+                },
+                _ => return None,
+            },
+            _ => return None,
+        },
+    };
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Remove the headers describing the failure of a job and its previous attempts.
+fn strip_failure_headers(headers: &mut FieldTable) {
+    let failure_headers = headers
+        .keys()
+        .filter(|name| {
+            name.starts_with("x-batch-") || name.starts_with("x-death")
+                || name.starts_with("x-first-death-") || *name == "retries"
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in failure_headers {
+        headers.remove(&name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_headers_are_stripped_from_replayed_jobs() {
+        let mut headers = FieldTable::new();
+        headers.insert("task".to_string(), AMQPValue::LongString("send-email".to_string()));
+        headers.insert("retries".to_string(), AMQPValue::LongUInt(3));
+        headers.insert("x-batch-error".to_string(), AMQPValue::LongString("oops".to_string()));
+        headers.insert("x-batch-failed-at".to_string(), AMQPValue::Timestamp(42));
+        headers.insert(EXCHANGE_HEADER.to_string(), AMQPValue::LongString("emails".to_string()));
+        headers.insert(ROUTING_KEY_HEADER.to_string(), AMQPValue::LongString("send".to_string()));
+        let properties = BasicProperties {
+            headers: Some(headers.clone()),
+            ..BasicProperties::default()
+        };
+        let route = original_route(&properties).unwrap();
+        assert_eq!(route, ("emails".to_string(), "send".to_string()));
+        assert_eq!(failed_at(&headers), Some(UNIX_EPOCH + Duration::from_secs(42)));
+        strip_failure_headers(&mut headers);
+        assert_eq!(headers.keys().collect::<Vec<_>>(), vec!["task"]);
+    }
+
+    #[test]
+    fn route_falls_back_to_rabbitmq_deaths() {
+        let mut death = FieldTable::new();
+        death.insert("exchange".to_string(), AMQPValue::LongString("emails".to_string()));
+        let keys = vec![AMQPValue::LongString("send".to_string())];
+        death.insert("routing-keys".to_string(), AMQPValue::FieldArray(keys));
+        death.insert("time".to_string(), AMQPValue::Timestamp(42));
+        let mut headers = FieldTable::new();
+        let deaths = vec![AMQPValue::FieldTable(death)];
+        headers.insert("x-death".to_string(), AMQPValue::FieldArray(deaths));
+        let properties = BasicProperties {
+            headers: Some(headers.clone()),
+            ..BasicProperties::default()
+        };
+        let route = original_route(&properties).unwrap();
+        assert_eq!(route, ("emails".to_string(), "send".to_string()));
+        assert_eq!(failed_at(&headers), Some(UNIX_EPOCH + Duration::from_secs(42)));
+        assert_eq!(original_route(&BasicProperties::default()), None);
+    }
+}
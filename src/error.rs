@@ -5,6 +5,11 @@ use std::fmt;
 use std::result::Result as StdResult;
 
 /// `Error` type for the batch crate. Implements `Fail`.
+///
+/// The cause of an error can be matched programmatically through [`Error::kind`]. Use
+/// `Fail::compat` to convert it to a type implementing `std::error::Error`.
+///
+/// [`Error::kind`]: struct.Error.html#method.kind
 #[derive(Debug)]
 pub struct Error {
     inner: Context<ErrorKind>,
@@ -41,8 +46,15 @@ pub enum ErrorKind {
     #[fail(display = "The given connection URL is invalid: {}", _0)]
     InvalidUrl(::std::string::String),
 
-    /// The given priority is invalid, must be one of: trivial, low, normal, high, critical.
-    #[fail(display = "Invalid priority, must be one of: trivial, low, normal, high, critical.")]
+    /// No connection URL was found in the environment.
+    #[fail(display = "Neither BATCH_BROKER_URL nor AMQP_URL is set")]
+    NoConnectionUrl,
+
+    /// The given priority is invalid, must be one of: trivial, low, normal, high, critical, or a
+    /// number between 0 and 255.
+    #[fail(
+        display = "Invalid priority, must be one of: trivial, low, normal, high, critical, or a number between 0 and 255."
+    )]
     InvalidPriority,
 
     /// An error occured in the RabbitMQ broker.
@@ -51,12 +63,100 @@ pub enum ErrorKind {
 
     /// An error occured while setting up TLS.
     #[fail(display = "An error occured while setting up TLS: {}", _0)]
-    Tls(#[cause] ::native_tls::Error),
+    Tls(#[cause] ::std::io::Error),
+
+    /// Couldn't connect to the RabbitMQ broker, or the connection was lost.
+    #[fail(display = "Couldn't connect to the RabbitMQ broker: {}", _0)]
+    Connection(#[cause] ::std::io::Error),
+
+    /// Couldn't declare a queue, an exchange or a binding on the RabbitMQ broker.
+    #[fail(display = "Couldn't declare a queue or an exchange: {}", _0)]
+    Declare(#[cause] ::std::io::Error),
+
+    /// Couldn't connect to the RabbitMQ broker within the timeout of the `Connection`.
+    #[fail(display = "Couldn't connect to the RabbitMQ broker within {:?}", _0)]
+    Timeout(::std::time::Duration),
+
+    /// The value of an environment variable read by `Worker::from_env` is invalid.
+    #[fail(display = "The environment variable `{}' is invalid: {}", _0, _1)]
+    InvalidEnvironment(::std::string::String, ::std::string::String),
+
+    /// The configuration loaded by `Config` is invalid.
+    #[fail(display = "The configuration is invalid: {}", _0)]
+    InvalidConfig(::std::string::String),
+
+    /// The broker didn't accept a job within the publish timeout of the `Client`.
+    #[fail(
+        display = "The broker didn't accept the job within {:?}, it may be blocking publishers",
+        _0
+    )]
+    BrokerBlocked(::std::time::Duration),
+
+    /// The job isn't listed in the `Manifest` of the `Client`.
+    #[fail(display = "Job `{}' isn't handled by the workers of the manifest", _0)]
+    UnknownJob(::std::string::String),
+
+    /// An environment variable required by a job is not set.
+    #[fail(display = "Job `{}' requires the environment variable `{}' which is not set", _0, _1)]
+    MissingEnvironment(::std::string::String, ::std::string::String),
+
+    /// The serialized job is larger than the maximum payload size of the `Client`.
+    #[fail(
+        display = "The serialized job is {} bytes long, more than the maximum of {} bytes: consider compressing it, or storing its data elsewhere",
+        _0, _1
+    )]
+    PayloadTooLarge(usize, usize),
+
+    /// The payload of a job stored in a `PayloadStore` couldn't be retrieved.
+    #[fail(display = "Couldn't retrieve the payload `{}' of the job from a payload store", _0)]
+    MissingPayload(::std::string::String),
+
+    /// A queue or an exchange already exists with different options or arguments.
+    ///
+    /// `existing` is the reply of the broker, describing the difference, and `requested` the
+    /// options & arguments batch declared it with. See `Reconcile` to handle this automatically.
+    #[fail(
+        display = "`{}' already exists with different options or arguments: {} (requested {})",
+        name, existing, requested
+    )]
+    DeclareConflict {
+        /// The name of the queue or exchange.
+        name: ::std::string::String,
+        /// The reply of the broker.
+        existing: ::std::string::String,
+        /// The requested options & arguments.
+        requested: ::std::string::String,
+    },
+
+    /// The RabbitMQ management API replied with an unexpected HTTP status code.
+    #[fail(display = "The RabbitMQ management API replied with HTTP status {}", _0)]
+    Management(u16),
+
+    /// The state of a job was requested from a client without a status tracker.
+    #[fail(display = "No status tracker was configured")]
+    NoStatusTracker,
+
+    #[doc(hidden)]
+    #[fail(display = "")]
+    __Nonexhaustive,
 }
 
 impl Error {
-    /// Returns the underlying `Kind` of this error
-    pub(crate) fn kind(&self) -> &ErrorKind {
+    /// Returns the underlying `ErrorKind` of this error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::{Error, ErrorKind};
+    ///
+    /// fn is_transient(error: &Error) -> bool {
+    ///     match *error.kind() {
+    ///         ErrorKind::Connection(_) | ErrorKind::Timeout(_) | ErrorKind::Io(_) => true,
+    ///         _ => false,
+    ///     }
+    /// }
+    /// ```
+    pub fn kind(&self) -> &ErrorKind {
         self.inner.get_context()
     }
 
@@ -116,6 +216,46 @@ impl Error {
         }
     }
 
+    /// Returns true if the error is from a connection URL missing from the environment.
+    pub fn is_no_connection_url(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::NoConnectionUrl => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from an invalid environment variable.
+    pub fn is_invalid_environment(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::InvalidEnvironment(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from an invalid configuration.
+    pub fn is_invalid_config(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::InvalidConfig(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a publication the broker didn't accept in time.
+    pub fn is_broker_blocked(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::BrokerBlocked(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a job missing from the manifest of the client.
+    pub fn is_unknown_job(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::UnknownJob(_) => true,
+            _ => false,
+        }
+    }
+
     /// Returns true if the error is from an invalid priority.
     pub fn is_invalid_priority(&self) -> bool {
         match *self.kind() {
@@ -139,6 +279,79 @@ impl Error {
             _ => false,
         }
     }
+
+    /// Returns true if the error is from the connection to the `RabbitMQ` broker.
+    pub fn is_connection(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Connection(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from the declaration of a queue or an exchange.
+    pub fn is_declare(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Declare(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a connection that didn't succeed in time.
+    pub fn is_timeout(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Timeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from an environment variable required by a job being unset.
+    pub fn is_missing_environment(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::MissingEnvironment(_, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a job larger than the maximum payload size.
+    pub fn is_payload_too_large(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::PayloadTooLarge(_, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a payload missing from its payload store.
+    pub fn is_missing_payload(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::MissingPayload(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a queue or an exchange declared with different options
+    /// or arguments than the existing one.
+    pub fn is_declare_conflict(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::DeclareConflict { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from an unexpected reply of the RabbitMQ management API.
+    pub fn is_management(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Management(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a client without a status tracker.
+    pub fn is_no_status_tracker(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::NoStatusTracker => true,
+            _ => false,
+        }
+    }
 }
 
 impl Fail for Error {
@@ -0,0 +1,499 @@
+//! Information about the job being executed.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lapin::channel::BasicProperties;
+use lapin::types::AMQPValue;
+
+use cancellation;
+use error::{ErrorKind, Result};
+use executor::{self, Frame};
+use extensions::{self, Extensions};
+use job::Job;
+use progress::Progress;
+use query;
+use rabbitmq::Delivery;
+use ser;
+
+/// Names of the headers used internally by batch, which can't be set as custom headers.
+pub(crate) const RESERVED_HEADERS: &[&str] = &[
+    "lang",
+    "task",
+    "id",
+    "root_id",
+    "parent_id",
+    "group",
+    "timelimit",
+    "retries",
+    "idempotency_key",
+    DEADLINE_HEADER,
+    "payload_ref",
+    "fairness_key",
+    "ordering_key",
+    extensions::HEADER,
+    "argsrepr",
+    "kwargsrepr",
+    query::CANARY_HEADER,
+];
+
+/// Name of the header carrying the deadline of a job, in milliseconds since the Unix epoch.
+pub(crate) const DEADLINE_HEADER: &str = "deadline";
+
+thread_local! {
+    static CURRENT: RefCell<Option<Execution>> = RefCell::new(None);
+    static BATCH: RefCell<Vec<Execution>> = RefCell::new(Vec::new());
+}
+
+/// Information about the job currently being executed.
+///
+/// A job handler can retrieve it by calling `Execution::current`, e.g. to read the custom headers
+/// attached to the job when it was published.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::{Execution, Perform};
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_routing_key = "emails"]
+/// struct SendNewsletter;
+///
+/// impl Perform for SendNewsletter {
+///     type Context = ();
+///
+///     fn perform(&self, _ctx: Self::Context) {
+///         let tenant = Execution::current()
+///             .and_then(|execution| execution.header("tenant_id").map(String::from));
+///         println!("Sending newsletter for tenant {:?}", tenant);
+///     }
+/// }
+///
+/// # fn main() {}
+/// ```
+#[derive(Clone, Debug)]
+pub struct Execution {
+    id: String,
+    name: String,
+    attempt: u32,
+    deadline: Option<SystemTime>,
+    headers: BTreeMap<String, String>,
+    extensions: Extensions,
+    timed_out: Option<Arc<AtomicBool>>,
+}
+
+impl Execution {
+    /// Create a new `Execution` from the properties of a job's message.
+    pub(crate) fn from_properties(properties: &BasicProperties) -> Self {
+        let mut name = String::new();
+        let mut headers = BTreeMap::new();
+        if let Some(ref table) = properties.headers {
+            for (key, value) in table {
+                let value = match *value {
+                    AMQPValue::LongString(ref value) => value,
+                    _ => continue,
+                };
+                if key == "task" {
+                    name = value.clone();
+                } else if !RESERVED_HEADERS.contains(&key.as_str()) {
+                    headers.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        Execution {
+            id: properties.correlation_id.clone().unwrap_or_default(),
+            name,
+            attempt: 1,
+            deadline: deadline(properties),
+            headers,
+            extensions: Extensions::from_properties(properties),
+            timed_out: None,
+        }
+    }
+
+    /// Create a new `Execution` from a job's delivery, counting the previous attempts.
+    pub(crate) fn from_delivery(delivery: &Delivery) -> Self {
+        Execution {
+            attempt: delivery.attempt(),
+            ..Execution::from_properties(delivery.properties())
+        }
+    }
+
+    /// Return information about the job being executed by the current thread, if any.
+    pub fn current() -> Option<Execution> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Run the given closure with this `Execution` set as the current one.
+    pub(crate) fn scope<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        /// Restores the previous `Execution` when dropped, even if the closure panicked.
+        struct Restore(Option<Execution>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                CURRENT.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        let previous = CURRENT.with(|current| mem::replace(&mut *current.borrow_mut(), Some(self)));
+        let _restore = Restore(previous);
+        f()
+    }
+
+    /// Return information about the jobs of the batch being executed by the current thread, in
+    /// the order they were given to
+    /// [`PerformBatch::perform_batch`](trait.PerformBatch.html#tymethod.perform_batch).
+    ///
+    /// Outside of a batch handler, the returned list is empty.
+    pub fn batch() -> Vec<Execution> {
+        BATCH.with(|batch| batch.borrow().clone())
+    }
+
+    /// Run the given closure with the given `Execution`s set as the current batch.
+    pub(crate) fn scope_batch<F, R>(executions: Vec<Execution>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        /// Restores the previous batch when dropped, even if the closure panicked.
+        struct Restore(Option<Vec<Execution>>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take().unwrap_or_default();
+                BATCH.with(|batch| *batch.borrow_mut() = previous);
+            }
+        }
+
+        let previous = BATCH.with(|batch| mem::replace(&mut *batch.borrow_mut(), executions));
+        let _restore = Restore(Some(previous));
+        f()
+    }
+
+    /// Set the flag raised when the job times out while executed in the worker process.
+    pub(crate) fn with_timeout_flag(mut self, timed_out: Arc<AtomicBool>) -> Self {
+        self.timed_out = Some(timed_out);
+        self
+    }
+
+    /// Return the unique ID of the job.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return the name of the job.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the number of the current attempt at executing the job, starting at 1.
+    ///
+    /// Attempts are counted from the headers of the job, so the count survives worker restarts
+    /// and retries through dead-letter exchanges. A job redelivered after a worker crashed or was
+    /// interrupted counts as another attempt.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Execution;
+    ///
+    /// fn charge(execution: &Execution) {
+    ///     if execution.attempt() > 1 {
+    ///         println!("Checking whether the previous attempt charged the card");
+    ///     }
+    /// }
+    /// ```
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Return the deadline of the job, if it was published with one.
+    ///
+    /// See [`Query::deadline`](struct.Query.html#method.deadline).
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+
+    /// Return the time left before the deadline of the job, if it was published with one.
+    ///
+    /// Returns a zero duration once the deadline passed. Long-running jobs can check this to stop
+    /// early, or to bound the time they spend waiting on other services.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Execution;
+    /// use std::time::Duration;
+    ///
+    /// fn render_report(execution: &Execution) {
+    ///     let timeout = execution.time_remaining().unwrap_or(Duration::from_secs(30));
+    ///     println!("Querying the database with a timeout of {:?}", timeout);
+    /// }
+    /// ```
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Return true if the job was cancelled while running.
+    ///
+    /// Jobs are never interrupted when they are cancelled: long-running jobs should check this
+    /// regularly and return early once cancelled. See
+    /// [`Client::cancel`](struct.Client.html#method.cancel).
+    ///
+    /// Jobs executed in the worker process (see [`WorkerBuilder::in_process`]) are also cancelled
+    /// when they time out, since they can't be killed.
+    ///
+    /// [`WorkerBuilder::in_process`]: struct.WorkerBuilder.html#method.in_process
+    pub fn is_cancelled(&self) -> bool {
+        let timed_out = self.timed_out
+            .as_ref()
+            .map_or(false, |timed_out| timed_out.load(Ordering::SeqCst));
+        timed_out || (!self.id.is_empty() && cancellation::is_marked(&self.id))
+    }
+
+    /// Report the progress of the job to the clients following it.
+    ///
+    /// Reports are published by the worker, if it enabled them with
+    /// [`WorkerBuilder::enable_progress`], and received with [`Client::progress`]. Reports made
+    /// in quick succession may be merged, only the latest one being published.
+    ///
+    /// [`WorkerBuilder::enable_progress`]: struct.WorkerBuilder.html#method.enable_progress
+    /// [`Client::progress`]: struct.Client.html#method.progress
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::{Execution, Progress};
+    ///
+    /// fn transcode(execution: &Execution) {
+    ///     for step in 0..10 {
+    ///         // ...
+    ///         let progress = Progress::new(step * 10).with_message("Encoding");
+    ///         if let Err(e) = execution.report_progress(&progress) {
+    ///             eprintln!("Couldn't report progress: {}", e);
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn report_progress(&self, progress: &Progress) -> Result<()> {
+        progress.record(&self.id);
+        Ok(())
+    }
+
+    /// Publish this job again with a new payload, once the given delay has elapsed.
+    ///
+    /// The new job is published by the worker after the current one completes, with the same
+    /// name, exchange, routing key & headers but a new ID. This is useful for jobs that process
+    /// a chunk of data and schedule themselves to process the rest. Calling this method again
+    /// replaces the previous request, and it has no effect when the job is executed with
+    /// [`Query::perform_now`](struct.Query.html#method.perform_now).
+    ///
+    /// The delay is rounded up to 1, 5, 10 or 30 seconds, 1, 5, 10 or 30 minutes, 1, 2, 6 or 12
+    /// hours, or a whole number of days, so that the broker holds a bounded number of delay
+    /// queues.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::{Execution, Perform};
+    /// use std::time::Duration;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "imports"]
+    /// struct ImportUsers {
+    ///     page: u32,
+    /// }
+    ///
+    /// impl Perform for ImportUsers {
+    ///     type Context = ();
+    ///
+    ///     fn perform(&self, _ctx: Self::Context) {
+    ///         let has_more = false; // import the current page...
+    ///         if has_more {
+    ///             let next = ImportUsers { page: self.page + 1 };
+    ///             if let Some(execution) = Execution::current() {
+    ///                 execution.retry_with(&next, Duration::from_secs(60)).unwrap();
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub fn retry_with<T: Job>(&self, job: &T, delay: Duration) -> Result<()> {
+        let reschedule = Reschedule {
+            data: ser::to_vec(job).map_err(ErrorKind::Serialization)?,
+            delay,
+        };
+        reschedule.record(&self.id);
+        Ok(())
+    }
+
+    /// Return the value of a custom header attached to the job.
+    ///
+    /// See [`Query::header`](struct.Query.html#method.header).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_ref)
+    }
+
+    /// Return all the custom headers attached to the job.
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    /// Return the extensions attached to the job.
+    ///
+    /// See [`Query::extension`](struct.Query.html#method.extension).
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+}
+
+/// Return the deadline set in the given job properties, if any.
+pub(crate) fn deadline(properties: &BasicProperties) -> Option<SystemTime> {
+    let headers = properties.headers.as_ref()?;
+    match headers.get(DEADLINE_HEADER) {
+        Some(&AMQPValue::LongLongInt(millis)) if millis >= 0 => {
+            Some(UNIX_EPOCH + Duration::from_millis(millis as u64))
+        }
+        _ => None,
+    }
+}
+
+/// Return the value of the header carrying the given deadline.
+pub(crate) fn deadline_header(deadline: SystemTime) -> AMQPValue {
+    let elapsed = deadline.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let millis = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000);
+    AMQPValue::LongLongInt(millis as i64)
+}
+
+/// A request to publish a job again, made with `Execution::retry_with`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Reschedule {
+    pub data: Vec<u8>,
+    pub delay: Duration,
+}
+
+/// The requests made by the jobs of the current process, until the worker takes them.
+static RESCHEDULES: Reports<Reschedule> = Reports::new();
+
+impl Reschedule {
+    /// Record this request for the worker executing the given job.
+    pub fn record(self, job_id: &str) {
+        if executor::is_executor() {
+            executor::send(&Frame::Rescheduled {
+                job: job_id.to_string(),
+                reschedule: self,
+            });
+        } else {
+            RESCHEDULES.put(job_id, self);
+        }
+    }
+
+    /// Remove the request made by the given job and return it, if any.
+    pub fn take(job_id: &str) -> Option<Reschedule> {
+        RESCHEDULES.take(job_id)
+    }
+}
+
+/// Details about the failure of a job, recorded by the process executing it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FailureReport {
+    pub message: String,
+    pub backtrace: Option<String>,
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+/// The failures of the jobs of the current process, until the worker takes them.
+static FAILURES: Reports<FailureReport> = Reports::new();
+
+impl FailureReport {
+    /// Record the failure of the given job, for the worker to attach it to the dead-lettered job.
+    pub fn record(self, job_id: &str) {
+        if executor::is_executor() {
+            executor::send(&Frame::Report {
+                job: job_id.to_string(),
+                report: self,
+            });
+        } else {
+            FAILURES.put(job_id, self);
+        }
+    }
+
+    /// Remove the failure recorded by the given job and return it, if any.
+    pub fn take(job_id: &str) -> Option<FailureReport> {
+        FAILURES.take(job_id)
+    }
+}
+
+/// The reports made by the jobs executed by the current process, by job ID.
+///
+/// Executor processes send their reports to the worker as frames instead, which the worker
+/// records here, see `executor::Relay`.
+#[derive(Debug)]
+pub(crate) struct Reports<T> {
+    reports: Mutex<BTreeMap<String, T>>,
+}
+
+impl<T> Reports<T> {
+    pub const fn new() -> Self {
+        Reports {
+            reports: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record the report of the given job, replacing the previous one.
+    pub fn put(&self, job_id: &str, report: T) {
+        self.reports
+            .lock()
+            .unwrap()
+            .insert(job_id.to_string(), report);
+    }
+
+    /// Remove the report of the given job and return it, if any.
+    pub fn take(&self, job_id: &str) -> Option<T> {
+        self.reports.lock().unwrap().remove(job_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic;
+
+    #[test]
+    fn scope_restores_the_current_execution_after_a_panic() {
+        let execution = Execution::from_properties(&BasicProperties::default());
+        let job = panic::AssertUnwindSafe(|| execution.scope(|| panic!("job panicked")));
+        let result = panic::catch_unwind(job);
+        assert!(result.is_err());
+        assert!(Execution::current().is_none());
+    }
+}
@@ -0,0 +1,490 @@
+//! Protocol between a worker and the processes executing its jobs.
+//!
+//! The worker writes a versioned `Request` to the standard input of the executor process, on its
+//! own line, followed by an `Instruction` per line while the jobs run, e.g. when one is
+//! cancelled. The executor reports the state of its jobs, their progress & failures with
+//! `Frame`s written to its standard output, each on its own line and prefixed with a marker. The
+//! rest of the output of the process is the output of the job: each line of it is logged, tagged
+//! with the ID of the job. This lets the worker tell a job that failed from an executor that
+//! crashed before starting it, whatever the exit code of the process.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::Level;
+use serde::de::DeserializeOwned;
+
+use cancellation;
+use de;
+use error::{ErrorKind, Result};
+use execution::{FailureReport, Reschedule};
+use job::{Failure as JobFailure, Status as JobStatus};
+use limits::{self, ResourceLimits};
+use progress::Progress;
+use sandbox::Sandbox;
+use ser;
+
+/// Version of the protocol, increased when a change breaks it.
+///
+/// The executor process is started from the worker's executable, which may have been replaced
+/// since the worker started, e.g. during a deployment.
+pub(crate) const VERSION: u32 = 2;
+
+/// Whether the current process is an executor process.
+static EXECUTOR: AtomicBool = AtomicBool::new(false);
+
+/// Prefix of the lines of the executor's output holding a `Frame`.
+const MARKER: &[u8] = b"\x1ebatch-rs:";
+
+/// Time to wait for the last frames once the executor process exited.
+const DRAIN_TIMEOUT_MILLIS: u64 = 500;
+
+/// Target of the log records holding the output of jobs.
+const OUTPUT_TARGET: &str = "batch::output";
+
+/// The jobs an executor process is asked to execute, and the limits of its resources.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Request<T> {
+    pub version: u32,
+    pub jobs: T,
+    #[serde(default)]
+    pub limits: Option<ResourceLimits>,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+}
+
+impl<T> Request<T> {
+    pub fn new(jobs: T, limits: Option<ResourceLimits>, sandbox: Sandbox) -> Self {
+        Request {
+            version: VERSION,
+            jobs,
+            limits,
+            sandbox,
+        }
+    }
+}
+
+/// A report sent by an executor process to the worker.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "frame", rename_all = "lowercase")]
+pub(crate) enum Frame {
+    /// The executor is about to call the handler of the job.
+    Started,
+    /// The handler of the job completed successfully.
+    Completed,
+    /// The job failed, with the given error message.
+    Failed { message: String },
+    /// The job with the given ID reported its progress.
+    Progress { job: String, progress: Progress },
+    /// The job with the given ID asked to be published again.
+    Rescheduled { job: String, reschedule: Reschedule },
+    /// The job with the given ID failed, with details about its failure.
+    Report { job: String, report: FailureReport },
+}
+
+/// An instruction sent by the worker to an executor process, after its request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "instruction", rename_all = "lowercase")]
+pub(crate) enum Instruction {
+    /// The job with the given ID was cancelled, see `Execution::is_cancelled`.
+    Cancel { job: String },
+}
+
+/// Return `true` if the current process is an executor process, which sends the reports of its
+/// jobs to the worker instead of recording them.
+pub(crate) fn is_executor() -> bool {
+    EXECUTOR.load(Ordering::SeqCst)
+}
+
+/// Read the request sent by the worker on the standard input and apply its resource limits &
+/// sandbox, in the executor process.
+pub(crate) fn receive<T: DeserializeOwned>() -> Result<T> {
+    EXECUTOR.store(true, Ordering::SeqCst);
+    let mut line = Vec::new();
+    let read = io::stdin().lock().read_until(b'\n', &mut line);
+    let parsed = read
+        .map_err(::serde_json::Error::io)
+        .and_then(|_| de::from_slice(&line));
+    let request: Request<T> = match parsed {
+        Ok(request) => request,
+        Err(e) => {
+            send(&Frame::Failed {
+                message: format!("Couldn't read the jobs to execute: {}", e),
+            });
+            return Err(ErrorKind::Deserialization(e).into());
+        }
+    };
+    if request.version != VERSION {
+        let message = format!(
+            "Unsupported executor protocol version {} (expected {})",
+            request.version, VERSION
+        );
+        send(&Frame::Failed {
+            message: message.clone(),
+        });
+        let e = io::Error::new(io::ErrorKind::InvalidData, message);
+        return Err(ErrorKind::SubProcessManagement(e).into());
+    }
+    if let Some(ref limits) = request.limits {
+        if let Err(e) = limits.apply() {
+            send(&Frame::Failed {
+                message: format!("Couldn't apply the resource limits: {}", e),
+            });
+            return Err(ErrorKind::SubProcessManagement(e).into());
+        }
+    }
+    if let Err(e) = request.sandbox.apply() {
+        send(&Frame::Failed {
+            message: format!("Couldn't apply the sandbox: {}", e),
+        });
+        return Err(ErrorKind::SubProcessManagement(e).into());
+    }
+    thread::spawn(receive_instructions);
+    Ok(request.jobs)
+}
+
+/// Read the instructions sent by the worker on the standard input, until it is closed.
+fn receive_instructions() {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Couldn't read the instructions of the worker: {}", e);
+                return;
+            }
+        };
+        match de::from_str(&line) {
+            Ok(Instruction::Cancel { job }) => cancellation::mark(&job),
+            Err(e) => warn!("Invalid instruction from the worker: {}", e),
+        }
+    }
+}
+
+/// Send the given instruction to an executor process through its standard input, in the worker.
+pub(crate) fn instruct<W: Write>(stdin: &mut W, instruction: &Instruction) -> io::Result<()> {
+    let serialized =
+        ser::to_vec(instruction).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    stdin.write_all(&serialized)?;
+    stdin.write_all(b"\n")?;
+    stdin.flush()
+}
+
+/// Send the given frame to the worker, in the executor process.
+pub(crate) fn send(frame: &Frame) {
+    let result = ser::to_vec(frame)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .and_then(|serialized| {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(MARKER)?;
+            stdout.write_all(&serialized)?;
+            stdout.write_all(b"\n")?;
+            stdout.flush()
+        });
+    if let Err(e) = result {
+        error!("Couldn't report to the worker: {:?}: {}", frame, e);
+    }
+}
+
+/// The output of an executor process, read on dedicated threads.
+#[derive(Debug)]
+pub(crate) struct Relay {
+    frames: Receiver<Frame>,
+    tail: Arc<Mutex<VecDeque<String>>>,
+    out_of_memory: Arc<AtomicBool>,
+}
+
+impl Relay {
+    /// Start reading the standard output & error of an executor process executing the given
+    /// jobs.
+    ///
+    /// Each line of output is logged with the `batch::output` target, prefixed with the ID of the
+    /// jobs, or written to the same stream of the worker if no logger records this target. The
+    /// last `keep` lines are kept, see `Relay::tail`. The progress, reschedules &
+    /// failures reported by the jobs are recorded as they are received.
+    pub fn start<O, E>(stdout: O, stderr: E, job_ids: &[&str], keep: usize) -> Self
+    where
+        O: Read + Send + 'static,
+        E: Read + Send + 'static,
+    {
+        let (sender, frames) = mpsc::channel();
+        let tail = Arc::new(Mutex::new(VecDeque::new()));
+        let out_of_memory = Arc::new(AtomicBool::new(false));
+        let label = match job_ids.len() {
+            1 => job_ids[0].to_string(),
+            count => format!("{} +{}", job_ids.first().unwrap_or(&""), count - 1),
+        };
+        let output = Output {
+            label: Arc::new(label),
+            jobs: Arc::new(job_ids.iter().map(|job_id| job_id.to_string()).collect()),
+            tail: Arc::clone(&tail),
+            keep,
+            out_of_memory: Arc::clone(&out_of_memory),
+            stderr: false,
+        };
+        let errors = Output {
+            stderr: true,
+            ..output.clone()
+        };
+        thread::spawn(move || output.read(stdout, Some(sender)));
+        thread::spawn(move || errors.read(stderr, None));
+        Relay {
+            frames,
+            tail,
+            out_of_memory,
+        }
+    }
+
+    /// Return the frames about the state of the jobs sent by the process once it exited, waiting
+    /// shortly for the last ones.
+    pub fn drain(&self) -> Vec<Frame> {
+        let mut drained = Vec::new();
+        let timeout = Duration::from_millis(DRAIN_TIMEOUT_MILLIS);
+        loop {
+            match self.frames.recv_timeout(timeout) {
+                Ok(frame) => drained.push(frame),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        drained
+    }
+
+    /// Return the last lines of output of the process, if any were kept.
+    pub fn tail(&self) -> Option<String> {
+        let tail = self.tail.lock().unwrap();
+        if tail.is_empty() {
+            return None;
+        }
+        Some(tail.iter().cloned().collect::<Vec<_>>().join("\n"))
+    }
+
+    /// Return `true` if the process reported that it ran out of memory.
+    pub fn out_of_memory(&self) -> bool {
+        self.out_of_memory.load(Ordering::SeqCst)
+    }
+}
+
+/// A stream of output of an executor process.
+#[derive(Clone, Debug)]
+struct Output {
+    label: Arc<String>,
+    jobs: Arc<Vec<String>>,
+    tail: Arc<Mutex<VecDeque<String>>>,
+    keep: usize,
+    out_of_memory: Arc<AtomicBool>,
+    /// Whether this is the standard error of the process.
+    stderr: bool,
+}
+
+impl Output {
+    /// Log the lines of the given stream, sending the frames it holds to `frames`, if any.
+    fn read<R: Read>(&self, stream: R, frames: Option<Sender<Frame>>) {
+        let mut stream = BufReader::new(stream);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match stream.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("[{}] Couldn't read the output of the job: {}", self.label, e);
+                    break;
+                }
+            }
+            // A frame may follow output of the job which didn't end with a new line.
+            let (output, frame) = match (find(&line, MARKER), frames.as_ref()) {
+                (Some(index), Some(_)) => (&line[..index], parse(&line[index + MARKER.len()..])),
+                _ => (&line[..], None),
+            };
+            let output = String::from_utf8_lossy(output);
+            let output = output.trim_matches(|c| c == '\r' || c == '\n');
+            if !output.is_empty() {
+                self.log(output);
+            }
+            if let (Some(frame), Some(frames)) = (frame, frames.as_ref()) {
+                if let Some(frame) = self.record(frame) {
+                    let _ = frames.send(frame);
+                }
+            }
+        }
+    }
+
+    /// Record the reports of the jobs held by the given frame, returning it if it isn't one.
+    fn record(&self, frame: Frame) -> Option<Frame> {
+        let job_id = match frame {
+            Frame::Progress { ref job, .. }
+            | Frame::Rescheduled { ref job, .. }
+            | Frame::Report { ref job, .. } => job.clone(),
+            _ => return Some(frame),
+        };
+        if !self.jobs.contains(&job_id) {
+            warn!("[{}] Ignoring report about another job: {}", self.label, job_id);
+            return None;
+        }
+        match frame {
+            Frame::Progress { progress, .. } => progress.record(&job_id),
+            Frame::Rescheduled { reschedule, .. } => reschedule.record(&job_id),
+            Frame::Report { report, .. } => report.record(&job_id),
+            _ => unreachable!(),
+        }
+        None
+    }
+
+    fn log(&self, line: &str) {
+        // Without a logger recording it, the output would be lost.
+        if log_enabled!(target: OUTPUT_TARGET, Level::Info) {
+            info!(target: OUTPUT_TARGET, "[{}] {}", self.label, line);
+        } else if self.stderr {
+            let _ = writeln!(io::stderr(), "[{}] {}", self.label, line);
+        } else {
+            let _ = writeln!(io::stdout(), "[{}] {}", self.label, line);
+        }
+        if limits::is_allocation_failure(line) {
+            self.out_of_memory.store(true, Ordering::SeqCst);
+        }
+        if self.keep == 0 {
+            return;
+        }
+        let mut tail = self.tail.lock().unwrap();
+        if tail.len() == self.keep {
+            tail.pop_front();
+        }
+        tail.push_back(line.to_string());
+    }
+}
+
+/// Return the position of `needle` in `haystack`, if any.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Parse a frame from the output of an executor process, following its marker.
+fn parse(serialized: &[u8]) -> Option<Frame> {
+    match de::from_slice(serialized) {
+        Ok(frame) => Some(frame),
+        Err(e) => {
+            warn!("Invalid report from an executor process: {}", e);
+            None
+        }
+    }
+}
+
+/// Return the status of the jobs of an executor process from its frames, and how it exited.
+///
+/// `exit_failure` is the failure to report if the process exited without reporting the result of
+/// its jobs, according to its exit code.
+pub(crate) fn outcome(
+    frames: &[Frame],
+    exit_failure: JobFailure,
+    description: String,
+) -> (JobStatus, Option<String>) {
+    let started = frames.iter().any(|frame| *frame == Frame::Started);
+    let result = frames.iter().rev().find(|frame| **frame != Frame::Started);
+    match result {
+        Some(&Frame::Completed) => (JobStatus::Success, Some(description)),
+        Some(&Frame::Failed { .. }) if started => {
+            (JobStatus::Failed(JobFailure::Error), Some(description))
+        }
+        _ if started => (JobStatus::Failed(exit_failure), Some(description)),
+        _ => (
+            JobStatus::Failed(JobFailure::Crash),
+            Some(format!("{}, before starting the job", description)),
+        ),
+    }
+}
+
+/// Return the error message reported by an executor process, if any.
+pub(crate) fn error_message(frames: &[Frame]) -> Option<&str> {
+    frames
+        .iter()
+        .rev()
+        .filter_map(|frame| match *frame {
+            Frame::Failed { ref message } => Some(message.as_str()),
+            _ => None,
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_follow_their_marker() {
+        let mut line = b"job output".to_vec();
+        line.extend_from_slice(MARKER);
+        line.extend_from_slice(b"{\"frame\":\"failed\",\"message\":\"oops\"}\n");
+        let index = find(&line, MARKER).unwrap();
+        assert_eq!(&line[..index], b"job output");
+        let frame = Frame::Failed {
+            message: "oops".to_string(),
+        };
+        assert_eq!(parse(&line[index + MARKER.len()..]), Some(frame));
+        assert_eq!(find(b"{\"frame\":\"started\"}\n", MARKER), None);
+    }
+
+    #[test]
+    fn last_lines_of_output_are_kept() {
+        let mut stdout = b"first\nsecond\r\n".to_vec();
+        stdout.extend_from_slice(MARKER);
+        stdout.extend_from_slice(b"{\"frame\":\"completed\"}\nthird".as_ref());
+        let relay = Relay::start(
+            io::Cursor::new(stdout),
+            io::Cursor::new(b"".to_vec()),
+            &["job"],
+            2,
+        );
+        assert_eq!(relay.drain(), vec![Frame::Completed]);
+        assert_eq!(relay.tail().unwrap(), "second\nthird");
+    }
+
+    #[test]
+    fn reports_of_jobs_are_recorded() {
+        let mut stdout = Vec::new();
+        for job in &["tests-relay", "tests-relay-other"] {
+            let frame = Frame::Progress {
+                job: job.to_string(),
+                progress: Progress::new(42),
+            };
+            stdout.extend_from_slice(MARKER);
+            stdout.extend_from_slice(&ser::to_vec(&frame).unwrap());
+            stdout.push(b'\n');
+        }
+        let relay = Relay::start(
+            io::Cursor::new(stdout),
+            io::Cursor::new(b"".to_vec()),
+            &["tests-relay"],
+            0,
+        );
+        assert_eq!(relay.drain(), vec![]);
+        assert_eq!(Progress::take("tests-relay"), Some(Progress::new(42)));
+        // Executors can't report about the jobs of other processes.
+        assert_eq!(Progress::take("tests-relay-other"), None);
+    }
+
+    #[test]
+    fn outcome_tells_failures_from_crashes() {
+        let exited = || "exit code: 1".to_string();
+        let failed = Frame::Failed {
+            message: "oops".to_string(),
+        };
+        let (status, _) = outcome(&[Frame::Started, Frame::Completed], JobFailure::Crash, exited());
+        assert_eq!(status, JobStatus::Success);
+        let (status, _) = outcome(&[Frame::Started, failed.clone()], JobFailure::Crash, exited());
+        assert_eq!(status, JobStatus::Failed(JobFailure::Error));
+        assert_eq!(error_message(&[Frame::Started, failed.clone()]), Some("oops"));
+        let (status, _) = outcome(&[Frame::Started], JobFailure::Crash, exited());
+        assert_eq!(status, JobStatus::Failed(JobFailure::Crash));
+        let (status, description) = outcome(&[failed], JobFailure::Error, exited());
+        assert_eq!(status, JobStatus::Failed(JobFailure::Crash));
+        assert_eq!(description.unwrap(), "exit code: 1, before starting the job");
+    }
+}
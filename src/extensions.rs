@@ -0,0 +1,143 @@
+//! Typed data attached to jobs by applications & integrations.
+
+use std::collections::BTreeMap;
+
+use lapin::channel::BasicProperties;
+use lapin::types::AMQPValue;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use de;
+use error::{ErrorKind, Result};
+use ser;
+
+/// Name of the header carrying the extensions of a job, serialized as a JSON object.
+pub(crate) const HEADER: &str = "extensions";
+
+/// Serializable data attached to a job, by key.
+///
+/// Unlike custom headers, which are plain strings, extensions can be any serializable value, e.g.
+/// the context of a trace or the identity of a tenant. They are sent along with the job, see
+/// [`Query::extension`], and read back by its handler with [`Execution::extensions`].
+///
+/// [`Query::extension`]: struct.Query.html#method.extension
+/// [`Execution::extensions`]: struct.Execution.html#method.extensions
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate serde;
+/// extern crate batch;
+///
+/// use batch::Extensions;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct TraceContext {
+///     trace_id: String,
+///     span_id: String,
+/// }
+///
+/// # fn main() {
+/// let mut extensions = Extensions::new();
+/// let context = TraceContext {
+///     trace_id: "4bf92f3577b34da6".into(),
+///     span_id: "00f067aa0ba902b7".into(),
+/// };
+/// extensions.insert("trace", &context).unwrap();
+/// let context: TraceContext = extensions.get("trace").unwrap().unwrap();
+/// assert_eq!(context.span_id, "00f067aa0ba902b7");
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Extensions(BTreeMap<String, Value>);
+
+impl Extensions {
+    /// Create a new, empty `Extensions`.
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+
+    /// Attach the given value with the given key, replacing the previous value if any.
+    pub fn insert<V: Serialize>(&mut self, key: &str, value: &V) -> Result<()> {
+        let value = serde_json::to_value(value).map_err(ErrorKind::Serialization)?;
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    /// Return the value attached with the given key, if any.
+    pub fn get<V: DeserializeOwned>(&self, key: &str) -> Result<Option<V>> {
+        match self.0.get(key) {
+            Some(value) => {
+                let value = serde_json::from_value(value.clone())
+                    .map_err(ErrorKind::Deserialization)?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the value attached with the given key, returning `true` if there was one.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.0.remove(key).is_some()
+    }
+
+    /// Return `true` if a value is attached with the given key.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// Return the keys of the attached values, sorted.
+    pub fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_ref).collect()
+    }
+
+    /// Return `true` if no value is attached.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Read the extensions from the given job properties, ignoring them if they are invalid.
+    pub(crate) fn from_properties(properties: &BasicProperties) -> Self {
+        let header = properties.headers.as_ref().and_then(|headers| headers.get(HEADER));
+        match header {
+            Some(&AMQPValue::LongString(ref json)) => de::from_str(json).unwrap_or_else(|e| {
+                warn!("Ignoring invalid extensions of job: {}", e);
+                Extensions::new()
+            }),
+            _ => Extensions::new(),
+        }
+    }
+
+    /// Return the value of the header carrying these extensions.
+    pub(crate) fn to_header(&self) -> Result<AMQPValue> {
+        let json = ser::to_string(self).map_err(ErrorKind::Serialization)?;
+        Ok(AMQPValue::LongString(json))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::types::FieldTable;
+
+    #[test]
+    fn extensions_round_trip_through_headers() {
+        let mut extensions = Extensions::new();
+        extensions.insert("tenant", &42).unwrap();
+        extensions.insert("tags", &vec!["a", "b"]).unwrap();
+        let mut headers = FieldTable::new();
+        headers.insert(HEADER.to_string(), extensions.to_header().unwrap());
+        let properties = BasicProperties {
+            headers: Some(headers),
+            ..BasicProperties::default()
+        };
+        let extensions = Extensions::from_properties(&properties);
+        assert_eq!(extensions.keys(), vec!["tags", "tenant"]);
+        assert_eq!(extensions.get::<u64>("tenant").unwrap(), Some(42));
+        assert_eq!(extensions.get::<Vec<String>>("tags").unwrap().unwrap(), vec!["a", "b"]);
+        assert_eq!(extensions.get::<u64>("trace").unwrap(), None);
+        assert!(extensions.get::<String>("tenant").is_err());
+    }
+}
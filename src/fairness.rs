@@ -0,0 +1,256 @@
+//! Fair & ordered scheduling of jobs.
+//!
+//! Jobs carry an optional fairness key (e.g. the ID of a tenant), see
+//! [`Query::fairness_key`](struct.Query.html#method.fairness_key). Workers configured with a
+//! `Fairness` policy buffer the jobs they receive by key, and interleave their execution instead
+//! of executing them in the order they were published.
+//!
+//! Jobs also carry an optional ordering key, see
+//! [`Query::ordering_key`](struct.Query.html#method.ordering_key). Workers executing jobs in order
+//! buffer them the same way, and skip the jobs whose ordering key is already being executed.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::task::AtomicTask;
+use futures::{Async, Poll, Stream};
+
+use error;
+use rabbitmq;
+
+/// Name of the header carrying the fairness key of a job.
+pub(crate) const HEADER: &str = "fairness_key";
+
+/// Name of the header carrying the ordering key of a job.
+pub(crate) const ORDERING_HEADER: &str = "ordering_key";
+
+/// How a `Worker` interleaves the execution of jobs with different fairness keys.
+///
+/// See [`WorkerBuilder::fair_scheduling`](struct.WorkerBuilder.html#method.fair_scheduling).
+/// Jobs without a fairness key are grouped under an empty key.
+///
+/// # Example
+///
+/// ```
+/// use batch::Fairness;
+/// use std::collections::HashMap;
+///
+/// let mut weights = HashMap::new();
+/// weights.insert("premium-tenant".to_string(), 4);
+/// let fairness = Fairness::Weighted(weights);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fairness {
+    /// Execute one job of each key in turn.
+    RoundRobin,
+    /// Execute up to the given number of jobs of each key in turn, keys missing from the map
+    /// having a weight of 1.
+    Weighted(HashMap<String, u32>),
+}
+
+impl Fairness {
+    fn weight(&self, key: &str) -> u32 {
+        match *self {
+            Fairness::RoundRobin => 1,
+            Fairness::Weighted(ref weights) => weights.get(key).cloned().unwrap_or(1).max(1),
+        }
+    }
+}
+
+/// Items waiting to be scheduled, grouped by fairness key.
+#[derive(Debug)]
+pub(crate) struct FairQueue<T> {
+    fairness: Fairness,
+    keys: VecDeque<String>,
+    pending: HashMap<String, VecDeque<T>>,
+    served: u32,
+}
+
+impl<T> FairQueue<T> {
+    pub fn new(fairness: Fairness) -> Self {
+        FairQueue {
+            fairness,
+            keys: VecDeque::new(),
+            pending: HashMap::new(),
+            served: 0,
+        }
+    }
+
+    /// Add an item with the given fairness key.
+    pub fn push(&mut self, key: &str, item: T) {
+        if !self.pending.contains_key(key) {
+            self.keys.push_back(key.to_string());
+        }
+        self.pending
+            .entry(key.to_string())
+            .or_insert_with(VecDeque::new)
+            .push_back(item);
+    }
+
+    /// Remove the next item to schedule, according to the fairness policy.
+    pub fn pop(&mut self) -> Option<T> {
+        self.pop_ready(|_| true)
+    }
+
+    /// Remove the next item to schedule among the ready ones, according to the fairness policy.
+    ///
+    /// A key without ready items loses its turn.
+    pub fn pop_ready<F: Fn(&T) -> bool>(&mut self, ready: F) -> Option<T> {
+        for _ in 0..self.keys.len() {
+            let key = self.keys.front()?.clone();
+            let (item, exhausted) = {
+                let items = self.pending.get_mut(&key)?;
+                let item = items
+                    .iter()
+                    .position(|item| ready(item))
+                    .and_then(|index| items.remove(index));
+                (item, items.is_empty())
+            };
+            if item.is_none() {
+                self.keys.pop_front();
+                self.keys.push_back(key);
+                self.served = 0;
+                continue;
+            }
+            self.served += 1;
+            if exhausted {
+                self.pending.remove(&key);
+                self.keys.pop_front();
+                self.served = 0;
+            } else if self.served >= self.fairness.weight(&key) {
+                self.keys.pop_front();
+                self.keys.push_back(key);
+                self.served = 0;
+            }
+            return item;
+        }
+        None
+    }
+}
+
+/// Maximum number of jobs prefetched for each one executed in parallel with fair scheduling.
+pub(crate) const FAIR_PREFETCH_FACTOR: u16 = 10;
+
+/// The deliveries of a consumer waiting to be executed with fair scheduling, or in order.
+#[derive(Debug)]
+pub(crate) struct FairBuffer {
+    queue: Mutex<FairQueue<rabbitmq::Delivery>>,
+    parallelism: usize,
+    running: AtomicUsize,
+    ordered: bool,
+    running_keys: Mutex<HashSet<String>>,
+    task: Mutex<Option<Arc<AtomicTask>>>,
+}
+
+impl FairBuffer {
+    pub fn new(fairness: Fairness, parallelism: u16, ordered: bool) -> Self {
+        FairBuffer {
+            queue: Mutex::new(FairQueue::new(fairness)),
+            parallelism: parallelism.max(1) as usize,
+            running: AtomicUsize::new(0),
+            ordered,
+            running_keys: Mutex::new(HashSet::new()),
+            task: Mutex::new(None),
+        }
+    }
+
+    /// Wake the given task up when a job completes.
+    pub fn wake(&self, task: &Arc<AtomicTask>) {
+        *self.task.lock().unwrap() = Some(Arc::clone(task));
+    }
+
+    pub fn start_job(&self, ordering_key: Option<&str>) {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        if let (true, Some(key)) = (self.ordered, ordering_key) {
+            self.running_keys.lock().unwrap().insert(key.to_string());
+        }
+    }
+
+    pub fn finish_job(&self, ordering_key: Option<&str>) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        if let (true, Some(key)) = (self.ordered, ordering_key) {
+            self.running_keys.lock().unwrap().remove(key);
+        }
+        if let Some(ref task) = *self.task.lock().unwrap() {
+            task.notify();
+        }
+    }
+
+    /// Buffer the deliveries received by the consumer, returning the next one to execute once
+    /// fewer jobs than the parallelism are running, and none with the same ordering key.
+    pub fn poll(
+        &self,
+        consumer: &mut rabbitmq::Consumer,
+    ) -> Poll<Option<rabbitmq::Delivery>, error::Error> {
+        loop {
+            match consumer.poll()? {
+                Async::Ready(Some(delivery)) => {
+                    let key = delivery.fairness_key().unwrap_or("").to_string();
+                    self.queue.lock().unwrap().push(&key, delivery);
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => break,
+            }
+        }
+        if self.running.load(Ordering::SeqCst) >= self.parallelism {
+            return Ok(Async::NotReady);
+        }
+        let running_keys = self.running_keys.lock().unwrap();
+        let ready = |delivery: &rabbitmq::Delivery| match delivery.ordering_key() {
+            Some(key) if self.ordered => !running_keys.contains(key),
+            _ => true,
+        };
+        match self.queue.lock().unwrap().pop_ready(ready) {
+            Some(delivery) => Ok(Async::Ready(Some(delivery))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(mut queue: FairQueue<&'static str>) -> Vec<&'static str> {
+        let mut items = Vec::new();
+        while let Some(item) = queue.pop() {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn keys_are_interleaved() {
+        let mut queue = FairQueue::new(Fairness::RoundRobin);
+        for item in &["a1", "a2", "a3"] {
+            queue.push("a", *item);
+        }
+        queue.push("b", "b1");
+        queue.push("", "c1");
+        assert_eq!(drain(queue), vec!["a1", "b1", "c1", "a2", "a3"]);
+
+        let mut weights = HashMap::new();
+        weights.insert("a".to_string(), 2);
+        let mut queue = FairQueue::new(Fairness::Weighted(weights));
+        for item in &["a1", "a2", "a3"] {
+            queue.push("a", *item);
+        }
+        queue.push("b", "b1");
+        queue.push("b", "b2");
+        assert_eq!(drain(queue), vec!["a1", "a2", "b1", "a3", "b2"]);
+    }
+
+    #[test]
+    fn busy_items_are_skipped() {
+        let mut queue = FairQueue::new(Fairness::RoundRobin);
+        for item in &["x1", "y1", "x2"] {
+            queue.push("a", *item);
+        }
+        queue.push("b", "x3");
+        let ready = |item: &&str| !item.starts_with('x');
+        assert_eq!(queue.pop_ready(ready), Some("y1"));
+        assert_eq!(queue.pop_ready(ready), None);
+        assert_eq!(drain(queue), vec!["x3", "x1", "x2"]);
+    }
+}
@@ -0,0 +1,257 @@
+//! Groups of jobs, completing once all their jobs finished.
+//!
+//! Workers publish a notification to a dedicated exchange when a job of a group finishes, either
+//! successfully or after its last attempt, using the ID of the group as routing key. The
+//! notifications are collected in a queue declared before the jobs of the group are sent, so
+//! that none of them is missed.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use lapin::channel::{BasicProperties, BasicPublishOptions};
+use lapin::types::AMQPValue;
+use tokio_executor;
+use uuid::Uuid;
+
+use client::Client;
+use de;
+use error::{Error, Result};
+use job::Job;
+use outbox::OutboxMessage;
+use query::Query;
+use rabbitmq::{exchange, queue, Delivery, Publisher};
+use ser;
+use status::JobState;
+
+/// Name of the exchange the completion of the jobs of groups is published to.
+pub(crate) const EXCHANGE: &str = "batch.groups";
+
+/// Time after which the queue of a group nobody waits for anymore is deleted by the broker.
+const QUEUE_EXPIRATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A group of jobs, e.g. the jobs a workload is split into, to be notified of their completion.
+///
+/// The group is opened before its jobs are sent, see [`Query::group`], and
+/// [`GroupHandle::join`] then resolves once all of them finished. A completion job can be sent at
+/// that point, e.g. to combine the results of the jobs of the group.
+///
+/// [`Query::group`]: struct.Query.html#method.group
+/// [`GroupHandle::join`]: struct.GroupHandle.html#method.join
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// extern crate futures;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+/// extern crate tokio;
+///
+/// use batch::{job, Client, Group};
+/// use futures::{future, Future};
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_routing_key = "images"]
+/// struct ResizeImage {
+///     image_id: u64,
+/// }
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_routing_key = "images"]
+/// struct BuildArchive {
+///     album_id: u64,
+/// }
+///
+/// # fn main() {
+/// # fn example() -> Result<(), batch::Error> {
+/// let group = Group::new().then(job(BuildArchive { album_id: 42 }))?;
+/// let task = Client::builder()
+///     .build()
+///     .and_then(move |client| {
+///         group.open(&client).and_then(move |group| {
+///             let sent = (0..500)
+///                 .map(|image_id| job(ResizeImage { image_id }).group(&group).send(&client))
+///                 .collect::<Vec<_>>();
+///             future::join_all(sent).and_then(move |handles| group.join(handles.len()))
+///         })
+///     })
+///     .map(|failed| println!("Archive requested, {} image(s) couldn't be resized", failed))
+///     .map_err(|e| eprintln!("Couldn't resize images: {}", e));
+/// # if false {
+/// tokio::run(task);
+/// # }
+/// # Ok(())
+/// # }
+/// # example().unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Group {
+    id: String,
+    completion: Option<OutboxMessage>,
+}
+
+impl Group {
+    /// Create a new `Group`, with a unique ID.
+    pub fn new() -> Self {
+        Group {
+            id: Uuid::new_v4().to_string(),
+            completion: None,
+        }
+    }
+
+    /// Send the given job once all the jobs of the group finished, see
+    /// [`GroupHandle::join`](struct.GroupHandle.html#method.join).
+    ///
+    /// This fails if the job can't be serialized.
+    pub fn then<T: Job + Send + 'static>(mut self, query: Query<T>) -> Result<Self> {
+        self.completion = Some(query.stage()?);
+        Ok(self)
+    }
+
+    /// Return the unique ID of the group.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Declare the queue collecting the completion of the jobs of the group, resolving to a
+    /// handle to send its jobs with.
+    ///
+    /// The queue is deleted by the broker if nobody waits for the group for a week.
+    pub fn open(self, client: &Client) -> Box<Future<Item = GroupHandle, Error = Error> + Send> {
+        let client = client.clone();
+        let expiration = QUEUE_EXPIRATION.as_secs() as u32 * 1000;
+        let completions = queue(&queue_name(&self.id))
+            .bind(EXCHANGE, &self.id)
+            .argument("x-expires", AMQPValue::LongUInt(expiration))
+            .build();
+        let task = client
+            .declare(vec![exchange(EXCHANGE).build()], vec![completions])
+            .map(move |_| GroupHandle {
+                id: self.id,
+                completion: self.completion,
+                client,
+            });
+        Box::new(task)
+    }
+}
+
+impl Default for Group {
+    fn default() -> Self {
+        Group::new()
+    }
+}
+
+/// An opened [`Group`](struct.Group.html), whose jobs can be sent.
+#[derive(Debug)]
+pub struct GroupHandle {
+    id: String,
+    completion: Option<OutboxMessage>,
+    client: Client,
+}
+
+impl GroupHandle {
+    /// Return the unique ID of the group.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Wait for the given number of jobs of the group to finish, resolving to the number of jobs
+    /// that failed.
+    ///
+    /// Jobs finish once they succeeded, or failed and won't be retried anymore. The completion
+    /// job of the group, if any, is sent once they all finished, whether some failed or not: it
+    /// is sent by the process waiting for the group, so no completion job is sent if that process
+    /// stops before.
+    pub fn join(self, size: usize) -> Box<Future<Item = usize, Error = Error> + Send> {
+        let client = self.client.clone();
+        let completions = queue(&queue_name(&self.id)).passive(true).build();
+        let completion = self.completion;
+        let task = self.client
+            .consumer(Vec::new(), vec![completions], 0)
+            .and_then(move |consumer| {
+                let handle = consumer.handle();
+                let mut finished = HashSet::new();
+                // A job executed again, e.g. after its worker crashed, is only counted once.
+                consumer
+                    .filter(move |delivery| {
+                        let ack = handle
+                            .ack(delivery.tag())
+                            .map_err(|e| error!("Couldn't acknowledge job completion: {}", e));
+                        tokio_executor::spawn(ack);
+                        finished.insert(delivery.task_id().to_string())
+                    })
+                    .take(size as u64)
+                    .fold(0, move |failed, delivery| {
+                        match de::from_slice(delivery.data()) {
+                            Ok(JobState::Failed) => Ok::<_, Error>(failed + 1),
+                            Ok(_) => Ok(failed),
+                            Err(e) => {
+                                warn!("Invalid job completion: {}", e);
+                                Ok(failed)
+                            }
+                        }
+                    })
+            })
+            .and_then(move |failed| match completion {
+                Some(completion) => {
+                    let entry = completion.into_entry();
+                    let task = client
+                        .send(
+                            &entry.exchange,
+                            &entry.routing_key,
+                            &entry.data,
+                            &entry.options(),
+                            entry.properties.clone(),
+                        )
+                        .map(move |_| failed);
+                    future::Either::A(task)
+                }
+                None => future::Either::B(future::ok(failed)),
+            });
+        Box::new(task)
+    }
+}
+
+/// Publish the final state of a job to the exchange of the group it belongs to, if any.
+pub(crate) fn notify(publisher: &Publisher, delivery: &Delivery, state: JobState) {
+    let group_id = match delivery.group() {
+        Some(group_id) if state.is_finished() => group_id.to_string(),
+        _ => return,
+    };
+    let job_id = delivery.task_id().to_string();
+    let serialized = match ser::to_vec(&state) {
+        Ok(serialized) => serialized,
+        Err(e) => {
+            error!("[{}] Couldn't serialize job completion: {}", job_id, e);
+            return;
+        }
+    };
+    let properties = BasicProperties {
+        correlation_id: Some(job_id),
+        ..BasicProperties::default()
+    };
+    let sender = publisher.clone();
+    let task = publisher
+        .declare_exchanges(vec![exchange(EXCHANGE).build()])
+        .and_then(move |_| {
+            sender.send(
+                EXCHANGE,
+                &group_id,
+                &serialized,
+                &BasicPublishOptions::default(),
+                properties,
+            )
+        })
+        .map_err(|e| error!("Couldn't publish job completion: {}", e));
+    tokio_executor::spawn(task);
+}
+
+/// Return the name of the queue collecting the completion of the jobs of the given group.
+fn queue_name(group_id: &str) -> String {
+    format!("batch.group.{}", group_id)
+}
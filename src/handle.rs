@@ -0,0 +1,101 @@
+//! References to the jobs sent to a broker.
+
+use std::time::SystemTime;
+
+use futures::{Future, Stream};
+
+use client::Client;
+use error::Error;
+use progress::Progress;
+use status::JobState;
+
+/// A job sent to the broker, returned by [`Query::send`](struct.Query.html#method.send).
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// extern crate futures;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+/// extern crate tokio;
+///
+/// use batch::{job, Client};
+/// use futures::Future;
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_routing_key = "exports"]
+/// struct ExportAccount {
+///     account_id: u64,
+/// }
+///
+/// # fn main() {
+/// let task = Client::builder()
+///     .build()
+///     .and_then(|client| job(ExportAccount { account_id: 42 }).send(&client))
+///     .map(|handle| println!("Exporting account in job {}", handle.id()))
+///     .map_err(|e| eprintln!("Couldn't send job: {}", e));
+/// # if false {
+/// tokio::run(task);
+/// # }
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct JobHandle {
+    id: String,
+    name: String,
+    enqueued_at: SystemTime,
+    client: Client,
+}
+
+impl JobHandle {
+    pub(crate) fn new(id: String, name: String, client: Client) -> Self {
+        JobHandle {
+            id,
+            name,
+            enqueued_at: SystemTime::now(),
+            client,
+        }
+    }
+
+    /// Return the unique ID of the job.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return the name of the job.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the time the broker accepted the job at.
+    pub fn enqueued_at(&self) -> SystemTime {
+        self.enqueued_at
+    }
+
+    /// Cancel the job, see [`Client::cancel`](struct.Client.html#method.cancel).
+    pub fn cancel(&self) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.client.cancel(&self.id)
+    }
+
+    /// Receive the progress reported by the job, see
+    /// [`Client::progress`](struct.Client.html#method.progress).
+    ///
+    /// The progress reported before subscribing is lost: use `Client::progress` before sending
+    /// the job to follow it from its start.
+    pub fn progress(
+        &self,
+    ) -> Box<
+        Future<Item = Box<Stream<Item = Progress, Error = Error> + Send>, Error = Error> + Send,
+    > {
+        self.client.progress(&self.id)
+    }
+
+    /// Return the state of the job, see [`Client::status`](struct.Client.html#method.status).
+    pub fn status(&self) -> Box<Future<Item = Option<JobState>, Error = Error> + Send> {
+        self.client.status(&self.id)
+    }
+}
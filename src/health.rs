@@ -0,0 +1,163 @@
+//! Health reporting for process supervisors.
+//!
+//! The worker can expose its health on a Unix socket: each connection receives a single line of
+//! JSON, e.g. `{"live":true,"ready":true}`, before being closed. The worker is live as long as its
+//! event loop keeps ticking, and ready once it is connected to the broker and consuming its
+//! queues. When started by systemd with `Type=notify`, the worker also notifies systemd when it
+//! becomes ready, and pings its watchdog from the event loop if `WatchdogSec` is set.
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use tokio_timer::Interval;
+
+/// Interval between two ticks of the event loop, in seconds.
+const TICK_SECS: u64 = 1;
+
+/// Number of seconds after which the worker isn't live anymore if its event loop didn't tick.
+const LIVENESS_TIMEOUT_SECS: u64 = 10;
+
+/// Health of a worker, shared between the event loop and the health socket.
+#[derive(Debug)]
+pub(crate) struct Health {
+    last_tick: Mutex<Instant>,
+    ready: AtomicBool,
+    watchdog: bool,
+}
+
+impl Health {
+    /// Create a new `Health`, listening on the given Unix socket if any.
+    pub fn start(socket: Option<&Path>) -> io::Result<Arc<Health>> {
+        let health = Arc::new(Health {
+            last_tick: Mutex::new(Instant::now()),
+            ready: AtomicBool::new(false),
+            watchdog: watchdog_enabled(),
+        });
+        if let Some(path) = socket {
+            listen(Arc::clone(&health), path)?;
+        }
+        Ok(health)
+    }
+
+    /// Return a `Future` ticking on the event loop until the process exits.
+    pub fn heartbeat(health: Arc<Health>) -> Box<Future<Item = (), Error = ()> + Send> {
+        let task = Interval::new(Instant::now(), Duration::from_secs(TICK_SECS))
+            .for_each(move |_| {
+                health.tick();
+                Ok(())
+            })
+            .map_err(|e| error!("Health heartbeat stopped: {}", e));
+        Box::new(task)
+    }
+
+    fn tick(&self) {
+        *self.last_tick.lock().unwrap() = Instant::now();
+        if self.watchdog {
+            notify("WATCHDOG=1");
+        }
+    }
+
+    /// Mark the worker as ready (or not) to execute jobs.
+    pub fn set_ready(&self, ready: bool) {
+        let was_ready = self.ready.swap(ready, Ordering::SeqCst);
+        if ready && !was_ready {
+            notify("READY=1");
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        self.last_tick.lock().unwrap().elapsed() < Duration::from_secs(LIVENESS_TIMEOUT_SECS)
+    }
+
+    fn is_ready(&self) -> bool {
+        self.is_live() && self.ready.load(Ordering::SeqCst)
+    }
+}
+
+/// Answer every connection on the given socket with the current health of the worker.
+fn listen(health: Arc<Health>, path: &Path) -> io::Result<()> {
+    // Remove the socket left behind by a previous worker, if any.
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Reporting health on {:?}", path);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let report = format!(
+                "{{\"live\":{},\"ready\":{}}}\n",
+                health.is_live(),
+                health.is_ready()
+            );
+            let result = stream.and_then(|mut stream| stream.write_all(report.as_bytes()));
+            if let Err(e) = result {
+                warn!("Couldn't report health: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Return true if systemd expects watchdog notifications from this process.
+fn watchdog_enabled() -> bool {
+    if env::var_os("WATCHDOG_USEC").is_none() {
+        return false;
+    }
+    match env::var("WATCHDOG_PID") {
+        Ok(pid) => pid == process::id().to_string(),
+        Err(_) => true,
+    }
+}
+
+/// Send the given state to systemd, if the process was started with a notification socket.
+fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if path.to_string_lossy().starts_with('@') {
+        debug!("Abstract notification sockets are not supported, not sending {:?}", state);
+        return;
+    }
+    let result = UnixDatagram::unbound()
+        .and_then(|socket| socket.send_to(state.as_bytes(), &path));
+    if let Err(e) = result {
+        warn!("Couldn't notify systemd ({:?}): {}", state, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn socket_reports_health() {
+        let path = env::temp_dir().join(format!("batch-health-test-{}.sock", process::id()));
+        let health = Health::start(Some(&path)).unwrap();
+        let report = || {
+            let mut report = String::new();
+            UnixStream::connect(&path)
+                .unwrap()
+                .read_to_string(&mut report)
+                .unwrap();
+            report
+        };
+        assert_eq!(report(), "{\"live\":true,\"ready\":false}\n");
+        health.set_ready(true);
+        assert_eq!(report(), "{\"live\":true,\"ready\":true}\n");
+        let _ = fs::remove_file(&path);
+    }
+}
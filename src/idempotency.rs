@@ -0,0 +1,238 @@
+//! Deduplication of the jobs published with an idempotency key, or delivered more than once.
+//!
+//! Workers configured with an `IdempotencyStore` record the keys of the jobs that completed
+//! successfully, and acknowledge the deliveries of jobs with a recorded key without executing them.
+//! Workers with a deduplication window do the same with the IDs of the jobs they recently
+//! completed, to skip the copies `RabbitMQ` delivers again after losing an acknowledgement.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use futures::{future, Future};
+
+use blocking::Blocking;
+use clock::Clock;
+use error::{Error, Result};
+use rabbitmq::Delivery;
+
+/// Name of the header carrying the idempotency key of a job.
+pub(crate) const HEADER: &str = "idempotency_key";
+
+/// Maximum number of job IDs remembered by `RecentJobs`, however short the window.
+const MAX_RECENT_JOBS: usize = 100_000;
+
+/// A store of the idempotency keys of the jobs that completed successfully.
+///
+/// See [`WorkerBuilder::idempotency_store`]. The store is only used by the worker process, before
+/// & after executing jobs, so it doesn't have to be shared with the processes executing them.
+///
+/// [`WorkerBuilder::idempotency_store`]: struct.WorkerBuilder.html#method.idempotency_store
+pub trait IdempotencyStore: fmt::Debug + Send + Sync {
+    /// Return `true` if a job with the given key already completed successfully.
+    fn contains(&self, key: &str) -> Result<bool>;
+
+    /// Record that a job with the given key completed successfully.
+    fn insert(&self, key: &str) -> Result<()>;
+}
+
+/// An `IdempotencyStore` remembering the most recent keys in memory.
+///
+/// Keys are lost when the worker stops, and aren't shared with other workers: use a store backed
+/// by a database to deduplicate jobs across workers & restarts.
+///
+/// # Example
+///
+/// ```
+/// use batch::{IdempotencyStore, MemoryIdempotencyStore};
+///
+/// let store = MemoryIdempotencyStore::new(2);
+/// store.insert("a").unwrap();
+/// store.insert("b").unwrap();
+/// store.insert("c").unwrap();
+/// assert!(!store.contains("a").unwrap());
+/// assert!(store.contains("c").unwrap());
+/// ```
+#[derive(Debug)]
+pub struct MemoryIdempotencyStore {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    keys: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl MemoryIdempotencyStore {
+    /// Create a new `MemoryIdempotencyStore` remembering up to `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        MemoryIdempotencyStore {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+}
+
+impl Default for MemoryIdempotencyStore {
+    /// Create a new `MemoryIdempotencyStore` remembering up to 10,000 keys.
+    fn default() -> Self {
+        MemoryIdempotencyStore::new(10_000)
+    }
+}
+
+impl IdempotencyStore for MemoryIdempotencyStore {
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.inner.lock().unwrap().keys.contains(key))
+    }
+
+    fn insert(&self, key: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.keys.insert(key.to_string()) {
+            inner.order.push_back(key.to_string());
+        }
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.keys.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An `IdempotencyStore` called on its own thread, so that a slow store doesn't block the reactor.
+#[derive(Clone, Debug)]
+pub(crate) struct StoreHandle {
+    store: Arc<IdempotencyStore>,
+    blocking: Blocking,
+}
+
+impl StoreHandle {
+    pub fn new(store: Arc<IdempotencyStore>) -> Self {
+        StoreHandle {
+            store,
+            blocking: Blocking::new("batch-idempotency"),
+        }
+    }
+
+    /// Resolve to `true` if a job with the given key already completed successfully.
+    ///
+    /// Errors are logged, and resolve to `false` so that the job with the given ID is executed.
+    pub fn contains(
+        &self,
+        job_id: &str,
+        key: &str,
+    ) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let (store, job_id, key) = (Arc::clone(&self.store), job_id.to_string(), key.to_string());
+        self.blocking.run(move || {
+            Ok(store.contains(&key).unwrap_or_else(|e| {
+                error!("[{}] Couldn't check idempotency key: {}", job_id, e);
+                false
+            }))
+        })
+    }
+
+    /// Record that the job with the given ID & key completed successfully, resolving once it is
+    /// recorded.
+    ///
+    /// Errors are logged, and never fail the returned `Future`.
+    pub fn insert(&self, job_id: &str, key: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        let (store, job_id, key) = (Arc::clone(&self.store), job_id.to_string(), key.to_string());
+        self.blocking.run(move || {
+            if let Err(e) = store.insert(&key) {
+                error!("[{}] Couldn't record idempotency key: {}", job_id, e);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Record the idempotency key of the given delivery's job in the given store, if any, once the job
+/// completed successfully.
+pub(crate) fn record(
+    store: Option<&StoreHandle>,
+    delivery: &Delivery,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    match (store, delivery.idempotency_key()) {
+        (Some(store), Some(key)) => store.insert(delivery.task_id(), key),
+        _ => Box::new(future::ok(())),
+    }
+}
+
+/// The IDs of the jobs a worker completed successfully during the last `window`, see
+/// `WorkerBuilder::deduplication_window`.
+#[derive(Debug)]
+pub(crate) struct RecentJobs {
+    window: Duration,
+    clock: Arc<Clock>,
+    inner: Mutex<Recent>,
+}
+
+#[derive(Debug, Default)]
+struct Recent {
+    completed_at: HashMap<String, SystemTime>,
+    order: VecDeque<(String, SystemTime)>,
+}
+
+impl RecentJobs {
+    pub fn new(window: Duration, clock: Arc<Clock>) -> Self {
+        RecentJobs {
+            window,
+            clock,
+            inner: Mutex::new(Recent::default()),
+        }
+    }
+
+    /// Return `true` if the job with the given ID completed during the window.
+    pub fn contains(&self, job_id: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        self.expire(&mut inner);
+        inner.completed_at.contains_key(job_id)
+    }
+
+    /// Record that the job with the given ID completed successfully.
+    pub fn insert(&self, job_id: &str) {
+        let now = self.clock.system_time();
+        let mut inner = self.inner.lock().unwrap();
+        inner.completed_at.insert(job_id.to_string(), now);
+        inner.order.push_back((job_id.to_string(), now));
+        self.expire(&mut inner);
+    }
+
+    fn expire(&self, inner: &mut Recent) {
+        let now = self.clock.system_time();
+        while let Some((job_id, completed_at)) = inner.order.pop_front() {
+            let expired = completed_at + self.window <= now;
+            if !expired && inner.order.len() < MAX_RECENT_JOBS {
+                inner.order.push_front((job_id, completed_at));
+                break;
+            }
+            // The job may have completed again since, e.g. if it was published twice.
+            if inner.completed_at.get(&job_id) == Some(&completed_at) {
+                inner.completed_at.remove(&job_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::MockClock;
+
+    #[test]
+    fn recent_jobs_expire_after_the_window() {
+        let clock = MockClock::new();
+        let recent = RecentJobs::new(Duration::from_secs(60), Arc::new(clock.clone()));
+        recent.insert("a");
+        clock.advance(Duration::from_secs(30));
+        recent.insert("b");
+        assert!(recent.contains("a"));
+        clock.advance(Duration::from_secs(30));
+        assert!(!recent.contains("a"));
+        assert!(recent.contains("b"));
+        assert!(!recent.contains("c"));
+    }
+}
@@ -0,0 +1,131 @@
+//! Jobs consumed from a single queue, without a `Worker`.
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+
+use futures::{Future, Stream};
+use tokio_executor;
+
+use client::Client;
+use de;
+use error::Error;
+use execution::Execution;
+use job::{redact, Job};
+use rabbitmq::{self, ConsumerHandle, Queue};
+
+/// A job consumed from a queue, see [`QueueBuilder::consume`].
+///
+/// The job should be acknowledged with [`Incoming::ack`] once processed, or rejected with
+/// [`Incoming::reject`]. A job that is neither stays unacknowledged until the connection is
+/// closed, and is then delivered again.
+///
+/// [`QueueBuilder::consume`]: struct.QueueBuilder.html#method.consume
+/// [`Incoming::ack`]: struct.Incoming.html#method.ack
+/// [`Incoming::reject`]: struct.Incoming.html#method.reject
+pub struct Incoming<T> {
+    job: T,
+    delivery: rabbitmq::Delivery,
+    handle: Arc<ConsumerHandle>,
+}
+
+impl<T> fmt::Debug for Incoming<T>
+where
+    T: Job + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        let job = if T::redacted_fields().is_empty() {
+            format!("{:?}", self.job)
+        } else {
+            redact(&self.job)
+        };
+        write!(
+            f,
+            "Incoming {{ id: {:?} job: {} }}",
+            self.delivery.task_id(),
+            job
+        )
+    }
+}
+
+impl<T> Incoming<T> {
+    /// Return the consumed job.
+    pub fn job(&self) -> &T {
+        &self.job
+    }
+
+    /// Return the ID, name and headers of the consumed job.
+    pub fn execution(&self) -> Execution {
+        Execution::from_delivery(&self.delivery)
+    }
+
+    /// Acknowledge the job, removing it from the queue.
+    ///
+    /// Returns a `Future` that completes once the `ack` is sent to the broker.
+    pub fn ack(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.handle.ack(self.delivery.tag())
+    }
+
+    /// Reject the job, removing it from the queue or dead-lettering it if the queue has a
+    /// dead-letter exchange.
+    ///
+    /// Returns a `Future` that completes once the `reject` is sent to the broker.
+    pub fn reject(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.handle.reject(self.delivery.tag())
+    }
+}
+
+/// Consume the jobs of type `T` published to the given queue.
+pub(crate) fn consume<T>(
+    client: &Client,
+    queue: Queue,
+) -> Box<Stream<Item = Incoming<T>, Error = Error> + Send>
+where
+    T: Job + Send + 'static,
+{
+    let task = client
+        .consumer(Vec::new(), vec![queue], 1)
+        .map(|consumer| {
+            let handle = Arc::new(consumer.handle());
+            consumer.filter_map(move |delivery| decode(delivery, &handle))
+        })
+        .flatten_stream();
+    Box::new(task)
+}
+
+/// Decode the job of a delivery, rejecting the delivery if it isn't a `T`.
+fn decode<T>(delivery: rabbitmq::Delivery, handle: &Arc<ConsumerHandle>) -> Option<Incoming<T>>
+where
+    T: Job,
+{
+    let name = delivery.task();
+    let result = if name == T::name() || T::aliases().iter().any(|alias| alias == name) {
+        #[cfg(feature = "celery-compat")]
+        let data = ::celery::payload(&delivery);
+        #[cfg(not(feature = "celery-compat"))]
+        let data = Ok(delivery.data().to_vec());
+        data.and_then(|data| de::from_slice(&data)).map_err(|e| e.to_string())
+    } else {
+        Err(format!("expected `{}'", T::name()))
+    };
+    match result {
+        Ok(job) => Some(Incoming {
+            job,
+            delivery,
+            handle: Arc::clone(handle),
+        }),
+        Err(e) => {
+            warn!(
+                "[{}] Rejecting job `{}': {}",
+                delivery.task_id(),
+                delivery.task(),
+                e
+            );
+            let reject = handle
+                .reject(delivery.tag())
+                .map_err(|e| error!("Couldn't reject job: {}", e));
+            tokio_executor::spawn(reject);
+            None
+        }
+    }
+}
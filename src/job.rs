@@ -1,12 +1,18 @@
 //! A trait representing a job.
 
+use std::cmp::Ordering;
 use std::str::FromStr;
 use std::time::Duration;
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use serde_json::{self, Value};
 
 use error::{Error, ErrorKind, Result};
+use sandbox::Sandbox;
+
+/// Value replacing the redacted fields of a job.
+const REDACTED: &str = "[REDACTED]";
 
 /// A job and its related metadata (name, queue, timeout, etc.)
 ///
@@ -49,11 +55,43 @@ use error::{Error, ErrorKind, Result};
 /// #[job_routing_key = "emails"]
 /// #[job_timeout = "120"]
 /// #[job_retries = "0"]
+/// #[job_env = "SMTP_HOST, SMTP_PASSWORD"]
 /// struct SendPasswordResetEmail;
 ///
 /// #
 /// # fn main() {}
 /// ```
+///
+/// With a type parameter only used by the handler of the job:
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate batch;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::Job;
+/// use std::marker::PhantomData;
+///
+/// #[derive(Default)]
+/// struct Elasticsearch;
+///
+/// /// Reindex the documents updated since the given timestamp.
+/// #[derive(Deserialize, Serialize, Job)]
+/// #[job_name = "search:reindex"]
+/// #[job_routing_key = "search"]
+/// struct Reindex<B> {
+///     since: u64,
+///     #[serde(skip)]
+///     backend: PhantomData<B>,
+/// }
+///
+/// # fn main() {
+/// assert_eq!(Reindex::<Elasticsearch>::routing_key(), "search");
+/// # }
+/// ```
 pub trait Job: DeserializeOwned + Serialize {
     /// A should-be-unique human-readable ID for this job.
     fn name() -> &'static str;
@@ -64,6 +102,14 @@ pub trait Job: DeserializeOwned + Serialize {
     /// The routing key associated to this job.
     fn routing_key() -> &'static str;
 
+    /// The former names of this job.
+    ///
+    /// Workers also execute the jobs published with these names, e.g. by older clients, so that
+    /// renaming a job doesn't orphan the jobs already in the queues.
+    fn aliases() -> &'static [String] {
+        &[]
+    }
+
     /// The number of times this job must be retried in case of error.
     fn retries() -> u32;
 
@@ -72,12 +118,113 @@ pub trait Job: DeserializeOwned + Serialize {
 
     /// The priority associated to this job.
     fn priority() -> Priority;
+
+    /// Whether this job's `timeout` takes precedence over the default timeout of the queue it is
+    /// consumed from.
+    fn overrides_queue_timeout() -> bool {
+        true
+    }
+
+    /// Whether this job's `retries` take precedence over the default retries of the queue it is
+    /// consumed from.
+    fn overrides_queue_retries() -> bool {
+        true
+    }
+
+    /// The environment variables required by this job.
+    ///
+    /// When non-empty, the worker refuses to start if one of these variables is missing, and
+    /// the job's process only receives these variables instead of the worker's whole environment.
+    fn environment() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The serialized names of the fields of this job holding sensitive data, masked when the job
+    /// is logged.
+    ///
+    /// See [`redact`](fn.redact.html).
+    fn redacted_fields() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The restrictions applied to the processes executing this job.
+    ///
+    /// See [`Sandbox`](struct.Sandbox.html).
+    fn sandbox() -> Sandbox {
+        Sandbox::new()
+    }
+}
+
+/// Return the JSON representation of the given job, with its redacted fields masked.
+///
+/// This is what should be logged or displayed instead of the job itself. The fields to redact
+/// are set with the `job_redact` attribute when deriving `Job`.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_routing_key = "accounts"]
+/// #[job_redact = "password"]
+/// struct CreateAccount {
+///     username: String,
+///     password: String,
+/// }
+///
+/// # fn main() {
+/// let job = CreateAccount {
+///     username: "ferris".into(),
+///     password: "hunter2".into(),
+/// };
+/// assert_eq!(
+///     batch::redact(&job),
+///     r#"{"password":"[REDACTED]","username":"ferris"}"#
+/// );
+/// # }
+/// ```
+pub fn redact<T: Job>(job: &T) -> String {
+    match serde_json::to_value(job) {
+        Ok(value) => redact_value(value, T::redacted_fields()).to_string(),
+        Err(e) => format!("<unserializable job: {}>", e),
+    }
+}
+
+/// Return the given job payload, with the given fields masked.
+///
+/// Payloads which aren't JSON objects are replaced by their size, as there is no telling which
+/// parts of them are sensitive.
+pub(crate) fn redact_payload(data: &[u8], fields: &[&str]) -> String {
+    match serde_json::from_slice(data) {
+        Ok(value @ Value::Object(_)) => redact_value(value, fields).to_string(),
+        _ => format!("<{} bytes>", data.len()),
+    }
+}
+
+/// Mask the given fields of a serialized job.
+fn redact_value(mut value: Value, fields: &[&str]) -> Value {
+    if let Value::Object(ref mut object) = value {
+        for field in fields {
+            if let Some(field) = object.get_mut(*field) {
+                *field = Value::String(REDACTED.to_string());
+            }
+        }
+    }
+    value
 }
 
 /// The different priorities that can be assigned to a `Job`.
 ///
-/// The default value is `Priority::Normal`.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// The default value is `Priority::Normal`. The named priorities range from 0 to 4; if more
+/// granularity is needed, `Priority::Custom` accepts any value from 0 to 255. Priorities are
+/// compared by their numeric value, so `Priority::Custom(2)` is equal to `Priority::Normal`.
+#[derive(Debug, Copy, Clone)]
 pub enum Priority {
     /// The lowest available priority for a job.
     Trivial,
@@ -87,8 +234,13 @@ pub enum Priority {
     Normal,
     /// A higher priority than `Priority::Normal` but higher than `Priority::Critical`.
     High,
-    /// The highest available priority for a job.
+    /// The highest named priority for a job.
     Critical,
+    /// A numeric priority, ranging from 0 to 255.
+    ///
+    /// Queues must be declared with a high enough maximum priority for values greater than 4 to
+    /// be taken into account, see `QueueBuilder::max_priority`.
+    Custom(u8),
 }
 
 impl Default for Priority {
@@ -107,13 +259,36 @@ impl FromStr for Priority {
             "normal" => Ok(Priority::Normal),
             "high" => Ok(Priority::High),
             "critical" => Ok(Priority::Critical),
-            _ => Err(ErrorKind::InvalidPriority)?,
+            _ => match s.parse::<u8>() {
+                Ok(priority) => Ok(Priority::Custom(priority)),
+                Err(_) => Err(ErrorKind::InvalidPriority)?,
+            },
         }
     }
 }
 
+impl PartialEq for Priority {
+    fn eq(&self, other: &Priority) -> bool {
+        self.to_u8() == other.to_u8()
+    }
+}
+
+impl Eq for Priority {}
+
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Priority) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Priority) -> Ordering {
+        self.to_u8().cmp(&other.to_u8())
+    }
+}
+
 impl Priority {
-    /// Return the priority as a `u8` ranging from 0 to 4.
+    /// Return the priority as a `u8`, named priorities ranging from 0 to 4.
     pub(crate) fn to_u8(&self) -> u8 {
         match *self {
             Priority::Trivial => 0,
@@ -121,6 +296,7 @@ impl Priority {
             Priority::Normal => 2,
             Priority::High => 3,
             Priority::Critical => 4,
+            Priority::Custom(priority) => priority,
         }
     }
 }
@@ -147,6 +323,10 @@ pub enum Failure {
     Timeout,
     /// The job crashed (panic, segfault, etc.) while executing.
     Crash,
+    /// The job was interrupted because the worker was shutting down.
+    Interrupted,
+    /// The job exceeded the resource limits of its process (memory, CPU time).
+    ResourceLimit,
 }
 
 /// The `Perform` trait allow marking a `Job` as executable.
@@ -184,3 +364,71 @@ pub trait Perform {
     /// Perform the job's duty.
     fn perform(&self, Self::Context);
 }
+
+/// The `PerformBatch` trait allow marking a `Job` as executable in batches.
+///
+/// See [`WorkerBuilder::batch_job`](struct.WorkerBuilder.html#method.batch_job).
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::PerformBatch;
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_routing_key = "analytics"]
+/// struct RecordPageView {
+///     url: String,
+/// }
+///
+/// impl PerformBatch for RecordPageView {
+///     type Context = ();
+///
+///     fn perform_batch(jobs: Vec<Self>, _ctx: Self::Context) {
+///         println!("Inserting {} page views...", jobs.len());
+///     }
+/// }
+///
+/// # fn main() {}
+/// ```
+pub trait PerformBatch: Sized {
+    /// The type of the context value that will be given to this job's handler.
+    type Context;
+
+    /// Perform the duty of several jobs at once.
+    fn perform_batch(Vec<Self>, Self::Context);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_priorities_compare_by_value() {
+        assert_eq!(Priority::Custom(2), Priority::Normal);
+        assert!(Priority::Custom(5) > Priority::Critical);
+        assert!(Priority::Custom(0) < Priority::Low);
+        assert_eq!("10".parse::<Priority>().unwrap(), Priority::Custom(10));
+        assert!("256".parse::<Priority>().is_err());
+    }
+
+    #[test]
+    fn redacted_fields_are_masked() {
+        let value = serde_json::from_str(r#"{"username":"ferris","token":{"secret":42}}"#);
+        let redacted = redact_value(value.unwrap(), &["token", "password"]);
+        assert_eq!(redacted.to_string(), r#"{"token":"[REDACTED]","username":"ferris"}"#);
+    }
+
+    #[test]
+    fn unstructured_payloads_are_masked() {
+        let redacted = redact_payload(br#"{"token":"hunter2"}"#, &["token"]);
+        assert_eq!(redacted, r#"{"token":"[REDACTED]"}"#);
+        assert_eq!(redact_payload(br#"["hunter2"]"#, &["token"]), "<11 bytes>");
+    }
+}
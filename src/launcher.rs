@@ -0,0 +1,160 @@
+//! Pluggable launching of the processes executing jobs.
+//!
+//! Executor processes only talk to their worker through their standard streams (see the
+//! `executor` module): the worker writes their jobs & cancellations to their standard input, and
+//! they report on their standard output. They don't have to run on the worker's host: a `Launcher`
+//! can run them in a container or a virtual machine instead, as long as it relays their standard
+//! streams and stops them when asked to.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The command starting an executor process, i.e. the worker's own executable in executor mode.
+///
+/// See [`Launcher`](trait.Launcher.html).
+#[derive(Clone, Debug)]
+pub struct ExecutorCommand {
+    program: PathBuf,
+    envs: Vec<(OsString, OsString)>,
+    inherit_env: bool,
+}
+
+impl ExecutorCommand {
+    pub(crate) fn new(program: PathBuf, inherit_env: bool) -> Self {
+        ExecutorCommand {
+            program,
+            envs: Vec::new(),
+            inherit_env,
+        }
+    }
+
+    pub(crate) fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, name: K, value: V) {
+        self.envs
+            .push((name.as_ref().to_os_string(), value.as_ref().to_os_string()));
+    }
+
+    /// Return the path of the executable to run.
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+
+    /// Return the environment variables the process must be started with.
+    ///
+    /// They include the variable switching the executable to executor mode.
+    pub fn envs(&self) -> &[(OsString, OsString)] {
+        &self.envs
+    }
+
+    /// Return `true` if the process inherits the environment of the worker on top of `envs`, or
+    /// `false` if it must only receive `envs`, see the `job_env` attribute of the `Job` derive.
+    ///
+    /// `envs` then includes the variables the job requires, and a baseline such as `PATH`,
+    /// `HOME`, `TMPDIR`, `LD_LIBRARY_PATH` & `RUST_LOG`.
+    pub fn inherits_env(&self) -> bool {
+        self.inherit_env
+    }
+
+    /// Return a `Command` running this command locally, with piped standard streams.
+    pub fn to_command(&self) -> process::Command {
+        let mut command = process::Command::new(&self.program);
+        if !self.inherit_env {
+            command.env_clear();
+        }
+        command
+            .envs(self.envs.iter().map(|&(ref name, ref value)| (name, value)))
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped());
+        command
+    }
+}
+
+/// A strategy starting the processes executing jobs, see
+/// [`WorkerBuilder::launcher`](struct.WorkerBuilder.html#method.launcher).
+///
+/// The worker writes the jobs to execute to the standard input of the launched process, followed by
+/// the cancellations of these jobs while they run, reads its reports from its standard output, and
+/// waits for it to exit, stopping it with [`Launcher::kill`] when its job times out or when the
+/// worker is interrupted. The standard input, output & error of the returned child must therefore
+/// be piped, and stay connected to the executor until it exits.
+///
+/// [`Launcher::kill`]: #method.kill
+///
+/// # Example
+///
+/// ```
+/// use batch::{ExecutorCommand, Launcher};
+/// use std::io;
+/// use std::process::{Child, Command, Stdio};
+///
+/// /// Execute jobs in a Docker container holding the worker's executable at the same path.
+/// #[derive(Debug)]
+/// struct Docker {
+///     image: String,
+/// }
+///
+/// impl Docker {
+///     /// Return the name of the container run by the Docker CLI with the given PID.
+///     fn container(pid: u32) -> String {
+///         format!("batch-executor-{}", pid)
+///     }
+/// }
+///
+/// impl Launcher for Docker {
+///     fn launch(&self, command: &ExecutorCommand) -> io::Result<Child> {
+///         // `exec` gives the PID of the shell to the Docker CLI, which names the container after
+///         // it. `--init` & `--sig-proxy` forward signals to the executor, and `--rm` removes the
+///         // container once it exits.
+///         let script = "exec docker run --rm --init --interactive --sig-proxy=true \
+///                       --name \"batch-executor-$$\" \"$@\"";
+///         let mut docker = Command::new("sh");
+///         docker.args(&["-c", script, "sh"]);
+///         for &(ref name, ref value) in command.envs() {
+///             // The CLI passes the value of the variable from its own environment.
+///             docker.arg("--env").arg(name).env(name, value);
+///         }
+///         docker
+///             .arg(&self.image)
+///             .arg(command.program())
+///             .stdin(Stdio::piped())
+///             .stdout(Stdio::piped())
+///             .stderr(Stdio::piped())
+///             .spawn()
+///     }
+///
+///     fn kill(&self, child: &mut Child) -> io::Result<()> {
+///         // Killing the CLI leaves the container running, so it is killed first.
+///         Command::new("docker")
+///             .args(&["kill", &Docker::container(child.id())])
+///             .stdout(Stdio::null())
+///             .status()?;
+///         child.kill()
+///     }
+/// }
+/// ```
+pub trait Launcher: fmt::Debug + Send + Sync {
+    /// Start the process executing jobs with the given command.
+    fn launch(&self, command: &ExecutorCommand) -> io::Result<process::Child>;
+
+    /// Stop the given process, started by `launch`, e.g. because its job timed out.
+    ///
+    /// The worker waits for the process to exit afterwards. This kills it by default: launchers
+    /// whose process only relays the executor running elsewhere must stop the executor too.
+    fn kill(&self, child: &mut process::Child) -> io::Result<()> {
+        child.kill()
+    }
+}
+
+/// A `Launcher` starting executor processes on the worker's host, which is what workers do by
+/// default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalLauncher;
+
+impl Launcher for LocalLauncher {
+    fn launch(&self, command: &ExecutorCommand) -> io::Result<process::Child> {
+        command.to_command().spawn()
+    }
+}
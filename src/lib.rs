@@ -39,7 +39,8 @@
 //!         let to = "Ferris".to_string();
 //!
 //!         job(SayHello { to }).exchange("batch.example").send(&client)
-//!     }).map_err(|e| eprintln!("Couldn't publish message: {}", e));
+//!     }).map(|handle| println!("Sent job {}", handle.id()))
+//!     .map_err(|e| eprintln!("Couldn't publish message: {}", e));
 //!
 //! # if false {
 //!     tokio::run(send);
@@ -60,11 +61,20 @@ extern crate env_logger;
 #[macro_use]
 extern crate failure;
 extern crate futures;
+#[cfg(feature = "registry")]
+#[doc(hidden)]
+pub extern crate inventory;
 extern crate lapin_futures as lapin;
+#[cfg(unix)]
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate native_tls;
 extern crate num_cpus;
+#[cfg(feature = "otel")]
+extern crate opentelemetry;
+#[cfg(feature = "chaos")]
+extern crate rand;
 #[macro_use]
 extern crate serde;
 extern crate serde_json;
@@ -74,9 +84,14 @@ extern crate tokio_executor;
 extern crate tokio_io;
 extern crate tokio_reactor;
 extern crate tokio_tcp;
+extern crate tokio_timer;
 extern crate tokio_tls;
+#[cfg(feature = "config")]
+extern crate toml;
 extern crate uuid;
 extern crate wait_timeout;
+#[cfg(windows)]
+extern crate winapi;
 
 #[cfg(feature = "codegen")]
 #[macro_use]
@@ -89,16 +104,102 @@ pub use batch_codegen::*;
 use serde_json::de;
 use serde_json::ser;
 
+#[cfg(feature = "celery-compat")]
+mod celery;
+mod batcher;
+mod blocking;
+mod breaker;
+mod cancellation;
+#[cfg(feature = "chaos")]
+mod chaos;
 mod client;
+#[cfg(feature = "config")]
+mod config;
+mod cluster;
+mod clock;
+mod control;
+mod dlq;
 mod error;
+mod execution;
+mod executor;
+mod extensions;
+mod fairness;
+mod group;
+mod handle;
+#[cfg(all(feature = "health", unix))]
+mod health;
+mod idempotency;
+mod incoming;
 mod job;
+mod launcher;
+mod limits;
+#[cfg(feature = "management")]
+mod management;
+mod manifest;
+mod metrics;
+mod naming;
+#[cfg(feature = "otel")]
+mod otel;
+mod outbox;
+mod payload;
+mod peek;
+mod plan;
+mod prefetch;
+mod progress;
 mod query;
 mod rabbitmq;
+#[cfg(feature = "registry")]
+mod registry;
+mod router;
+mod routing;
+mod sandbox;
+mod shutdown;
+mod spool;
+mod status;
+mod topology;
 mod worker;
 
-pub use client::{Client, ClientBuilder};
-pub use error::Error;
-pub use job::{Job, Perform, Priority};
+#[cfg(feature = "chaos")]
+pub use chaos::ChaosConfig;
+pub use client::{Client, ClientBuilder, PublisherStatus};
+pub use cluster::WorkerInfo;
+pub use clock::{Clock, MockClock, SystemClock};
+#[cfg(feature = "config")]
+pub use config::Config;
+pub use control::{ControlHandle, RemoteControl};
+pub use dlq::Replayer;
+pub use error::{Error, ErrorKind};
+pub use execution::Execution;
+pub use extensions::Extensions;
+pub use fairness::Fairness;
+pub use group::{Group, GroupHandle};
+pub use handle::JobHandle;
+pub use idempotency::{IdempotencyStore, MemoryIdempotencyStore};
+pub use incoming::Incoming;
+pub use job::{redact, Job, Perform, PerformBatch, Priority};
+pub use launcher::{ExecutorCommand, Launcher, LocalLauncher};
+pub use limits::ResourceLimits;
+#[cfg(feature = "management")]
+pub use management::{monitor, ManagementApi, Monitor, QueueStats};
+pub use manifest::Manifest;
+pub use metrics::PublishStats;
+pub use naming::interpolate;
+pub use outbox::{OutboxMessage, OutboxStore};
+pub use payload::{FileSystemPayloadStore, PayloadStore};
+pub use peek::PeekedJob;
+pub use plan::{Plan, PlannedJob};
+pub use progress::Progress;
 pub use query::{job, Query};
-pub use rabbitmq::{exchange, queue, Exchange, ExchangeBuilder, Queue, QueueBuilder};
+pub use rabbitmq::{exchange, queue, Connection, ConnectionBuilder, Exchange, ExchangeBuilder, Queue,
+                   QueueBuilder, Reconcile};
+#[cfg(feature = "raw-channel")]
+pub use rabbitmq::Stream as RawStream;
+#[cfg(feature = "registry")]
+pub use registry::Registration;
+pub use router::Router;
+pub use routing::{Properties, Route, RoutingStrategy};
+pub use sandbox::Sandbox;
+pub use shutdown::ShutdownHandle;
+pub use status::{JobState, MemoryStatusTracker, StatusTracker};
+pub use topology::{Topology, TopologyBuilder};
 pub use worker::{Worker, WorkerBuilder};
@@ -0,0 +1,182 @@
+//! Resource limits of the processes executing jobs.
+
+use std::io;
+use std::time::Duration;
+
+#[cfg(unix)]
+use libc;
+
+/// Prefix of the message printed by Rust programs before aborting when an allocation fails.
+const ALLOCATION_FAILURE: &str = "memory allocation of ";
+
+/// The resources a process executing jobs may use, see
+/// [`WorkerBuilder::resource_limits`](struct.WorkerBuilder.html#method.resource_limits).
+///
+/// Limits are applied by the process itself, before it executes its job. On Unix, they are applied
+/// with `setrlimit` & `setpriority`. On Windows, the process assigns itself to a Job Object, which
+/// only enforces the memory limit: the CPU time & niceness are ignored. Jobs killed for exceeding
+/// their limits fail with `Failure::ResourceLimit`.
+///
+/// # Example
+///
+/// ```
+/// use batch::ResourceLimits;
+/// use std::time::Duration;
+///
+/// let limits = ResourceLimits::new()
+///     .max_memory(512 * 1024 * 1024)
+///     .max_cpu_time(Duration::from_secs(60))
+///     .nice(10);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    max_memory: Option<u64>,
+    max_cpu_time: Option<Duration>,
+    nice: Option<i32>,
+}
+
+impl ResourceLimits {
+    /// Create a new `ResourceLimits` limiting nothing.
+    pub fn new() -> Self {
+        ResourceLimits::default()
+    }
+
+    /// Limit the virtual memory of the process to the given number of bytes, or its committed
+    /// memory on Windows.
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Limit the CPU time of the process, rounded up to the second.
+    pub fn max_cpu_time(mut self, time: Duration) -> Self {
+        self.max_cpu_time = Some(time);
+        self
+    }
+
+    /// Set the niceness of the process, from -20 (highest priority) to 19 (lowest priority).
+    ///
+    /// Only privileged processes can lower their niceness.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Apply these limits to the current process.
+    #[cfg(unix)]
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if let Some(bytes) = self.max_memory {
+            set_limit(libc::RLIMIT_AS as libc::c_int, bytes, bytes)?;
+        }
+        if let Some(time) = self.max_cpu_time {
+            let secs = time.as_secs() + if time.subsec_nanos() > 0 { 1 } else { 0 };
+            // The process receives `SIGXCPU` at the soft limit, and is killed at the hard limit.
+            set_limit(libc::RLIMIT_CPU as libc::c_int, secs, secs + 1)?;
+        }
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply these limits to the current process, by assigning it to a new Job Object.
+    ///
+    /// The Job Object is closed when the process exits, which kills the processes it started.
+    #[cfg(windows)]
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        use std::{mem, ptr, usize};
+
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::jobapi2::{AssignProcessToJobObject, CreateJobObjectW,
+                                  SetInformationJobObject};
+        use winapi::um::processthreadsapi::GetCurrentProcess;
+        use winapi::um::winnt::{JobObjectExtendedLimitInformation,
+                                JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+                                JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+                                JOB_OBJECT_LIMIT_PROCESS_MEMORY};
+
+        if self.max_cpu_time.is_some() || self.nice.is_some() {
+            warn!("CPU time & niceness limits are only supported on Unix, ignoring them");
+        }
+        let job = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+        if job.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+        info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        if let Some(bytes) = self.max_memory {
+            info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+            info.ProcessMemoryLimit = bytes.min(usize::MAX as u64) as usize;
+        }
+        let assigned = unsafe {
+            SetInformationJobObject(
+                job,
+                JobObjectExtendedLimitInformation,
+                &mut info as *mut JOBOBJECT_EXTENDED_LIMIT_INFORMATION as *mut _,
+                mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as _,
+            ) != 0 && AssignProcessToJobObject(job, GetCurrentProcess()) != 0
+        };
+        if !assigned {
+            let e = io::Error::last_os_error();
+            unsafe { CloseHandle(job) };
+            return Err(e);
+        }
+        // The handle is only closed by the system when the process exits, closing the Job Object.
+        Ok(())
+    }
+
+    /// Apply these limits to the current process.
+    #[cfg(not(any(unix, windows)))]
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if *self != ResourceLimits::default() {
+            warn!("Resource limits are only supported on Unix & Windows, ignoring them");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_limit(resource: libc::c_int, soft: u64, hard: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource as _, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Return `true` if a process killed by the given signal exceeded its CPU time limit.
+#[cfg(unix)]
+pub(crate) fn is_cpu_limit(signal: Option<i32>) -> bool {
+    signal == Some(libc::SIGXCPU)
+}
+
+/// Return `true` if a process killed by the given signal exceeded its CPU time limit.
+#[cfg(not(unix))]
+pub(crate) fn is_cpu_limit(_signal: Option<i32>) -> bool {
+    false
+}
+
+/// Return `true` if the given line of error output reports a failed allocation.
+pub(crate) fn is_allocation_failure(line: &str) -> bool {
+    line.starts_with(ALLOCATION_FAILURE) && line.ends_with(" failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_kills_are_detected() {
+        assert!(is_allocation_failure("memory allocation of 1073741824 bytes failed"));
+        assert!(!is_allocation_failure("memory allocation of 1073741824 bytes succeeded"));
+        #[cfg(unix)]
+        assert!(is_cpu_limit(Some(libc::SIGXCPU)));
+        assert!(!is_cpu_limit(Some(9)));
+        assert!(!is_cpu_limit(None));
+    }
+}
@@ -0,0 +1,95 @@
+//! Lists of the jobs handled by workers, checked by clients before publishing.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::iter::FromIterator;
+use std::path::Path;
+
+use de;
+use error::{ErrorKind, Result};
+use ser;
+
+/// The names of the jobs handled by workers, see
+/// [`WorkerBuilder::manifest`](struct.WorkerBuilder.html#method.manifest).
+///
+/// A manifest is usually generated when the workers are built or deployed, and given to the
+/// clients with [`ClientBuilder::manifest`](struct.ClientBuilder.html#method.manifest), which then
+/// refuse to publish the jobs it doesn't list, e.g. because of a typo in a job name or a call site
+/// that wasn't updated after a job was removed.
+///
+/// # Example
+///
+/// ```
+/// use batch::Manifest;
+///
+/// let manifest = Manifest::new().job("send-email").job("generate-report");
+/// assert!(manifest.contains("send-email"));
+/// assert!(!manifest.contains("send-emial"));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    jobs: BTreeSet<String>,
+}
+
+impl Manifest {
+    /// Create a new, empty `Manifest`.
+    pub fn new() -> Self {
+        Manifest::default()
+    }
+
+    /// Add the job with the given name to this manifest.
+    pub fn job(mut self, name: &str) -> Self {
+        self.jobs.insert(name.to_string());
+        self
+    }
+
+    /// Return `true` if the job with the given name is listed in this manifest.
+    pub fn contains(&self, name: &str) -> bool {
+        self.jobs.contains(name)
+    }
+
+    /// Return the names of the jobs listed in this manifest, in alphabetical order.
+    pub fn jobs(&self) -> impl Iterator<Item = &str> {
+        self.jobs.iter().map(String::as_str)
+    }
+
+    /// Serialize this manifest, e.g. to write it next to the workers' executable.
+    pub fn to_json(&self) -> Result<String> {
+        let serialized = ser::to_string(self).map_err(ErrorKind::Serialization)?;
+        Ok(serialized)
+    }
+
+    /// Deserialize a manifest.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let manifest = de::from_str(json).map_err(ErrorKind::Deserialization)?;
+        Ok(manifest)
+    }
+
+    /// Load a manifest from the given file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let json = fs::read_to_string(path).map_err(ErrorKind::Io)?;
+        Manifest::from_json(&json)
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Manifest {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(names: I) -> Self {
+        Manifest {
+            jobs: names.into_iter().map(str::to_string).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_round_trip() {
+        let manifest = vec!["b", "a"].into_iter().collect::<Manifest>();
+        let json = manifest.to_json().unwrap();
+        assert_eq!(json, r#"{"jobs":["a","b"]}"#);
+        assert_eq!(Manifest::from_json(&json).unwrap(), manifest);
+        assert!(Manifest::from_json("[]").unwrap_err().is_deserialization());
+    }
+}
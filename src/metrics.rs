@@ -0,0 +1,194 @@
+//! Statistics about the jobs published by a `Client`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use error::{Error, ErrorKind};
+
+/// Upper bounds of the buckets of the latency histograms, in seconds.
+const BUCKETS: [f64; 10] = [0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Statistics about the jobs published by a `Client`, see
+/// [`Client::publish_stats`](struct.Client.html#method.publish_stats).
+///
+/// The latency of a publication is the time from the serialized job being handed to the client
+/// to the broker accepting it, payload storage & spooling included.
+#[derive(Clone, Debug, Default)]
+pub struct PublishStats {
+    published: u64,
+    failures: BTreeMap<&'static str, u64>,
+    latency: Histogram,
+    serialization: Histogram,
+}
+
+impl PublishStats {
+    /// Return the number of jobs published successfully.
+    pub fn published(&self) -> u64 {
+        self.published
+    }
+
+    /// Return the number of jobs that couldn't be published, by kind of error (e.g. `rabbitmq`
+    /// or `payload_too_large`).
+    pub fn failures(&self) -> &BTreeMap<&'static str, u64> {
+        &self.failures
+    }
+
+    /// Return the average time taken to publish a job, failed publications included.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        self.latency.mean()
+    }
+
+    /// Return the average time taken to serialize a job.
+    pub fn mean_serialization_time(&self) -> Option<Duration> {
+        self.serialization.mean()
+    }
+
+    /// Render the statistics in the Prometheus text format, the latencies as histograms.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Client;
+    ///
+    /// fn metrics(client: &Client) -> String {
+    ///     client.publish_stats().to_prometheus()
+    /// }
+    /// ```
+    pub fn to_prometheus(&self) -> String {
+        let mut output = String::new();
+        let _ = writeln!(
+            output,
+            "# HELP batch_client_published Jobs published.\n\
+             # TYPE batch_client_published counter\n\
+             batch_client_published {}",
+            self.published
+        );
+        let _ = writeln!(
+            output,
+            "# HELP batch_client_failures Jobs that couldn't be published.\n\
+             # TYPE batch_client_failures counter"
+        );
+        for (kind, count) in &self.failures {
+            let _ = writeln!(output, "batch_client_failures{{kind=\"{}\"}} {}", kind, count);
+        }
+        self.latency.render(
+            &mut output,
+            "batch_client_publish_seconds",
+            "Time taken to publish a job.",
+        );
+        self.serialization.render(
+            &mut output,
+            "batch_client_serialization_seconds",
+            "Time taken to serialize a job.",
+        );
+        output
+    }
+}
+
+/// The statistics of a `Client`, shared by its clones.
+#[derive(Debug, Default)]
+pub(crate) struct PublishMetrics {
+    stats: Mutex<PublishStats>,
+}
+
+impl PublishMetrics {
+    /// Record the outcome of the publication of a job.
+    pub fn record_publish(&self, elapsed: Duration, error: Option<&Error>) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.latency.observe(elapsed);
+        match error {
+            Some(error) => *stats.failures.entry(failure_kind(error)).or_insert(0) += 1,
+            None => stats.published += 1,
+        }
+    }
+
+    /// Record the time taken to serialize a job.
+    pub fn record_serialization(&self, elapsed: Duration) {
+        self.stats.lock().unwrap().serialization.observe(elapsed);
+    }
+
+    /// Return a snapshot of the statistics.
+    pub fn snapshot(&self) -> PublishStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+/// A histogram of durations, with fixed buckets.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    buckets: [u64; 10],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        for (bucket, &bound) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let nanos = (self.sum / self.count as f64 * 1e9).round() as u64;
+        Some(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32))
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let _ = writeln!(output, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for (count, bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(output, "{}_sum {}\n{}_count {}", name, self.sum, name, self.count);
+    }
+}
+
+/// Return the label of the kind of the given error.
+fn failure_kind(error: &Error) -> &'static str {
+    match *error.kind() {
+        ErrorKind::Serialization(_) => "serialization",
+        ErrorKind::Io(_) => "io",
+        ErrorKind::Rabbitmq(_) => "rabbitmq",
+        ErrorKind::BrokerBlocked(_) => "broker_blocked",
+        ErrorKind::UnknownJob(_) => "unknown_job",
+        ErrorKind::Tls(_) => "tls",
+        ErrorKind::Connection(_) => "connection",
+        ErrorKind::Declare(_) => "declare",
+        ErrorKind::Timeout(_) => "timeout",
+        ErrorKind::PayloadTooLarge(..) => "payload_too_large",
+        ErrorKind::MissingPayload(_) => "payload_store",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publications_are_counted() {
+        let metrics = PublishMetrics::default();
+        metrics.record_publish(Duration::from_millis(2), None);
+        let error = ErrorKind::PayloadTooLarge(2048, 1024).into();
+        metrics.record_publish(Duration::from_millis(4), Some(&error));
+        let stats = metrics.snapshot();
+        assert_eq!(stats.published(), 1);
+        assert_eq!(stats.failures()["payload_too_large"], 1);
+        assert_eq!(stats.mean_latency(), Some(Duration::from_millis(3)));
+        assert_eq!(stats.mean_serialization_time(), None);
+        let rendered = stats.to_prometheus();
+        assert!(rendered.contains("batch_client_failures{kind=\"payload_too_large\"} 1\n"));
+        assert!(rendered.contains("batch_client_publish_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(rendered.contains("batch_client_publish_seconds_bucket{le=\"0.001\"} 0\n"));
+    }
+}
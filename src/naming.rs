@@ -0,0 +1,65 @@
+//! Interpolation of a runtime prefix in the names of exchanges, queues & jobs.
+//!
+//! Names can contain a `{prefix}` placeholder, replaced with the value of the `BATCH_PREFIX`
+//! environment variable when the name is used, so that several environments (e.g. staging &
+//! production) can share the same code and broker while using distinct resources.
+
+use std::env;
+
+/// Name of the environment variable holding the prefix.
+pub(crate) const PREFIX_VAR: &str = "BATCH_PREFIX";
+
+/// Placeholder replaced with the prefix.
+const PLACEHOLDER: &str = "{prefix}";
+
+/// Replace the `{prefix}` placeholder in the given name with the value of `BATCH_PREFIX`.
+///
+/// When `BATCH_PREFIX` is unset or empty, the placeholder is removed along with the `.` following
+/// it, if any. This is done for the names given to [`exchange`], [`queue`] & [`Query`], and for
+/// the `job_name`, `job_exchange` & `job_routing_key` attributes of the `Job` derive.
+///
+/// [`exchange`]: fn.exchange.html
+/// [`queue`]: fn.queue.html
+/// [`Query`]: struct.Query.html
+///
+/// # Example
+///
+/// ```
+/// use batch::interpolate;
+/// use std::env;
+///
+/// env::set_var("BATCH_PREFIX", "staging");
+/// assert_eq!(interpolate("{prefix}.notifications"), "staging.notifications");
+///
+/// env::remove_var("BATCH_PREFIX");
+/// assert_eq!(interpolate("{prefix}.notifications"), "notifications");
+/// ```
+pub fn interpolate(name: &str) -> String {
+    if !name.contains(PLACEHOLDER) {
+        return name.to_string();
+    }
+    with_prefix(name, env::var(PREFIX_VAR).ok().as_ref().map(String::as_str))
+}
+
+fn with_prefix(name: &str, prefix: Option<&str>) -> String {
+    match prefix {
+        Some(prefix) if !prefix.is_empty() => name.replace(PLACEHOLDER, prefix),
+        _ => name.replace(&format!("{}.", PLACEHOLDER), "")
+            .replace(PLACEHOLDER, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_replaced() {
+        assert_eq!(with_prefix("emails", Some("staging")), "emails");
+        assert_eq!(with_prefix("{prefix}.emails", Some("staging")), "staging.emails");
+        assert_eq!(with_prefix("batch.{prefix}", Some("staging")), "batch.staging");
+        assert_eq!(with_prefix("{prefix}.emails", None), "emails");
+        assert_eq!(with_prefix("{prefix}.emails", Some("")), "emails");
+        assert_eq!(with_prefix("batch-{prefix}", None), "batch-");
+    }
+}
@@ -0,0 +1,227 @@
+//! OpenTelemetry spans & metrics of the jobs published & executed.
+//!
+//! The context of the span publishing a job is sent in the headers of its message with the global
+//! propagator, and the span of its execution by a worker is a child of it. The exporters,
+//! propagator & providers are set up by the application with the `opentelemetry` crate: until
+//! then, the spans & metrics are discarded.
+
+use std::time::Instant;
+
+use lapin::channel::BasicProperties;
+use lapin::types::{AMQPValue, FieldTable};
+use opentelemetry::global;
+use opentelemetry::metrics::Unit;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+
+use error::Result;
+use job::{Failure as JobFailure, Status as JobStatus};
+use rabbitmq::Delivery;
+
+/// Name of the tracer & meter of the crate.
+const INSTRUMENTATION: &str = "batch";
+
+/// The span of the publication of a job.
+#[derive(Debug)]
+pub(crate) struct PublishSpan {
+    context: Context,
+    job: String,
+    started: Instant,
+}
+
+impl PublishSpan {
+    /// Start the span of the publication of a job, sending its context in the job's headers.
+    pub fn start(properties: &mut BasicProperties, exchange: &str, routing_key: &str) -> Self {
+        let job = header(properties.headers.as_ref(), "task").unwrap_or_default();
+        let tracer = global::tracer(INSTRUMENTATION);
+        let span = tracer
+            .span_builder(format!("{} publish", job))
+            .with_kind(SpanKind::Producer)
+            .with_attributes(vec![
+                KeyValue::new("messaging.system", "rabbitmq"),
+                KeyValue::new("messaging.destination.name", exchange.to_string()),
+                KeyValue::new(
+                    "messaging.rabbitmq.destination.routing_key",
+                    routing_key.to_string(),
+                ),
+                KeyValue::new("batch.job.name", job.clone()),
+                KeyValue::new(
+                    "batch.job.id",
+                    properties.correlation_id.clone().unwrap_or_default(),
+                ),
+            ])
+            .start(&tracer);
+        let context = Context::current_with_span(span);
+        let headers = properties.headers.get_or_insert_with(FieldTable::new);
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut HeaderInjector(headers))
+        });
+        PublishSpan {
+            context,
+            job,
+            started: Instant::now(),
+        }
+    }
+
+    /// End the span with the outcome of the publication.
+    pub fn end(self, result: &Result<()>) {
+        let span = self.context.span();
+        let outcome = match *result {
+            Ok(()) => "success",
+            Err(ref e) => {
+                span.set_status(Status::error(e.to_string()));
+                "failure"
+            }
+        };
+        span.end();
+        let attributes = [
+            KeyValue::new("batch.job.name", self.job),
+            KeyValue::new("batch.outcome", outcome),
+        ];
+        let meter = global::meter(INSTRUMENTATION);
+        meter
+            .u64_counter("batch.jobs.published")
+            .with_description("Jobs published.")
+            .init()
+            .add(1, &attributes);
+        meter
+            .f64_histogram("batch.publish.duration")
+            .with_description("Time taken to publish a job.")
+            .with_unit(Unit::new("s"))
+            .init()
+            .record(seconds(self.started), &attributes);
+    }
+}
+
+/// The span of the execution of a job by a worker.
+#[derive(Debug)]
+pub(crate) struct ExecutionSpan {
+    context: Context,
+    job: String,
+    queue: String,
+    started: Instant,
+}
+
+impl ExecutionSpan {
+    /// Start the span of the execution of the given job, as a child of its publication's.
+    pub fn start(delivery: &Delivery) -> Self {
+        let headers = delivery.properties().headers.as_ref();
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        let tracer = global::tracer(INSTRUMENTATION);
+        let span = tracer
+            .span_builder(format!("{} process", delivery.task()))
+            .with_kind(SpanKind::Consumer)
+            .with_attributes(vec![
+                KeyValue::new("messaging.system", "rabbitmq"),
+                KeyValue::new("messaging.source.name", delivery.queue().to_string()),
+                KeyValue::new("batch.job.name", delivery.task().to_string()),
+                KeyValue::new("batch.job.id", delivery.task_id().to_string()),
+                KeyValue::new("batch.job.attempt", i64::from(delivery.attempt())),
+            ])
+            .start_with_context(&tracer, &parent);
+        ExecutionSpan {
+            context: parent.with_span(span),
+            job: delivery.task().to_string(),
+            queue: delivery.queue().to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// End the span with the outcome of the execution.
+    pub fn end(self, status: &Result<(JobStatus, Option<String>)>) {
+        let span = self.context.span();
+        let outcome = match *status {
+            Ok((JobStatus::Success, _)) => "success",
+            Ok((JobStatus::Failed(failure), _)) => failure_label(failure),
+            Ok(_) => "unknown",
+            Err(_) => "error",
+        };
+        if outcome != "success" {
+            span.set_status(Status::error(match *status {
+                Err(ref e) => e.to_string(),
+                _ => outcome.to_string(),
+            }));
+        }
+        span.end();
+        let attributes = [
+            KeyValue::new("batch.job.name", self.job),
+            KeyValue::new("batch.queue", self.queue),
+            KeyValue::new("batch.outcome", outcome),
+        ];
+        let meter = global::meter(INSTRUMENTATION);
+        meter
+            .u64_counter("batch.jobs.executed")
+            .with_description("Jobs executed.")
+            .init()
+            .add(1, &attributes);
+        meter
+            .f64_histogram("batch.execution.duration")
+            .with_description("Time taken to execute a job.")
+            .with_unit(Unit::new("s"))
+            .init()
+            .record(seconds(self.started), &attributes);
+    }
+}
+
+/// Writes the propagated context to the headers of a job.
+struct HeaderInjector<'a>(&'a mut FieldTable);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), AMQPValue::LongString(value));
+    }
+}
+
+/// Reads the propagated context from the headers of a job.
+struct HeaderExtractor<'a>(Option<&'a FieldTable>);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self.0?.get(key) {
+            Some(&AMQPValue::LongString(ref value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .map_or_else(Vec::new, |headers| headers.keys().map(String::as_str).collect())
+    }
+}
+
+fn header(headers: Option<&FieldTable>, key: &str) -> Option<String> {
+    HeaderExtractor(headers).get(key).map(str::to_string)
+}
+
+fn failure_label(failure: JobFailure) -> &'static str {
+    match failure {
+        JobFailure::Error => "failure",
+        JobFailure::Timeout => "timeout",
+        JobFailure::Crash => "crash",
+        JobFailure::Interrupted => "interrupted",
+        JobFailure::ResourceLimit => "resource_limit",
+    }
+}
+
+fn seconds(started: Instant) -> f64 {
+    let elapsed = started.elapsed();
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contexts_are_propagated_through_headers() {
+        let mut headers = FieldTable::new();
+        HeaderInjector(&mut headers).set("traceparent", "00-01-02-01".to_string());
+        let extractor = HeaderExtractor(Some(&headers));
+        assert_eq!(extractor.get("traceparent"), Some("00-01-02-01"));
+        assert_eq!(extractor.keys(), vec!["traceparent"]);
+        assert_eq!(HeaderExtractor(None).get("traceparent"), None);
+    }
+}
@@ -0,0 +1,168 @@
+//! Publishing jobs through a transactional outbox.
+//!
+//! Publishing a job right before or after committing a database transaction loses the job, or
+//! publishes it for changes that were rolled back, when the process crashes in between. Instead,
+//! jobs can be staged in the application's database, inside the same transaction as the changes
+//! they relate to, and relayed to the broker once committed.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_timer::Delay;
+
+use client::Client;
+use de;
+use error::{Error, ErrorKind, Result};
+use ser;
+use spool::Entry;
+
+/// Maximum number of messages relayed from the outbox at once.
+const RELAY_BATCH_SIZE: usize = 100;
+
+/// A job staged in an outbox, see [`Query::stage`](struct.Query.html#method.stage).
+///
+/// The message is meant to be serialized with `to_json` and stored in the application's
+/// database, until an `OutboxStore` hands it back to be relayed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxMessage(Entry);
+
+impl OutboxMessage {
+    pub(crate) fn new(entry: Entry) -> Self {
+        OutboxMessage(entry)
+    }
+
+    pub(crate) fn into_entry(self) -> Entry {
+        self.0
+    }
+
+    /// Serialize this message, to store it in the outbox.
+    pub fn to_json(&self) -> Result<String> {
+        let serialized = ser::to_string(self).map_err(ErrorKind::Serialization)?;
+        Ok(serialized)
+    }
+
+    /// Deserialize a message stored in the outbox.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let message = de::from_str(json).map_err(ErrorKind::Deserialization)?;
+        Ok(message)
+    }
+}
+
+/// The outbox of an application, usually a table in its database.
+///
+/// See [`Client::relay_outbox`](struct.Client.html#method.relay_outbox).
+///
+/// # Example
+///
+/// ```
+/// use batch::{Error, OutboxMessage, OutboxStore};
+/// use std::collections::BTreeMap;
+/// use std::sync::Mutex;
+///
+/// // An outbox is usually a database table: `INSERT` the staged messages in the transaction
+/// // modifying the data they relate to, and `SELECT` & `DELETE` them in the store.
+/// #[derive(Debug, Default)]
+/// struct MemoryOutbox {
+///     rows: Mutex<BTreeMap<u64, String>>,
+/// }
+///
+/// impl OutboxStore for MemoryOutbox {
+///     fn pending(&self, limit: usize) -> Result<Vec<(String, OutboxMessage)>, Error> {
+///         let rows = self.rows.lock().unwrap();
+///         rows.iter()
+///             .take(limit)
+///             .map(|(id, json)| Ok((id.to_string(), OutboxMessage::from_json(json)?)))
+///             .collect()
+///     }
+///
+///     fn remove(&self, id: &str) -> Result<(), Error> {
+///         let id = id.parse().unwrap();
+///         self.rows.lock().unwrap().remove(&id);
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait OutboxStore: fmt::Debug + Send + Sync {
+    /// Return up to `limit` messages waiting in the outbox, in the order they were staged, along
+    /// with their ID in the outbox.
+    fn pending(&self, limit: usize) -> Result<Vec<(String, OutboxMessage)>>;
+
+    /// Remove the message with the given ID from the outbox, once it was published.
+    fn remove(&self, id: &str) -> Result<()>;
+}
+
+/// Publish the messages of the outbox, checking for new ones at the given interval.
+pub(crate) fn relay(
+    client: Client,
+    store: Arc<OutboxStore>,
+    interval: Duration,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let task = future::loop_fn((), move |_| {
+        relay_pending(client.clone(), Arc::clone(&store)).then(move |_| {
+            Delay::new(Instant::now() + interval)
+                .then(|_| Ok(future::Loop::Continue(())))
+        })
+    });
+    Box::new(task)
+}
+
+/// Publish the messages waiting in the outbox, in order, stopping at the first failure.
+fn relay_pending(
+    client: Client,
+    store: Arc<OutboxStore>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let messages = match store.pending(RELAY_BATCH_SIZE) {
+        Ok(messages) => messages,
+        Err(e) => {
+            error!("Couldn't read the outbox: {}", e);
+            return Box::new(future::ok(()));
+        }
+    };
+    if !messages.is_empty() {
+        debug!("Relaying {} job(s) from the outbox", messages.len());
+    }
+    let task = future::loop_fn(messages.into_iter(), move |mut messages| {
+        let (id, OutboxMessage(entry)) = match messages.next() {
+            Some(message) => message,
+            None => return future::Either::A(future::ok(future::Loop::Break(()))),
+        };
+        let store = Arc::clone(&store);
+        let task = client
+            .send(
+                &entry.exchange,
+                &entry.routing_key,
+                &entry.data,
+                &entry.options(),
+                entry.properties.clone(),
+            )
+            .and_then(move |_| store.remove(&id))
+            .map(move |_| future::Loop::Continue(messages));
+        future::Either::B(task)
+    });
+    Box::new(task.map_err(|e| error!("Couldn't relay job from the outbox: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::channel::BasicProperties;
+
+    #[test]
+    fn messages_are_serialized_as_json() {
+        let message = OutboxMessage::new(Entry {
+            exchange: "batch.example".to_string(),
+            routing_key: "hello-world".to_string(),
+            mandatory: false,
+            immediate: false,
+            properties: BasicProperties::default(),
+            data: b"{}".to_vec(),
+        });
+        let json = message.to_json().unwrap();
+        let OutboxMessage(entry) = OutboxMessage::from_json(&json).unwrap();
+        assert_eq!(entry.exchange, "batch.example");
+        assert_eq!(entry.routing_key, "hello-world");
+        assert_eq!(entry.data, b"{}");
+    }
+}
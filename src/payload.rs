@@ -0,0 +1,180 @@
+//! Storage of large job payloads outside of the broker (the "claim-check" pattern).
+//!
+//! Clients configured with a `PayloadStore` upload the payloads larger than a threshold to the
+//! store, and publish a reference to it instead. Workers configured with the same store fetch
+//! the payload before executing the job, and delete it once the job is done with.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures::Future;
+use uuid::Uuid;
+
+use blocking::Blocking;
+use error::{Error, ErrorKind, Result};
+use rabbitmq::Delivery;
+
+/// Name of the header carrying the reference of a payload stored in a `PayloadStore`.
+pub(crate) const HEADER: &str = "payload_ref";
+
+/// A store for the payloads too large to be sent through the broker.
+///
+/// See [`ClientBuilder::payload_store`] & [`WorkerBuilder::payload_store`].
+///
+/// Stored payloads are deleted by the worker configured with the same store once their job is
+/// done with: after it succeeded, or failed for the last time. Dead-lettered jobs are published
+/// with their payload instead of its reference, so that they can be replayed. The client deletes
+/// the payloads it stored for the jobs it couldn't publish. The payloads of jobs that are never
+/// consumed, e.g. because they expire in their queue, must be cleaned up by the store itself.
+///
+/// [`ClientBuilder::payload_store`]: struct.ClientBuilder.html#method.payload_store
+/// [`WorkerBuilder::payload_store`]: struct.WorkerBuilder.html#method.payload_store
+pub trait PayloadStore: fmt::Debug + Send + Sync {
+    /// Store the given payload, returning a reference to retrieve it.
+    fn put(&self, data: &[u8]) -> Result<String>;
+
+    /// Retrieve the payload stored with the given reference.
+    fn get(&self, reference: &str) -> Result<Vec<u8>>;
+
+    /// Delete the payload stored with the given reference.
+    ///
+    /// Deleting a payload that doesn't exist, e.g. because it was already deleted, must succeed.
+    fn delete(&self, reference: &str) -> Result<()>;
+}
+
+/// A `PayloadStore` keeping payloads as files in a directory.
+///
+/// The directory must be shared by the clients & the workers, e.g. on a network file system.
+///
+/// # Example
+///
+/// ```
+/// use batch::{FileSystemPayloadStore, PayloadStore};
+/// # use std::env;
+///
+/// # let directory = env::temp_dir();
+/// let store = FileSystemPayloadStore::new(directory);
+/// let reference = store.put(b"large payload").unwrap();
+/// assert_eq!(store.get(&reference).unwrap(), b"large payload");
+/// store.delete(&reference).unwrap();
+/// assert!(store.get(&reference).is_err());
+/// ```
+#[derive(Clone, Debug)]
+pub struct FileSystemPayloadStore {
+    directory: PathBuf,
+}
+
+impl FileSystemPayloadStore {
+    /// Create a new `FileSystemPayloadStore` storing payloads in the given directory.
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        FileSystemPayloadStore {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl PayloadStore for FileSystemPayloadStore {
+    fn put(&self, data: &[u8]) -> Result<String> {
+        let reference = format!("batch-payload-{}", Uuid::new_v4());
+        fs::write(self.directory.join(&reference), data).map_err(ErrorKind::Io)?;
+        Ok(reference)
+    }
+
+    fn get(&self, reference: &str) -> Result<Vec<u8>> {
+        if !is_valid(reference) {
+            return Err(ErrorKind::MissingPayload(reference.to_string()).into());
+        }
+        Ok(fs::read(self.directory.join(reference)).map_err(ErrorKind::Io)?)
+    }
+
+    fn delete(&self, reference: &str) -> Result<()> {
+        if !is_valid(reference) {
+            return Ok(());
+        }
+        match fs::remove_file(self.directory.join(reference)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result.map_err(ErrorKind::Io)?),
+        }
+    }
+}
+
+/// Return `true` if the given reference may have been returned by a `FileSystemPayloadStore`,
+/// so that it can't point outside of its directory.
+fn is_valid(reference: &str) -> bool {
+    reference
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// A `PayloadStore` called on its own thread, so that a slow store doesn't block the reactor.
+#[derive(Clone, Debug)]
+pub(crate) struct PayloadHandle {
+    store: Arc<PayloadStore>,
+    blocking: Blocking,
+}
+
+impl PayloadHandle {
+    pub fn new(store: Arc<PayloadStore>) -> Self {
+        PayloadHandle {
+            store,
+            blocking: Blocking::new("batch-payload"),
+        }
+    }
+
+    /// Return the store, to be called synchronously.
+    pub fn store(&self) -> &PayloadStore {
+        &*self.store
+    }
+
+    /// Store the given payload, resolving to a reference to retrieve it.
+    pub fn put(&self, data: Vec<u8>) -> Box<Future<Item = String, Error = Error> + Send> {
+        let store = Arc::clone(&self.store);
+        self.blocking.run(move || store.put(&data))
+    }
+
+    /// Retrieve the payload stored with the given reference.
+    pub fn get(&self, reference: &str) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        let (store, reference) = (Arc::clone(&self.store), reference.to_string());
+        self.blocking.run(move || store.get(&reference))
+    }
+
+    /// Delete the payload stored with the given reference, without waiting for it.
+    ///
+    /// Errors are logged.
+    pub fn delete(&self, reference: &str) {
+        let (store, reference) = (Arc::clone(&self.store), reference.to_string());
+        self.blocking.spawn(move || {
+            if let Err(e) = store.delete(&reference) {
+                warn!("Couldn't delete stored payload {:?}: {}", reference, e);
+            }
+        });
+    }
+}
+
+/// Delete the payload of the given delivery from the store, if it was stored in one, without
+/// waiting for it.
+pub(crate) fn release(store: Option<&PayloadHandle>, delivery: &Delivery) {
+    if let (Some(store), Some(reference)) = (store, delivery.payload_ref()) {
+        debug!("[{}] Deleting stored payload {:?}", delivery.task_id(), reference);
+        store.delete(reference);
+    }
+}
+
+/// Return a copy of the given delivery with the payload fetched from the store, if its payload
+/// was stored in a `PayloadStore`.
+pub(crate) fn resolve(
+    store: Option<&PayloadStore>,
+    delivery: &Delivery,
+) -> Result<Option<Delivery>> {
+    let reference = match delivery.payload_ref() {
+        Some(reference) => reference,
+        None => return Ok(None),
+    };
+    match store {
+        Some(store) => Ok(Some(delivery.with_data(store.get(reference)?))),
+        None => Err(ErrorKind::MissingPayload(reference.to_string()).into()),
+    }
+}
@@ -0,0 +1,128 @@
+//! Inspecting the jobs waiting in a queue, without consuming them.
+
+use std::cmp;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{Future, Stream};
+use tokio_executor;
+
+use client::Client;
+use error::Error;
+use execution::Execution;
+use rabbitmq::{self, queue};
+
+/// Time to wait for the jobs of a queue with fewer jobs than requested.
+const IDLE_TIMEOUT_MILLIS: u64 = 500;
+
+/// A job waiting in a queue, see [`QueueBuilder::peek`](struct.QueueBuilder.html#method.peek).
+#[derive(Clone, Debug)]
+pub struct PeekedJob {
+    delivery: rabbitmq::Delivery,
+}
+
+impl PeekedJob {
+    /// Return the ID, name and headers of the job.
+    ///
+    /// The attempt it returns counts the delivery used to peek the job.
+    pub fn execution(&self) -> Execution {
+        Execution::from_delivery(&self.delivery)
+    }
+
+    /// Return the priority the job was published with, if any.
+    pub fn priority(&self) -> Option<u8> {
+        self.delivery.properties().priority
+    }
+
+    /// Return the time the job was published at, if known.
+    pub fn published_at(&self) -> Option<SystemTime> {
+        self.delivery
+            .properties()
+            .timestamp
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Return the serialized job.
+    pub fn data(&self) -> &[u8] {
+        self.delivery.data()
+    }
+
+    /// Return the beginning of the serialized job, at most `max_len` bytes of it, as text.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::PeekedJob;
+    ///
+    /// fn describe(job: &PeekedJob) {
+    ///     println!("{}: {}", job.execution().name(), job.preview(80));
+    /// }
+    /// ```
+    pub fn preview(&self, max_len: usize) -> String {
+        let data = self.delivery.data();
+        let preview = String::from_utf8_lossy(&data[..cmp::min(data.len(), max_len)]);
+        if data.len() > max_len {
+            format!("{}…", preview)
+        } else {
+            preview.into_owned()
+        }
+    }
+}
+
+/// Return the first `count` jobs waiting in the given queue, putting them back in the queue.
+pub(crate) fn peek(
+    client: &Client,
+    name: &str,
+    count: u16,
+) -> Box<Future<Item = Vec<PeekedJob>, Error = Error> + Send> {
+    let task = client
+        .consumer(Vec::new(), vec![queue(name).passive(true).build()], count.max(1))
+        .and_then(move |consumer| {
+            let handle = consumer.handle();
+            consumer
+                .until_idle(Duration::from_millis(IDLE_TIMEOUT_MILLIS))
+                .take(u64::from(count))
+                .collect()
+                .map(move |deliveries| {
+                    for delivery in &deliveries {
+                        let task = handle
+                            .requeue(delivery.tag())
+                            .map_err(|e| error!("Couldn't requeue peeked job: {}", e));
+                        tokio_executor::spawn(task);
+                    }
+                    deliveries
+                        .into_iter()
+                        .map(|delivery| PeekedJob { delivery })
+                        .collect()
+                })
+        });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::channel::BasicProperties;
+    use lapin::message::Delivery as Message;
+
+    #[test]
+    fn previews_are_truncated() {
+        let message = Message {
+            delivery_tag: 1,
+            exchange: "batch.tests".to_string(),
+            routing_key: "tests.peek".to_string(),
+            redelivered: false,
+            properties: BasicProperties {
+                timestamp: Some(42),
+                ..BasicProperties::default()
+            },
+            data: b"{\"to\":\"john@example.com\"}".to_vec(),
+        };
+        let job = PeekedJob {
+            delivery: rabbitmq::Delivery::new(message, "tests.peek".to_string()),
+        };
+        assert_eq!(job.preview(6), "{\"to\":…");
+        assert_eq!(job.preview(100), "{\"to\":\"john@example.com\"}");
+        assert_eq!(job.published_at(), Some(UNIX_EPOCH + Duration::from_secs(42)));
+        assert_eq!(job.priority(), None);
+    }
+}
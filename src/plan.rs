@@ -0,0 +1,194 @@
+//! Description of the topology & jobs of a worker, see `Worker::plan`.
+
+use std::fmt;
+use std::result::Result as StdResult;
+
+use rabbitmq::{Exchange, Queue};
+
+/// The exchanges & queues a `Worker` declares, and the jobs it has handlers for.
+///
+/// See [`Worker::plan`](struct.Worker.html#method.plan). Its `Display` implementation describes
+/// the plan in a human readable way, one item per line.
+#[derive(Clone, Debug)]
+pub struct Plan {
+    exchanges: Vec<Exchange>,
+    queues: Vec<Queue>,
+    jobs: Vec<PlannedJob>,
+}
+
+impl Plan {
+    pub(crate) fn new(exchanges: Vec<Exchange>, queues: Vec<Queue>, jobs: Vec<PlannedJob>) -> Self {
+        Plan {
+            exchanges,
+            queues,
+            jobs,
+        }
+    }
+
+    /// Return the exchanges declared by the worker.
+    pub fn exchanges(&self) -> &[Exchange] {
+        &self.exchanges
+    }
+
+    /// Return the queues declared & consumed by the worker.
+    ///
+    /// The private queues a worker uses to receive cancellations & control commands are named
+    /// after their exchange, followed by `<worker-id>` in place of the random ID they get when
+    /// the worker runs.
+    pub fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    /// Return the jobs the worker has handlers for, sorted by name.
+    pub fn jobs(&self) -> &[PlannedJob] {
+        &self.jobs
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        writeln!(f, "Exchanges:")?;
+        for exchange in &self.exchanges {
+            writeln!(f, "  {} ({})", display_name(exchange.name()), exchange.kind())?;
+            for binding in exchange.bindings() {
+                writeln!(
+                    f,
+                    "    -> exchange {} with routing key {:?}",
+                    display_name(binding.exchange()),
+                    binding.routing_key()
+                )?;
+            }
+            for binding in exchange.sources() {
+                writeln!(
+                    f,
+                    "    <- exchange {} with routing key {:?}",
+                    display_name(binding.exchange()),
+                    binding.routing_key()
+                )?;
+            }
+        }
+        writeln!(f, "Queues:")?;
+        for queue in &self.queues {
+            writeln!(f, "  {}", queue.name())?;
+            for binding in queue.bindings() {
+                writeln!(
+                    f,
+                    "    <- exchange {} with routing key {:?}",
+                    display_name(binding.exchange()),
+                    binding.routing_key()
+                )?;
+            }
+        }
+        writeln!(f, "Jobs:")?;
+        for job in &self.jobs {
+            write!(f, "  {}", job.name())?;
+            if let (Some(exchange), Some(routing_key)) = (job.exchange(), job.routing_key()) {
+                write!(
+                    f,
+                    " (exchange {} with routing key {:?})",
+                    display_name(exchange),
+                    routing_key
+                )?;
+            }
+            if !job.queues().is_empty() {
+                write!(f, " -> {}", job.queues().join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Return the name of an exchange, or a placeholder for the default exchange.
+fn display_name(exchange: &str) -> &str {
+    if exchange.is_empty() {
+        "(default)"
+    } else {
+        exchange
+    }
+}
+
+/// A job a `Worker` has a handler for, see [`Plan::jobs`](struct.Plan.html#method.jobs).
+#[derive(Clone, Debug)]
+pub struct PlannedJob {
+    name: String,
+    route: Option<(String, String)>,
+    queues: Vec<String>,
+}
+
+impl PlannedJob {
+    pub(crate) fn new(name: &str, route: Option<(&str, &str)>, queues: &[Queue]) -> Self {
+        let queues = match route {
+            Some((exchange, routing_key)) => queues
+                .iter()
+                .filter(|queue| {
+                    (exchange.is_empty() && queue.name() == routing_key)
+                        || queue.bindings().iter().any(|binding| {
+                            binding.exchange() == exchange && binding.routing_key() == routing_key
+                        })
+                })
+                .map(|queue| queue.name().to_string())
+                .collect(),
+            None => Vec::new(),
+        };
+        PlannedJob {
+            name: name.to_string(),
+            route: route.map(|(exchange, routing_key)| (exchange.into(), routing_key.into())),
+            queues,
+        }
+    }
+
+    /// Return the name of the job.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the exchange the job is published to by default.
+    ///
+    /// Returns `None` for the handlers registered with
+    /// [`WorkerBuilder::handler`](struct.WorkerBuilder.html#method.handler), which are only
+    /// known by name.
+    pub fn exchange(&self) -> Option<&str> {
+        self.route.as_ref().map(|&(ref exchange, _)| exchange.as_str())
+    }
+
+    /// Return the routing key the job is published with by default.
+    pub fn routing_key(&self) -> Option<&str> {
+        self.route.as_ref().map(|&(_, ref routing_key)| routing_key.as_str())
+    }
+
+    /// Return the names of the queues consumed by the worker the job is routed to by default.
+    ///
+    /// An empty list means the worker won't receive the job unless it is published with another
+    /// exchange or routing key, or routed by a binding the worker doesn't declare.
+    pub fn queues(&self) -> &[String] {
+        &self.queues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rabbitmq::{exchange, queue};
+
+    #[test]
+    fn jobs_are_matched_to_their_queues() {
+        let queues = vec![
+            queue("thumbnails").bind("batch.media", "thumbnail").build(),
+            queue("transcoding").bind("batch.media", "transcode").build(),
+        ];
+        let job = PlannedJob::new("thumbnail", Some(("batch.media", "thumbnail")), &queues);
+        assert_eq!(job.queues(), &["thumbnails".to_string()]);
+        let job = PlannedJob::new("direct", Some(("", "transcoding")), &queues);
+        assert_eq!(job.queues(), &["transcoding".to_string()]);
+        let job = PlannedJob::new("dynamic", None, &queues);
+        assert!(job.queues().is_empty());
+
+        let plan = Plan::new(vec![exchange("batch.media").build()], queues, vec![job]);
+        let description = plan.to_string();
+        assert!(description.contains("  batch.media (direct)\n"));
+        let binding = "    <- exchange batch.media with routing key \"transcode\"\n";
+        assert!(description.contains(binding));
+        assert!(description.contains("  dynamic\n"));
+    }
+}
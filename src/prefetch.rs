@@ -0,0 +1,94 @@
+//! Adaptive prefetching of jobs.
+//!
+//! A worker configured with `WorkerBuilder::adaptive_prefetch` adjusts how many jobs it prefetches
+//! to the average duration of its jobs: it prefetches many short jobs to avoid waiting for the
+//! broker between them, and few long jobs to leave them to the other workers.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Amount of work kept buffered by adaptive prefetching, in microseconds.
+const PREFETCH_BUFFER_MICROS: usize = 100_000;
+
+/// Tracks the average duration of jobs to compute how many jobs to prefetch.
+#[derive(Debug)]
+pub(crate) struct PrefetchTuner {
+    parallelism: usize,
+    min: usize,
+    max: usize,
+    average: AtomicUsize,
+    current: AtomicUsize,
+}
+
+impl PrefetchTuner {
+    pub fn new(parallelism: u16, min: u16, max: u16) -> Self {
+        let min = min.max(1) as usize;
+        let max = (max as usize).max(min);
+        PrefetchTuner {
+            parallelism: parallelism.max(1) as usize,
+            min,
+            max,
+            average: AtomicUsize::new(0),
+            current: AtomicUsize::new((parallelism as usize).max(min).min(max)),
+        }
+    }
+
+    pub fn current(&self) -> u16 {
+        self.current.load(Ordering::SeqCst) as u16
+    }
+
+    /// Record the duration of an executed job, as an exponentially weighted moving average.
+    pub fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_secs() as usize * 1_000_000 + elapsed.subsec_micros() as usize;
+        let micros = micros.max(1);
+        let average = match self.average.load(Ordering::SeqCst) {
+            0 => micros,
+            average => (average * 7 + micros) / 8,
+        };
+        self.average.store(average, Ordering::SeqCst);
+    }
+
+    /// Return the new prefetch count if it differs enough from the current one.
+    ///
+    /// Small variations are ignored, to avoid sending a `basic.qos` for every job.
+    pub fn adjust(&self) -> Option<u16> {
+        let average = self.average.load(Ordering::SeqCst);
+        if average == 0 {
+            return None;
+        }
+        let buffered = (self.parallelism * PREFETCH_BUFFER_MICROS + average - 1) / average;
+        let target = (self.parallelism + buffered).max(self.min).min(self.max);
+        let current = self.current.load(Ordering::SeqCst);
+        let difference = if target > current { target - current } else { current - target };
+        if difference == 0 || difference * 4 < current {
+            return None;
+        }
+        self.current.store(target, Ordering::SeqCst);
+        Some(target as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefetch_tuner_follows_job_durations() {
+        let tuner = PrefetchTuner::new(4, 2, 100);
+        assert_eq!(tuner.current(), 4);
+        assert_eq!(tuner.adjust(), None);
+        for _ in 0..50 {
+            tuner.record(Duration::from_millis(10));
+        }
+        assert_eq!(tuner.adjust(), Some(44));
+        assert_eq!(tuner.adjust(), None);
+        for _ in 0..100 {
+            tuner.record(Duration::from_secs(2));
+        }
+        assert_eq!(tuner.adjust(), Some(5));
+        for _ in 0..50 {
+            tuner.record(Duration::from_millis(1));
+        }
+        assert_eq!(tuner.adjust(), Some(100));
+    }
+}
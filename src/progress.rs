@@ -0,0 +1,97 @@
+//! Progress reports of running jobs.
+//!
+//! Jobs report their progress to the worker executing them with frames of the executor protocol.
+//! Workers publish each report to a dedicated exchange, using the ID of the job as routing key,
+//! from which clients consume the reports of the jobs they are interested in.
+
+use execution::Reports;
+use executor::{self, Frame};
+
+/// Name of the exchange progress reports are published to.
+pub(crate) const EXCHANGE: &str = "batch.progress";
+
+/// The latest progress of the jobs of the current process, until the worker takes it.
+static PROGRESS: Reports<Progress> = Reports::new();
+
+/// The progress of a running job.
+///
+/// See [`Execution::report_progress`] and [`Client::progress`].
+///
+/// [`Execution::report_progress`]: struct.Execution.html#method.report_progress
+/// [`Client::progress`]: struct.Client.html#method.progress
+///
+/// # Example
+///
+/// ```
+/// use batch::Progress;
+///
+/// let progress = Progress::new(42).with_message("Encoding");
+/// assert_eq!(progress.percent(), 42);
+/// assert_eq!(progress.message(), Some("Encoding"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    percent: u8,
+    message: Option<String>,
+}
+
+impl Progress {
+    /// Create a new `Progress`, capping the given percentage to 100.
+    pub fn new(percent: u8) -> Self {
+        Progress {
+            percent: percent.min(100),
+            message: None,
+        }
+    }
+
+    /// Attach a message describing the current step of the job. Chainable.
+    pub fn with_message(mut self, message: &str) -> Self {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    /// Return the completed percentage of the job.
+    pub fn percent(&self) -> u8 {
+        self.percent
+    }
+
+    /// Return the message describing the current step of the job, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(String::as_ref)
+    }
+
+    /// Record this progress for the worker executing the given job, replacing the previous one.
+    pub(crate) fn record(&self, job_id: &str) {
+        if executor::is_executor() {
+            executor::send(&Frame::Progress {
+                job: job_id.to_string(),
+                progress: self.clone(),
+            });
+        } else {
+            PROGRESS.put(job_id, self.clone());
+        }
+    }
+
+    /// Remove the latest progress recorded by the given job and return it, if any.
+    pub(crate) fn take(job_id: &str) -> Option<Progress> {
+        PROGRESS.take(job_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_latest_progress_is_taken() {
+        let job_id = "tests-progress";
+        assert_eq!(Progress::take(job_id), None);
+        Progress::new(10).record(job_id);
+        Progress::new(250).with_message("Done").record(job_id);
+        assert_eq!(
+            Progress::take(job_id),
+            Some(Progress::new(100).with_message("Done"))
+        );
+        assert_eq!(Progress::take(job_id), None);
+    }
+}
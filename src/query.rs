@@ -2,18 +2,35 @@
 
 use std::fmt;
 use std::result::Result as StdResult;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use futures::{Future, IntoFuture};
 use lapin::channel::{BasicProperties, BasicPublishOptions};
 use lapin::types::{AMQPValue, FieldTable};
+use serde::Serialize;
+use serde_json;
 use uuid::Uuid;
 
 use client::Client;
+use de;
 use error::{self, Error, Result};
-use job::{Job, Priority};
+use execution::{self, Execution, Reschedule, DEADLINE_HEADER, RESERVED_HEADERS};
+use extensions::{self, Extensions};
+use fairness;
+use group::GroupHandle;
+use handle::JobHandle;
+use idempotency;
+use outbox::OutboxMessage;
+use job::{redact, Job, Perform, Priority};
+use naming::interpolate;
 use rabbitmq::Exchange;
 use ser;
+use spool::Entry;
+use status::JobState;
+
+/// Name of the header carrying the suffix of the routing key of a canary job, removed by the
+/// `Client` when it publishes the job.
+pub(crate) const CANARY_HEADER: &str = "canary";
 
 /// A `Query` is responsible for publishing jobs to `RabbitMQ`.
 pub struct Query<T>
@@ -27,6 +44,8 @@ where
     retries: u32,
     options: BasicPublishOptions,
     properties: BasicProperties,
+    #[cfg(feature = "celery-compat")]
+    celery: bool,
 }
 
 impl<T> fmt::Debug for Query<T>
@@ -34,10 +53,15 @@ where
     T: Job + fmt::Debug + 'static,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        let job = if T::redacted_fields().is_empty() {
+            format!("{:?}", self.job)
+        } else {
+            redact(&self.job)
+        };
         write!(
             f,
-            "Query {{ job: {:?} exchange: {:?} routing_key: {:?} timeout: {:?} retries: {:?} options: {:?} properties: {:?} }}",
-            self.job,
+            "Query {{ job: {} exchange: {:?} routing_key: {:?} timeout: {:?} retries: {:?} options: {:?} properties: {:?} }}",
+            job,
             self.exchange,
             self.routing_key,
             self.timeout,
@@ -88,9 +112,19 @@ where
             retries: T::retries(),
             options: BasicPublishOptions::default(),
             properties,
+            #[cfg(feature = "celery-compat")]
+            celery: false,
         }
     }
 
+    /// Return the unique ID of this job.
+    pub fn id(&self) -> &str {
+        self.properties
+            .correlation_id
+            .as_ref()
+            .map_or("", String::as_ref)
+    }
+
     /// Return a reference the properties of this message.
     pub fn properties(&self) -> &BasicProperties {
         &self.properties
@@ -113,13 +147,13 @@ where
 
     /// Set the exchange this job will be published to.
     pub fn exchange(mut self, exchange: &str) -> Self {
-        self.exchange = exchange.into();
+        self.exchange = interpolate(exchange);
         self
     }
 
     /// Set the routing key associated with this job.
     pub fn routing_key(mut self, routing_key: &str) -> Self {
-        self.routing_key = routing_key.into();
+        self.routing_key = interpolate(routing_key);
         self
     }
 
@@ -135,6 +169,360 @@ where
         self
     }
 
+    /// Send this job using Celery's message protocol, to be consumed by Celery workers.
+    ///
+    /// The job is published as the keyword arguments of a Celery task if it is serialized as a
+    /// JSON object, or as its positional arguments if it is serialized as a JSON array. Workers
+    /// recognize Celery messages from their headers, whether or not they were sent this way.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_name = "tasks.add"]
+    /// #[job_routing_key = "celery"]
+    /// struct Add {
+    ///     x: i32,
+    ///     y: i32,
+    /// }
+    ///
+    /// # fn main() {
+    /// let query = job(Add { x: 1, y: 2 }).celery();
+    /// # }
+    /// ```
+    #[cfg(feature = "celery-compat")]
+    pub fn celery(mut self) -> Self {
+        self.celery = true;
+        self
+    }
+
+    /// Drop this job if it isn't consumed within the given duration.
+    ///
+    /// The expiration is enforced by `RabbitMQ`, which discards the job (or dead-letters it, if
+    /// the queue has a dead-letter exchange) once it has been waiting in a queue for longer
+    /// than `ttl`. This is useful for time-sensitive jobs that are pointless when executed late.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    /// use std::time::Duration;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "notifications"]
+    /// struct SendPushNotification;
+    ///
+    /// # fn main() {
+    /// let query = job(SendPushNotification).expires_in(Duration::from_secs(60));
+    /// # }
+    /// ```
+    pub fn expires_in(mut self, ttl: Duration) -> Self {
+        let millis = ttl.as_secs() * 1000 + u64::from(ttl.subsec_nanos() / 1_000_000);
+        {
+            let properties = self.properties_mut();
+            properties.expiration = Some(millis.to_string());
+        }
+        self
+    }
+
+    /// Set the idempotency key of this job.
+    ///
+    /// Workers configured with an idempotency store acknowledge the jobs with the key of a job
+    /// that already completed successfully without executing them, e.g. when the same job is
+    /// published twice because the publisher retried after a network failure. See
+    /// [`WorkerBuilder::idempotency_store`].
+    ///
+    /// [`WorkerBuilder::idempotency_store`]: struct.WorkerBuilder.html#method.idempotency_store
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "payments"]
+    /// struct ChargeOrder {
+    ///     order_id: u64,
+    /// }
+    ///
+    /// # fn main() {
+    /// let query = job(ChargeOrder { order_id: 42 }).idempotency_key("charge-order-42");
+    /// # }
+    /// ```
+    pub fn idempotency_key(mut self, key: &str) -> Self {
+        {
+            let properties = self.properties_mut();
+            let headers = properties.headers.get_or_insert_with(FieldTable::new);
+            headers.insert(
+                idempotency::HEADER.to_string(),
+                AMQPValue::LongString(key.to_string()),
+            );
+        }
+        self
+    }
+
+    /// Add this job to the given group, to be notified once it finishes along with the other jobs
+    /// of the group. See [`Group`](struct.Group.html).
+    pub fn group(mut self, group: &GroupHandle) -> Self {
+        {
+            let properties = self.properties_mut();
+            let headers = properties.headers.get_or_insert_with(FieldTable::new);
+            headers.insert(
+                "group".to_string(),
+                AMQPValue::LongString(group.id().to_string()),
+            );
+        }
+        self
+    }
+
+    /// Set the fairness key of this job, e.g. the ID of the tenant it is executed for.
+    ///
+    /// Workers with fair scheduling enabled interleave the execution of jobs with different keys,
+    /// so that a tenant publishing many jobs doesn't delay the jobs of the others. See
+    /// [`WorkerBuilder::fair_scheduling`].
+    ///
+    /// [`WorkerBuilder::fair_scheduling`]: struct.WorkerBuilder.html#method.fair_scheduling
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "reports"]
+    /// struct GenerateReport {
+    ///     tenant_id: u64,
+    /// }
+    ///
+    /// # fn main() {
+    /// let query = job(GenerateReport { tenant_id: 42 }).fairness_key("42");
+    /// # }
+    /// ```
+    pub fn fairness_key(mut self, key: &str) -> Self {
+        {
+            let properties = self.properties_mut();
+            let headers = properties.headers.get_or_insert_with(FieldTable::new);
+            headers.insert(
+                fairness::HEADER.to_string(),
+                AMQPValue::LongString(key.to_string()),
+            );
+        }
+        self
+    }
+
+    /// Set the ordering key of this job, e.g. the ID of the user whose events it processes.
+    ///
+    /// Workers executing jobs in order (see [`WorkerBuilder::ordered_execution`]) never execute
+    /// two jobs with the same ordering key at the same time, and execute them in the order they
+    /// were received, while jobs with different keys still run concurrently.
+    ///
+    /// [`WorkerBuilder::ordered_execution`]: struct.WorkerBuilder.html#method.ordered_execution
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "events"]
+    /// struct ProcessEvent {
+    ///     user_id: u64,
+    /// }
+    ///
+    /// # fn main() {
+    /// let query = job(ProcessEvent { user_id: 42 }).ordering_key("42");
+    /// # }
+    /// ```
+    pub fn ordering_key(mut self, key: &str) -> Self {
+        {
+            let properties = self.properties_mut();
+            let headers = properties.headers.get_or_insert_with(FieldTable::new);
+            headers.insert(
+                fairness::ORDERING_HEADER.to_string(),
+                AMQPValue::LongString(key.to_string()),
+            );
+        }
+        self
+    }
+
+    /// Set the deadline of this job.
+    ///
+    /// Workers don't execute jobs whose deadline already passed: they reject them, dead-lettering
+    /// them if their queue has a dead-letter exchange, with an `x-batch-error` header telling the
+    /// deadline passed. Handlers can read the time left with
+    /// [`Execution::time_remaining`](struct.Execution.html#method.time_remaining). This is useful
+    /// to propagate the deadline of a request, so that no work is done once its user gave up.
+    ///
+    /// Unlike [`Query::expires_in`](#method.expires_in), the deadline is absolute and is checked
+    /// by the workers, so it relies on the clocks of the publisher & the workers being in sync.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "exports"]
+    /// struct ExportInvoices;
+    ///
+    /// # fn main() {
+    /// let deadline = SystemTime::now() + Duration::from_secs(30);
+    /// let query = job(ExportInvoices).deadline(deadline);
+    /// # }
+    /// ```
+    pub fn deadline(mut self, deadline: SystemTime) -> Self {
+        {
+            let properties = self.properties_mut();
+            let headers = properties.headers.get_or_insert_with(FieldTable::new);
+            headers.insert(
+                DEADLINE_HEADER.to_string(),
+                execution::deadline_header(deadline),
+            );
+        }
+        self
+    }
+
+    /// Attach a custom header to this job.
+    ///
+    /// Headers are sent along with the job and can be read by its handler using
+    /// [`Execution::header`](struct.Execution.html#method.header). The names used internally by
+    /// batch (`lang`, `task`, `id`, `root_id`, `parent_id`, `group`, `timelimit`, `retries`,
+    /// `idempotency_key`, `deadline`, `payload_ref`, `fairness_key`, `ordering_key` & `extensions`)
+    /// are reserved and can't be overwritten.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "emails"]
+    /// struct SendNewsletter;
+    ///
+    /// # fn main() {
+    /// let query = job(SendNewsletter).header("tenant_id", "acme");
+    /// # }
+    /// ```
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        if RESERVED_HEADERS.contains(&name) {
+            warn!("Ignoring custom header `{}': name is reserved", name);
+            return self;
+        }
+        {
+            let properties = self.properties_mut();
+            let headers = properties.headers.get_or_insert_with(FieldTable::new);
+            headers.insert(name.to_string(), AMQPValue::LongString(value.to_string()));
+        }
+        self
+    }
+
+    /// Attach a serializable value to this job, with the given key.
+    ///
+    /// Extensions are sent along with the job, in a header, and can be read by its handler using
+    /// [`Execution::extensions`](struct.Execution.html#method.extensions). A value that can't be
+    /// serialized is ignored, with a warning.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "emails"]
+    /// struct SendNewsletter;
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Tenant {
+    ///     id: u64,
+    ///     plan: String,
+    /// }
+    ///
+    /// # fn main() {
+    /// let tenant = Tenant { id: 42, plan: "premium".into() };
+    /// let query = job(SendNewsletter).extension("tenant", &tenant);
+    /// assert!(query.extensions().contains_key("tenant"));
+    /// # }
+    /// ```
+    pub fn extension<V: Serialize>(mut self, key: &str, value: &V) -> Self {
+        let mut extensions = self.extensions();
+        let header = extensions
+            .insert(key, value)
+            .and_then(|_| extensions.to_header());
+        match header {
+            Ok(header) => {
+                let properties = self.properties_mut();
+                let headers = properties.headers.get_or_insert_with(FieldTable::new);
+                headers.insert(extensions::HEADER.to_string(), header);
+            }
+            Err(e) => warn!("Ignoring extension `{}': {}", key, e),
+        }
+        self
+    }
+
+    /// Return the extensions attached to this job.
+    pub fn extensions(&self) -> Extensions {
+        Extensions::from_properties(&self.properties)
+    }
+
     /// Set the priority for this job.
     pub fn priority(mut self, priority: Priority) -> Self {
         {
@@ -144,24 +532,212 @@ where
         self
     }
 
-    /// Send the job using the given client.
-    pub fn send(self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
+    /// Send this job to canary workers with the given probability, in percent.
+    ///
+    /// Canary jobs are published with `suffix` appended to their routing key, so that they are
+    /// routed to a queue consumed by workers running a new version of their handler. That queue
+    /// must be declared with the client, see
+    /// [`ClientBuilder::queues`](struct.ClientBuilder.html#method.queues), and bound to the job's
+    /// exchange with the suffixed routing key. Otherwise, canary jobs are published with their
+    /// original routing key instead of being dropped by the broker.
+    ///
+    /// Jobs are picked from their ID, so a job is always routed the same way, e.g. when its
+    /// publication is retried.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "invoices"]
+    /// struct GenerateInvoice {
+    ///     order_id: u64,
+    /// }
+    ///
+    /// # fn main() {
+    /// // 5% of the invoices are generated by the workers consuming `invoices.canary`.
+    /// let query = job(GenerateInvoice { order_id: 42 }).canary(5, ".canary");
+    /// # }
+    /// ```
+    pub fn canary(mut self, percentage: u8, suffix: &str) -> Self {
+        if is_canary(self.id(), percentage) {
+            let properties = self.properties_mut();
+            let headers = properties.headers.get_or_insert_with(FieldTable::new);
+            headers.insert(
+                CANARY_HEADER.to_string(),
+                AMQPValue::LongString(suffix.to_string()),
+            );
+        }
+        self
+    }
+
+    /// Stage the job in a transactional outbox instead of sending it.
+    ///
+    /// The returned message should be stored in the application's database, inside the
+    /// transaction modifying the data the job relates to, and is published once the transaction
+    /// is committed by [`Client::relay_outbox`](struct.Client.html#method.relay_outbox).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// # extern crate failure;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::job;
+    /// # use failure::Error;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "emails"]
+    /// struct SendWelcomeEmail {
+    ///     user_id: u64,
+    /// }
+    ///
+    /// # fn main() {
+    /// #     example().unwrap();
+    /// # }
+    /// #
+    /// # fn example() -> Result<(), Error> {
+    /// // In the transaction creating the user:
+    /// let message = job(SendWelcomeEmail { user_id: 42 }).stage()?;
+    /// let row = message.to_json()?;
+    /// // INSERT INTO outbox (message) VALUES ($1)
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stage(mut self) -> Result<OutboxMessage> {
+        let data = self.serialize().map_err(error::ErrorKind::Serialization)?;
+        Ok(OutboxMessage::new(Entry {
+            exchange: self.exchange,
+            routing_key: self.routing_key,
+            mandatory: self.options.mandatory,
+            immediate: self.options.immediate,
+            properties: self.properties,
+            data,
+        }))
+    }
+
+    /// Send the job using the given client, resolving to a handle to the sent job.
+    ///
+    /// If the client has a status tracker, the job is recorded as pending right before being
+    /// published.
+    pub fn send(mut self, client: &Client) -> Box<Future<Item = JobHandle, Error = Error> + Send> {
         let client = client.clone();
-        let task = ser::to_vec(&self.job)
+        let id = self.id().to_string();
+        let start = Instant::now();
+        let serialized = self.serialize();
+        client.record_serialization(start.elapsed());
+        let task = serialized
             .map_err(error::ErrorKind::Serialization)
             .into_future()
             .map_err(|e| e.into())
             .and_then(move |serialized| {
-                client.send(
-                    &self.exchange,
-                    &self.routing_key,
-                    &serialized,
-                    &self.options,
-                    self.properties,
-                )
+                let handle_client = client.clone();
+                client.track(&id, JobState::Pending).and_then(move |_| {
+                    client
+                        .send(
+                            &self.exchange,
+                            &self.routing_key,
+                            &serialized,
+                            &self.options,
+                            self.properties,
+                        )
+                        .map(move |_| JobHandle::new(id, T::name().to_string(), handle_client))
+                })
             });
         Box::new(task)
     }
+
+    /// Serialize the job into the body of the message published to the broker.
+    fn serialize(&mut self) -> serde_json::Result<Vec<u8>> {
+        #[cfg(feature = "celery-compat")]
+        {
+            if self.celery {
+                return ::celery::to_vec(&self.job, &mut self.properties);
+            }
+        }
+        ser::to_vec(&self.job)
+    }
+}
+
+impl<T> Query<T>
+where
+    T: Job + Perform + Send + 'static,
+{
+    /// Execute the job immediately in the current thread, without going through a broker.
+    ///
+    /// The job is serialized and deserialized just like it would be when sent to a `Worker`,
+    /// before its handler is called with the given context. This is useful for tests and command
+    /// line tools that need to run a job synchronously.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// # extern crate failure;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::{job, Perform};
+    /// # use failure::Error;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "hello-world"]
+    /// struct SayHello {
+    ///     to: String,
+    /// }
+    ///
+    /// impl Perform for SayHello {
+    ///     type Context = ();
+    ///
+    ///     fn perform(&self, _ctx: Self::Context) {
+    ///         println!("Hello {}", self.to);
+    ///     }
+    /// }
+    ///
+    /// # fn main() {
+    /// #     example().unwrap();
+    /// # }
+    /// #
+    /// # fn example() -> Result<(), Error> {
+    /// let to = "Ferris".to_string();
+    /// job(SayHello { to }).perform_now(())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn perform_now(self, context: T::Context) -> Result<()> {
+        let serialized = ser::to_vec(&self.job).map_err(error::ErrorKind::Serialization)?;
+        let job: T = de::from_slice(&serialized).map_err(error::ErrorKind::Deserialization)?;
+        Execution::from_properties(&self.properties).scope(|| Perform::perform(&job, context));
+        if Reschedule::take(self.id()).is_some() {
+            warn!("[{}] Ignoring reschedule request of job performed now", self.id());
+        }
+        Ok(())
+    }
+}
+
+/// Return `true` if the job with the given ID falls within the given percentage of canary jobs.
+fn is_canary(id: &str, percentage: u8) -> bool {
+    // FNV-1a, which is stable across releases unlike `DefaultHasher`.
+    let hash = id
+        .bytes()
+        .fold(0x811c_9dc5u32, |hash, byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193));
+    hash % 100 < u32::from(percentage)
 }
 
 /// Shorthand to create a new `Query` instance from a `Job`.
@@ -171,3 +747,16 @@ where
 {
     Query::new(job)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canary_jobs_are_picked_from_their_id() {
+        let ids = (0..1000).map(|_| Uuid::new_v4().to_string()).collect::<Vec<_>>();
+        let canaries = ids.iter().filter(|id| is_canary(id, 10)).count();
+        assert!(canaries > 50 && canaries < 150, "{} canaries", canaries);
+        assert!(ids.iter().all(|id| is_canary(id, 100) && !is_canary(id, 0)));
+    }
+}
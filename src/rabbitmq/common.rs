@@ -1,45 +1,55 @@
+use std::env;
+use std::fs;
 use std::io;
 use std::net;
+use std::process;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use amq_protocol::uri::{AMQPScheme, AMQPUri};
 use futures::{future, Future, IntoFuture};
-use lapin::channel::{Channel, ExchangeBindOptions, QueueBindOptions};
+use lapin::channel::{
+    Channel, ExchangeBindOptions, ExchangeDeclareOptions, ExchangeDeleteOptions, QueueBindOptions,
+    QueueDeclareOptions, QueueDeleteOptions,
+};
 use lapin::client::{self, Client, ConnectionOptions};
 use lapin::types::FieldTable;
 use native_tls::TlsConnector;
 use tokio_executor;
 use tokio_reactor::Handle;
 use tokio_tcp::TcpStream;
+use tokio_timer::Timeout;
 use tokio_tls::TlsConnectorExt;
 
 use error::{Error, ErrorKind};
+use rabbitmq::connection::{redact_url, Connection};
 use rabbitmq::stream::Stream;
-use rabbitmq::types::{Exchange, Queue};
+use rabbitmq::types::{Exchange, Queue, Reconcile};
 
-/// Declare the given queues to the given `Channel`.
+/// Declare the given queues on the given `Channel`, returning the channel to keep using.
+///
+/// The broker closes the channel when a queue already exists with different options or
+/// arguments: the `Reconcile` policy of the queue is then applied on a new channel, which is
+/// returned instead.
 pub fn declare_queues<Q>(
     queues: Q,
+    client: Client<Stream>,
     channel: Channel<Stream>,
-) -> Box<Future<Item = (), Error = io::Error> + Send>
+) -> Box<Future<Item = Channel<Stream>, Error = Error> + Send>
 where
     Q: IntoIterator<Item = Queue> + 'static,
     Q::IntoIter: Send,
 {
-    let task = future::loop_fn(queues.into_iter(), move |mut iter| {
+    let task = future::loop_fn((queues.into_iter(), channel), move |(mut iter, channel)| {
         let next = iter.next();
-        let task: Box<Future<Item = future::Loop<_, _>, Error = io::Error> + Send> =
+        let task: Box<Future<Item = future::Loop<_, _>, Error = Error> + Send> =
             if let Some(queue) = next {
                 trace!("Declaring queue {:?}", queue.name());
-                let binding_channel = channel.clone();
-                let task = channel
-                    .queue_declare(
-                        queue.name(),
-                        queue.options().clone(),
-                        queue.arguments().clone(),
-                    )
-                    .and_then(move |_| {
-                        future::join_all(queue.bindings().clone().into_iter().map(move |b| {
+                let task = declare(&client, channel, Declaration::Queue(queue.clone()))
+                    .and_then(move |channel| {
+                        let binding_channel = channel.clone();
+                        let bindings = queue.bindings().clone().into_iter().map(move |b| {
                             trace!(
                                 "Binding queue {:?} to exchange {:?} on routing key {:?}",
                                 queue.name(),
@@ -53,46 +63,49 @@ where
                                 QueueBindOptions::default(),
                                 FieldTable::new(),
                             )
-                        }))
+                        });
+                        future::join_all(bindings)
+                            .map_err(|e| ErrorKind::Declare(e).into())
+                            .map(move |_| channel)
                     })
-                    .and_then(|_| Ok(future::Loop::Continue(iter)));
+                    .map(move |channel| future::Loop::Continue((iter, channel)));
                 Box::new(task)
             } else {
-                Box::new(future::ok(future::Loop::Break(())))
+                Box::new(future::ok(future::Loop::Break(channel)))
             };
         task
     });
-    Box::new(task.map(|_| ()))
+    Box::new(task)
 }
 
-/// Declare the given exchanges to the given `Channel`.
+/// Declare the given exchanges on the given `Channel`, returning the channel to keep using.
+///
+/// See `declare_queues`.
 pub fn declare_exchanges<E>(
     exchanges: E,
+    client: Client<Stream>,
     channel: Channel<Stream>,
-) -> Box<Future<Item = (), Error = io::Error> + Send>
+) -> Box<Future<Item = Channel<Stream>, Error = Error> + Send>
 where
     E: IntoIterator<Item = Exchange> + 'static,
     E::IntoIter: Send,
 {
-    let task = future::loop_fn(exchanges.into_iter(), move |mut iter| {
+    let task = future::loop_fn((exchanges.into_iter(), channel), move |(mut iter, channel)| {
         let next = iter.next();
-        let task: Box<Future<Item = future::Loop<_, _>, Error = io::Error> + Send> =
+        let task: Box<Future<Item = future::Loop<_, _>, Error = Error> + Send> =
             if let Some(exchange) = next {
-                let binding_channel = channel.clone();
                 trace!(
                     "Declaring RabbitMQ exchange {:?} ({:?})",
                     exchange.name(),
                     exchange.kind()
                 );
-                let task = channel
-                    .exchange_declare(
-                        exchange.name(),
-                        exchange.kind(),
-                        exchange.options().clone(),
-                        exchange.arguments().clone(),
-                    )
-                    .and_then(move |_| {
-                        future::join_all(exchange.bindings().clone().into_iter().map(move |b| {
+                let task = declare(&client, channel, Declaration::Exchange(exchange.clone()))
+                    .and_then(move |channel| {
+                        let binding_channel = channel.clone();
+                        let source_channel = channel.clone();
+                        let name = exchange.name().to_string();
+                        let sources = exchange.sources().clone();
+                        let bindings = exchange.bindings().clone().into_iter().map(move |b| {
                             trace!(
                                 "Binding exchange {:?} ({:?}) to exchange {:?} on routing key {:?}",
                                 exchange.name(),
@@ -107,23 +120,247 @@ where
                                 ExchangeBindOptions::default(),
                                 FieldTable::new(),
                             )
-                        }))
+                        });
+                        let sources = sources.into_iter().map(move |b| {
+                            trace!(
+                                "Binding exchange {:?} to exchange {:?} on routing key {:?}",
+                                b.exchange(),
+                                name,
+                                b.routing_key()
+                            );
+                            source_channel.exchange_bind(
+                                &name,
+                                b.exchange(),
+                                b.routing_key(),
+                                ExchangeBindOptions::default(),
+                                FieldTable::new(),
+                            )
+                        });
+                        future::join_all(bindings)
+                            .and_then(move |_| future::join_all(sources))
+                            .map_err(|e| ErrorKind::Declare(e).into())
+                            .map(move |_| channel)
                     })
-                    .and_then(|_| Ok(future::Loop::Continue(iter)));
+                    .map(move |channel| future::Loop::Continue((iter, channel)));
                 Box::new(task)
             } else {
-                Box::new(future::ok(future::Loop::Break(())))
+                Box::new(future::ok(future::Loop::Break(channel)))
             };
         task
     });
-    Box::new(task.map(|_| ()))
+    Box::new(task)
+}
+
+/// A queue or an exchange to declare.
+enum Declaration {
+    Queue(Queue),
+    Exchange(Exchange),
+}
+
+impl Declaration {
+    fn name(&self) -> &str {
+        match *self {
+            Declaration::Queue(ref queue) => queue.name(),
+            Declaration::Exchange(ref exchange) => exchange.name(),
+        }
+    }
+
+    fn reconcile(&self) -> Reconcile {
+        match *self {
+            Declaration::Queue(ref queue) => queue.reconcile(),
+            Declaration::Exchange(ref exchange) => exchange.reconcile(),
+        }
+    }
+
+    /// Describe the options & arguments of the declaration.
+    fn requested(&self) -> String {
+        match *self {
+            Declaration::Queue(ref queue) => {
+                format!("{:?} {:?}", queue.options(), queue.arguments())
+            }
+            Declaration::Exchange(ref exchange) => format!(
+                "{:?} of kind {:?} {:?}",
+                exchange.options(),
+                exchange.kind(),
+                exchange.arguments()
+            ),
+        }
+    }
+
+    /// Declare the queue or exchange, only checking that it exists if `passive` is true.
+    fn declare(
+        &self,
+        channel: &Channel<Stream>,
+        passive: bool,
+    ) -> Box<Future<Item = (), Error = io::Error> + Send> {
+        match *self {
+            Declaration::Queue(ref queue) => {
+                let options = QueueDeclareOptions {
+                    passive: passive || queue.options().passive,
+                    ..queue.options().clone()
+                };
+                let task = channel
+                    .queue_declare(queue.name(), options, queue.arguments().clone())
+                    .map(|_| ());
+                Box::new(task)
+            }
+            Declaration::Exchange(ref exchange) => {
+                let options = ExchangeDeclareOptions {
+                    passive: passive || exchange.options().passive,
+                    ..exchange.options().clone()
+                };
+                let task = channel.exchange_declare(
+                    exchange.name(),
+                    exchange.kind(),
+                    options,
+                    exchange.arguments().clone(),
+                );
+                Box::new(task)
+            }
+        }
+    }
+
+    fn delete(
+        &self,
+        channel: &Channel<Stream>,
+    ) -> Box<Future<Item = (), Error = io::Error> + Send> {
+        match *self {
+            Declaration::Queue(ref queue) => {
+                Box::new(channel.queue_delete(queue.name(), QueueDeleteOptions::default()))
+            }
+            Declaration::Exchange(ref exchange) => {
+                Box::new(channel.exchange_delete(exchange.name(), ExchangeDeleteOptions::default()))
+            }
+        }
+    }
+}
+
+/// Declare the given queue or exchange, applying its `Reconcile` policy if the declaration fails
+/// because it already exists with different options or arguments.
+fn declare(
+    client: &Client<Stream>,
+    channel: Channel<Stream>,
+    declaration: Declaration,
+) -> Box<Future<Item = Channel<Stream>, Error = Error> + Send> {
+    let client = client.clone();
+    let task = declaration
+        .declare(&channel, false)
+        .then(move |result| match result {
+            Ok(()) => future::Either::A(future::ok(channel)),
+            Err(e) => future::Either::B(reconcile(&client, declaration, e)),
+        });
+    Box::new(task)
+}
+
+/// Apply the `Reconcile` policy of a queue or exchange that couldn't be declared.
+///
+/// The failed declaration closed its channel, so a new one is created. The failure is a conflict
+/// if the queue or exchange exists, which is checked with a passive declaration: the original
+/// error is returned otherwise.
+fn reconcile(
+    client: &Client<Stream>,
+    declaration: Declaration,
+    error: io::Error,
+) -> Box<Future<Item = Channel<Stream>, Error = Error> + Send> {
+    let task = client
+        .create_channel()
+        .and_then(move |channel| {
+            declaration
+                .declare(&channel, true)
+                .then(move |exists| Ok((exists.is_ok(), channel, declaration)))
+        })
+        .map_err(|e| ErrorKind::Connection(e).into())
+        .and_then(move |(exists, channel, declaration)| {
+            let task: Box<Future<Item = Channel<Stream>, Error = Error> + Send> = if !exists {
+                Box::new(future::err(ErrorKind::Declare(error).into()))
+            } else {
+                match declaration.reconcile() {
+                    Reconcile::Fail => Box::new(future::err(
+                        ErrorKind::DeclareConflict {
+                            name: declaration.name().to_string(),
+                            existing: error.to_string(),
+                            requested: declaration.requested(),
+                        }.into(),
+                    )),
+                    Reconcile::Passive => {
+                        warn!(
+                            "`{}' already exists with different options or arguments, using it as is: {}",
+                            declaration.name(),
+                            error
+                        );
+                        Box::new(future::ok(channel))
+                    }
+                    Reconcile::DeleteAndRecreate => {
+                        warn!(
+                            "`{}' already exists with different options or arguments, deleting it: {}",
+                            declaration.name(),
+                            error
+                        );
+                        let task = declaration
+                            .delete(&channel)
+                            .and_then(move |_| {
+                                declaration.declare(&channel, false).map(|_| channel)
+                            })
+                            .map_err(|e| ErrorKind::Declare(e).into());
+                        Box::new(task)
+                    }
+                }
+            };
+            task
+        });
+    Box::new(task)
+}
+
+/// Return the name of the host the process runs on.
+pub fn hostname() -> String {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| {
+            fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|hostname| hostname.trim().to_string())
+        })
+        .unwrap_or_default()
+}
+
+/// Return the name identifying the connections of the process by default: the name of the host
+/// it runs on and its ID.
+pub fn default_connection_name() -> String {
+    format!("{}-{}", hostname(), process::id())
 }
 
+/// Connect to the first reachable endpoint of the given `Connection`, trying them in order.
 pub fn connect(
-    connection_url: &str,
+    connection: &Connection,
+    handle: Handle,
+) -> Box<Future<Item = (Client<Stream>, HeartbeatHandle), Error = Error> + Send> {
+    let connection = connection.clone();
+    let endpoints = connection.endpoints();
+    let task = future::loop_fn(endpoints.into_iter(), move |mut endpoints| {
+        let url = endpoints.next().expect("connection without endpoint");
+        let last = endpoints.as_slice().is_empty();
+        connect_endpoint(&url, &connection, handle.clone()).then(move |result| match result {
+            Ok(connected) => Ok(future::Loop::Break(connected)),
+            Err(e) if !last => {
+                warn!("Couldn't connect to {}, trying the next endpoint: {}", redact_url(&url), e);
+                Ok(future::Loop::Continue(endpoints))
+            }
+            Err(e) => Err(e),
+        })
+    });
+    Box::new(task)
+}
+
+/// Connect to the broker at the given URL, using the settings of the given `Connection`.
+fn connect_endpoint(
+    url: &str,
+    connection: &Connection,
     handle: Handle,
 ) -> Box<Future<Item = (Client<Stream>, HeartbeatHandle), Error = Error> + Send> {
-    let task = AMQPUri::from_str(connection_url)
+    let heartbeat = connection.heartbeat().map(|interval| interval.as_secs() as u16);
+    let frame_max = connection.frame_max();
+    let task = AMQPUri::from_str(url)
         .map_err(|e| ErrorKind::InvalidUrl(e).into())
         .into_future()
         .and_then(|uri| {
@@ -131,7 +368,7 @@ pub fn connect(
             let addr_uri = uri.clone();
             let addr = (addr_uri.authority.host.as_ref(), addr_uri.authority.port);
             net::TcpStream::connect(addr)
-                .map_err(|e| ErrorKind::Io(e).into())
+                .map_err(|e| ErrorKind::Connection(e).into())
                 .into_future()
                 .join(future::ok(uri))
         })
@@ -141,19 +378,19 @@ pub fn connect(
                     trace!("Wrapping TCP connection into tokio-tcp");
                     let task = TcpStream::from_std(stream, &handle)
                         .map(Stream::Raw)
-                        .map_err(|e| ErrorKind::Io(e).into())
+                        .map_err(|e| ErrorKind::Connection(e).into())
                         .into_future();
                     Box::new(task)
                 } else {
                     trace!("Wrapping TCP connection into tokio-tls");
                     let host = uri.authority.host.clone();
                     let task = TlsConnector::builder()
-                        .map_err(|e| ErrorKind::Tls(e).into())
+                        .map_err(tls_error)
                         .into_future()
-                        .and_then(|builder| builder.build().map_err(|e| ErrorKind::Tls(e).into()))
+                        .and_then(|builder| builder.build().map_err(tls_error))
                         .and_then(move |connector| {
                             TcpStream::from_std(stream, &handle)
-                                .map_err(|e| ErrorKind::Io(e).into())
+                                .map_err(|e| ErrorKind::Connection(e).into())
                                 .into_future()
                                 .join(future::ok(connector))
                         })
@@ -161,7 +398,7 @@ pub fn connect(
                             connector
                                 .connect_async(&host, stream)
                                 .map(Stream::Tls)
-                                .map_err(|e| ErrorKind::Tls(e).into())
+                                .map_err(tls_error)
                         });
                     Box::new(task)
                 };
@@ -173,32 +410,104 @@ pub fn connect(
                 username: uri.authority.userinfo.username,
                 password: uri.authority.userinfo.password,
                 vhost: uri.vhost,
-                frame_max: uri.query.frame_max.unwrap_or(0),
-                heartbeat: uri.query.heartbeat.unwrap_or(0),
+                frame_max: frame_max.or(uri.query.frame_max).unwrap_or(0),
+                heartbeat: heartbeat.or(uri.query.heartbeat).unwrap_or(0),
             };
             Client::connect(stream, opts)
-                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+                .map_err(|e| ErrorKind::Connection(e).into())
                 .map(move |(client, mut heartbeat)| {
-                    let heartbeat_handle = HeartbeatHandle(heartbeat.handle());
+                    let lost = Arc::new(AtomicBool::new(false));
+                    let heartbeat_handle = HeartbeatHandle {
+                        handle: heartbeat.handle(),
+                        lost: Arc::clone(&lost),
+                    };
                     trace!("Spawning RabbitMQ heartbeat future");
-                    tokio_executor::spawn(heartbeat.map_err(|e| {
+                    tokio_executor::spawn(heartbeat.map_err(move |e| {
                         error!("Couldn't send heartbeat to RabbitMQ: {}", e);
+                        lost.store(true, Ordering::SeqCst);
                     }));
                     (client, heartbeat_handle)
                 })
         });
-    Box::new(task)
+    match connection.timeout() {
+        Some(timeout) => {
+            let task = Timeout::new(task, timeout).map_err(move |e| {
+                if e.is_elapsed() {
+                    return ErrorKind::Timeout(timeout).into();
+                }
+                e.into_inner().unwrap_or_else(|| {
+                    let e = io::Error::new(io::ErrorKind::Other, "timer failure");
+                    ErrorKind::Reactor(e).into()
+                })
+            });
+            Box::new(task)
+        }
+        None => Box::new(task),
+    }
 }
 
-pub struct HeartbeatHandle(Option<client::HeartbeatHandle>);
+/// Wrap an error of the TLS stack, whose type isn't exposed by `ErrorKind::Tls`.
+fn tls_error(error: ::native_tls::Error) -> Error {
+    ErrorKind::Tls(io::Error::new(io::ErrorKind::Other, error)).into()
+}
+
+/// Stops the heartbeat future of a connection when dropped.
+pub struct HeartbeatHandle {
+    handle: Option<client::HeartbeatHandle>,
+    lost: Arc<AtomicBool>,
+}
+
+impl HeartbeatHandle {
+    /// Return true if a heartbeat couldn't be sent, i.e. the connection to the broker was lost.
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+}
 
 impl Drop for HeartbeatHandle {
     fn drop(&mut self) {
         trace!("Signaling RabbitMQ heartbeat future to stop");
-        if let Some(handle) = self.0.take() {
+        if let Some(handle) = self.handle.take() {
             handle.stop();
         } else {
             warn!("Couldn't acquire heartbeat handle");
         }
     }
 }
+
+/// Declare the given exchanges and queues on a fresh connection, then close it.
+pub fn declare_with_handle<E, Q>(
+    connection: &Connection,
+    exchanges_iter: E,
+    queues_iter: Q,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = Error> + Send>
+where
+    E: IntoIterator<Item = Exchange> + Send,
+    Q: IntoIterator<Item = Queue> + Send,
+{
+    let exchanges = exchanges_iter.into_iter().collect::<Vec<_>>();
+    let queues = queues_iter.into_iter().collect::<Vec<_>>();
+
+    let task = connect(connection, handle)
+        .and_then(|(client, heartbeat_handle)| {
+            trace!("Creating declaration RabbitMQ channel");
+            client
+                .create_channel()
+                .map(|channel| (client, channel, heartbeat_handle))
+                .map_err(|e| ErrorKind::Connection(e).into())
+        })
+        .and_then(move |(client, channel, heartbeat_handle)| {
+            trace!("Declaring RabbitMQ exchanges");
+            declare_exchanges(exchanges, client.clone(), channel)
+                .map(|channel| (client, channel, heartbeat_handle))
+        })
+        .and_then(move |(client, channel, heartbeat_handle)| {
+            trace!("Declaring RabbitMQ queues");
+            declare_queues(queues, client, channel).map(|channel| (channel, heartbeat_handle))
+        })
+        .map(|(_channel, _heartbeat_handle)| {
+            trace!("Declared RabbitMQ topology");
+        });
+    Box::new(task)
+}
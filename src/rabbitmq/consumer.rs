@@ -1,7 +1,9 @@
 use std::fmt;
 use std::io;
+use std::mem;
 use std::result::Result as StdResult;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::{self, future, Async, Future, Poll};
 use lapin::channel::{BasicConsumeOptions, BasicQosOptions, Channel};
@@ -9,22 +11,47 @@ use lapin::client::Client;
 use lapin::message::Delivery as Message;
 use lapin::queue::Queue as LapinQueue;
 use lapin::types::FieldTable;
+use tokio_executor::{self, DefaultExecutor, Executor};
 use tokio_reactor::Handle;
+use tokio_timer::Timeout;
 
 use error::{Error, ErrorKind};
 use rabbitmq::common::{connect, declare_exchanges, declare_queues, HeartbeatHandle};
 use rabbitmq::delivery::Delivery;
+use rabbitmq::connection::Connection;
 use rabbitmq::stream::Stream;
 use rabbitmq::types::{Exchange, Queue};
 
+/// Type of the stream of messages consumed from a queue.
+type Messages = Box<futures::Stream<Item = Message, Error = io::Error> + Send>;
+
 /// A `Consumer` of incoming jobs.
 ///
 /// The type of the stream is a tuple containing a `u64` which is a unique ID for the
 /// job used when `ack`'ing or `reject`'ing it, and a `Job` instance.
 pub struct Consumer {
     channel: Channel<Stream>,
-    stream: Box<futures::Stream<Item = Message, Error = io::Error> + Send>,
+    queues: Vec<(String, State)>,
+    next: usize,
+    exclusive: bool,
+    name: String,
     heartbeat_handle: Arc<HeartbeatHandle>,
+    /// Whether the connection is shared with others, in which case the channel is closed when
+    /// the `Consumer` is dropped.
+    shared: bool,
+}
+
+/// Type of the future starting the consumption of a queue.
+type Consume = Box<Future<Item = Messages, Error = io::Error> + Send>;
+
+/// The state of the consumption of a queue.
+enum State {
+    Consuming(Messages),
+    /// The consumer was cancelled, and its remaining messages are drained. The consumption is
+    /// started again once they all are, if the queue was resumed meanwhile.
+    Pausing(Messages, Option<Consume>),
+    Paused,
+    Resuming(Consume),
 }
 
 impl fmt::Debug for Consumer {
@@ -35,11 +62,16 @@ impl fmt::Debug for Consumer {
 
 impl Consumer {
     /// Create a `Consumer` instance from a RabbitMQ URI and an explicit tokio handle.
+    ///
+    /// If `exclusive` is true, the broker refuses to let other consumers consume the same queues.
+    /// The tags of the RabbitMQ consumers of the queues start with the given `name`.
     pub fn new_with_handle<E, Q>(
-        connection_url: &str,
+        connection: &Connection,
         exchanges_iter: E,
         queues_iter: Q,
         prefetch_count: u16,
+        exclusive: bool,
+        name: &str,
         handle: Handle,
     ) -> Box<Future<Item = Self, Error = Error> + Send>
     where
@@ -48,29 +80,82 @@ impl Consumer {
     {
         let exchanges = exchanges_iter.into_iter().collect::<Vec<_>>();
         let queues = queues_iter.into_iter().collect::<Vec<_>>();
+        let name = name.to_string();
+        let task = connect(connection, handle).and_then(move |(client, heartbeat_handle)| {
+            let heartbeat_handle = Arc::new(heartbeat_handle);
+            Consumer::create(
+                client,
+                heartbeat_handle,
+                exchanges,
+                queues,
+                prefetch_count,
+                exclusive,
+                &name,
+            )
+        });
+        Box::new(task)
+    }
+
+    /// Create a `Consumer` instance on its own channel of an existing connection to the broker.
+    ///
+    /// The channel is closed once the `Consumer` is dropped, leaving the connection open.
+    pub fn on_connection<E, Q>(
+        client: Client<Stream>,
+        heartbeat_handle: Arc<HeartbeatHandle>,
+        exchanges_iter: E,
+        queues_iter: Q,
+        prefetch_count: u16,
+        exclusive: bool,
+        name: &str,
+    ) -> Box<Future<Item = Self, Error = Error> + Send>
+    where
+        E: IntoIterator<Item = Exchange>,
+        Q: IntoIterator<Item = Queue>,
+    {
+        let exchanges = exchanges_iter.into_iter().collect::<Vec<_>>();
+        let queues = queues_iter.into_iter().collect::<Vec<_>>();
+        let task = Consumer::create(
+            client,
+            heartbeat_handle,
+            exchanges,
+            queues,
+            prefetch_count,
+            exclusive,
+            name,
+        ).map(|mut consumer| {
+            consumer.shared = true;
+            consumer
+        });
+        Box::new(task)
+    }
+
+    /// Create a `Consumer` instance on a new channel of the given connection.
+    fn create(
+        client: Client<Stream>,
+        heartbeat_handle: Arc<HeartbeatHandle>,
+        exchanges: Vec<Exchange>,
+        queues: Vec<Queue>,
+        prefetch_count: u16,
+        exclusive: bool,
+        name: &str,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
         let queues_ = queues.clone();
+        let name = name.to_string();
+        let consumer_name = name.clone();
 
-        let task = connect(connection_url, handle)
-            .and_then(|(client, heartbeat_handle)| {
-                trace!("Creating consumer's RabbitMQ channel");
-                client
-                    .create_channel()
-                    .map(|channel| (channel, heartbeat_handle))
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
-            })
-            .and_then(move |(channel, heartbeat_handle)| {
+        trace!("Creating consumer's RabbitMQ channel");
+        let task = client
+            .create_channel()
+            .map(|channel| (client, channel, heartbeat_handle))
+            .map_err(|e| ErrorKind::Connection(e).into())
+            .and_then(move |(client, channel, heartbeat_handle)| {
                 trace!("Declaring consumer's RabbitMQ exchanges");
-                let channel_ = channel.clone();
-                declare_exchanges(exchanges, channel_)
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
-                    .map(|_| (channel, heartbeat_handle))
+                declare_exchanges(exchanges, client.clone(), channel)
+                    .map(|channel| (client, channel, heartbeat_handle))
             })
-            .and_then(move |(channel, heartbeat_handle)| {
+            .and_then(move |(client, channel, heartbeat_handle)| {
                 trace!("Declaring consumer's RabbitMQ channels");
-                let channel_ = channel.clone();
-                declare_queues(queues_, channel_)
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
-                    .map(|_| (channel, heartbeat_handle))
+                declare_queues(queues_, client, channel).map(|channel| (channel, heartbeat_handle))
             })
             .and_then(move |(channel, heartbeat_handle)| {
                 channel
@@ -81,44 +166,136 @@ impl Consumer {
                     .map_err(|e| ErrorKind::Rabbitmq(e).into())
                     .map(|_| (channel, heartbeat_handle))
             })
-            .and_then(|(channel, heartbeat_handle)| {
+            .and_then(move |(channel, heartbeat_handle)| {
                 trace!("Creating consumer's inner stream");
                 let consumer_channel = channel.clone();
                 future::join_all(queues.into_iter().map(move |queue| {
-                    trace!(
-                        "Creating RabbitMQ consumer batch-rs-consumer-{}",
-                        queue.name()
-                    );
-                    consumer_channel
-                        .basic_consume(
-                            &LapinQueue::new(queue.name().into()),
-                            &format!("batch-rs-consumer-{}", queue.name()),
-                            BasicConsumeOptions::default(),
-                            FieldTable::new(),
-                        )
+                    let queue = queue.name().to_string();
+                    consume(&consumer_channel, &consumer_name, &queue, exclusive)
+                        .map(move |messages| (queue, State::Consuming(messages)))
                         .map_err(|e| ErrorKind::Rabbitmq(e).into())
                 })).join(future::ok((channel, heartbeat_handle)))
             })
-            .map(move |(mut consumers, (channel, heartbeat_handle))| {
-                let initial: Box<
-                    futures::Stream<Item = Message, Error = io::Error> + Send,
-                > = Box::new(consumers.pop().unwrap());
-                let stream = consumers.into_iter().fold(initial, |acc, consumer| {
-                    Box::new(futures::Stream::select(acc, consumer))
-                });
-                Consumer {
-                    channel,
-                    stream,
-                    heartbeat_handle: Arc::new(heartbeat_handle),
-                }
+            .map(move |(queues, (channel, heartbeat_handle))| Consumer {
+                channel,
+                queues,
+                next: 0,
+                exclusive,
+                name,
+                heartbeat_handle,
+                shared: false,
             });
         Box::new(task)
     }
 
+    /// Stop consuming the given queue, until it is resumed.
+    ///
+    /// The jobs already delivered by the broker are still yielded. Does nothing if the queue isn't
+    /// consumed by this `Consumer`, or is already paused.
+    pub fn pause(&mut self, queue: &str) {
+        let channel = self.channel.clone();
+        for &mut (ref name, ref mut state) in &mut self.queues {
+            if name != queue {
+                continue;
+            }
+            if let State::Pausing(_, ref mut resume) = *state {
+                if resume.take().is_some() {
+                    debug!("Pausing consumption of queue `{}' again", name);
+                }
+            }
+            if let State::Consuming(_) = *state {
+                debug!("Pausing consumption of queue `{}'", name);
+                let task = channel
+                    .basic_cancel(&consumer_tag(&self.name, name))
+                    .map_err(|e| error!("Couldn't cancel RabbitMQ consumer: {}", e));
+                tokio_executor::spawn(task);
+                *state = match mem::replace(state, State::Paused) {
+                    State::Consuming(messages) => State::Pausing(messages, None),
+                    _ => unreachable!(),
+                };
+            }
+        }
+    }
+
+    /// Consume the given queue again after it was paused.
+    ///
+    /// If the queue is still being paused, the jobs already delivered are yielded first, and the
+    /// consumption starts again once the broker confirmed the cancellation of the previous one.
+    /// Does nothing if the queue isn't consumed by this `Consumer`, or isn't paused.
+    pub fn resume(&mut self, queue: &str) {
+        let channel = self.channel.clone();
+        let exclusive = self.exclusive;
+        for &mut (ref name, ref mut state) in &mut self.queues {
+            if name != queue {
+                continue;
+            }
+            match *state {
+                State::Pausing(_, ref mut resume) => {
+                    if resume.is_none() {
+                        debug!("Resuming consumption of queue `{}' once it is paused", name);
+                        let (channel, consumer, queue) =
+                            (channel.clone(), self.name.clone(), name.clone());
+                        *resume = Some(Box::new(future::lazy(move || {
+                            consume(&channel, &consumer, &queue, exclusive)
+                        })));
+                    }
+                }
+                State::Paused => {
+                    debug!("Resuming consumption of queue `{}'", name);
+                    *state = State::Resuming(consume(&channel, &self.name, name, exclusive));
+                }
+                State::Consuming(_) | State::Resuming(_) => (),
+            }
+        }
+    }
+
+    /// Return the names of the queues consumed by this `Consumer`, paused or not.
+    pub fn queues(&self) -> Vec<&str> {
+        self.queues.iter().map(|&(ref name, _)| name.as_str()).collect()
+    }
+
     /// Creates a new `ConsumerHandle` instance.
     pub fn handle(&self) -> ConsumerHandle {
         ConsumerHandle::new(self.channel.clone(), Arc::clone(&self.heartbeat_handle))
     }
+
+    /// Return the stream of deliveries of this `Consumer`, ending once none was received for
+    /// `idle`, e.g. when its queues are empty.
+    pub fn until_idle(
+        self,
+        idle: Duration,
+    ) -> Box<futures::Stream<Item = Delivery, Error = Error> + Send> {
+        use futures::Stream;
+
+        let deliveries = Timeout::new(self, idle)
+            .then(|result| match result {
+                Ok(delivery) => Ok(Some(delivery)),
+                Err(ref e) if e.is_elapsed() => Ok(None),
+                Err(e) => Err(e.into_inner().unwrap_or_else(|| {
+                    let e = io::Error::new(io::ErrorKind::Other, "timer failure");
+                    ErrorKind::Reactor(e).into()
+                })),
+            })
+            .take_while(|delivery| Ok(delivery.is_some()))
+            .filter_map(|delivery| delivery);
+        Box::new(deliveries)
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        if !self.shared {
+            return;
+        }
+        trace!("Closing consumer's RabbitMQ channel");
+        let task = self
+            .channel
+            .close(200, "Bye")
+            .map_err(|e| debug!("Couldn't close consumer's RabbitMQ channel: {}", e));
+        if DefaultExecutor::current().spawn(Box::new(task)).is_err() {
+            debug!("Couldn't close consumer's RabbitMQ channel: no executor");
+        }
+    }
 }
 
 impl futures::Stream for Consumer {
@@ -126,22 +303,83 @@ impl futures::Stream for Consumer {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let async = match self.stream.poll() {
-            Ok(async) => async,
-            Err(e) => return Err(ErrorKind::Rabbitmq(e).into()),
-        };
-        let option = match async {
-            Async::Ready(option) => option,
-            Async::NotReady => return Ok(Async::NotReady),
-        };
-        let message = match option {
-            Some(message) => message,
-            None => return Ok(Async::Ready(None)),
-        };
-        Ok(Async::Ready(Some(Delivery(message))))
+        let count = self.queues.len();
+        let mut finished = 0;
+        for i in 0..count {
+            let index = (self.next + i) % count;
+            let &mut (ref name, ref mut state) = &mut self.queues[index];
+            match state.poll() {
+                Ok(Async::Ready(Some(message))) => {
+                    self.next = (index + 1) % count;
+                    return Ok(Async::Ready(Some(Delivery::new(message, name.clone()))));
+                }
+                Ok(Async::Ready(None)) => finished += 1,
+                Ok(Async::NotReady) => (),
+                Err(e) => return Err(ErrorKind::Rabbitmq(e).into()),
+            }
+        }
+        if finished == count {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl futures::Stream for State {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let next = match *self {
+                State::Consuming(ref mut messages) => return messages.poll(),
+                State::Pausing(ref mut messages, ref mut resume) => match messages.poll()? {
+                    Async::Ready(None) => match resume.take() {
+                        Some(consume) => State::Resuming(consume),
+                        None => State::Paused,
+                    },
+                    async => return Ok(async),
+                },
+                State::Paused => return Ok(Async::NotReady),
+                State::Resuming(ref mut consume) => match consume.poll()? {
+                    Async::Ready(messages) => State::Consuming(messages),
+                    Async::NotReady => return Ok(Async::NotReady),
+                },
+            };
+            *self = next;
+        }
     }
 }
 
+/// Start consuming the given queue on the given channel, exclusively if `exclusive` is true.
+fn consume(
+    channel: &Channel<Stream>,
+    name: &str,
+    queue: &str,
+    exclusive: bool,
+) -> Box<Future<Item = Messages, Error = io::Error> + Send> {
+    let tag = consumer_tag(name, queue);
+    trace!("Creating RabbitMQ consumer {}", tag);
+    let task = channel
+        .basic_consume(
+            &LapinQueue::new(queue.into()),
+            &tag,
+            BasicConsumeOptions {
+                exclusive,
+                ..Default::default()
+            },
+            FieldTable::new(),
+        )
+        .map(|consumer| -> Messages { Box::new(consumer) });
+    Box::new(task)
+}
+
+/// Return the tag of the RabbitMQ consumer of the given queue, for a consumer with the given name.
+fn consumer_tag(name: &str, queue: &str) -> String {
+    format!("{}:{}", name, queue)
+}
+
 pub struct ConsumerHandle(Channel<Stream>, Arc<HeartbeatHandle>);
 
 impl ConsumerHandle {
@@ -161,6 +399,33 @@ impl ConsumerHandle {
         Box::new(task)
     }
 
+    /// Reject a `Job` so that it is delivered again, to this consumer or another one.
+    ///
+    /// Returns a `Future` that completes once the `reject` is sent to the broker.
+    pub fn requeue(&self, uid: u64) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Requeuing message {}", uid);
+        let task = self.0
+            .basic_reject(uid, true)
+            .map_err(|e| ErrorKind::Rabbitmq(e).into());
+        Box::new(task)
+    }
+
+    /// Limit the number of unacknowledged jobs delivered on this consumer's channel.
+    ///
+    /// The limit is shared by all the queues consumed on the channel and applies immediately,
+    /// in addition to the per-queue limit given when creating the `Consumer`.
+    pub fn prefetch(&self, prefetch_count: u16) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Setting channel prefetch count to {}", prefetch_count);
+        let task = self.0
+            .basic_qos(BasicQosOptions {
+                prefetch_count,
+                global: true,
+                ..Default::default()
+            })
+            .map_err(|e| ErrorKind::Rabbitmq(e).into());
+        Box::new(task)
+    }
+
     /// Reject the successful execution of a `Job`.
     ///
     /// Returns a `Future` that completes once the `reject` is sent to the broker.
@@ -172,3 +437,32 @@ impl ConsumerHandle {
         Box::new(task)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream;
+    use futures::Stream as FuturesStream;
+    use lapin::channel::BasicProperties;
+
+    fn messages(tags: Vec<u64>) -> Messages {
+        let messages = tags.into_iter().map(|delivery_tag| Message {
+            delivery_tag,
+            exchange: "batch.tests".to_string(),
+            routing_key: "pause".to_string(),
+            redelivered: false,
+            properties: BasicProperties::default(),
+            data: Vec::new(),
+        });
+        Box::new(stream::iter_ok(messages.collect::<Vec<_>>()))
+    }
+
+    #[test]
+    fn resuming_drains_the_cancelled_consumer_first() {
+        let resume: Consume = Box::new(future::lazy(|| Ok(messages(vec![3]))));
+        let state = State::Pausing(messages(vec![1, 2]), Some(resume));
+        let tags = state.take(3).map(|message| message.delivery_tag).collect().wait().unwrap();
+        assert_eq!(tags, vec![1, 2, 3]);
+    }
+}
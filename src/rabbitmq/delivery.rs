@@ -1,12 +1,18 @@
-use std::time::Duration;
+use std::fmt;
+use std::time::{Duration, SystemTime};
 
 use lapin::channel::BasicProperties as Properties;
 use lapin::message::Delivery as Message;
 use lapin::types::{self, AMQPValue, FieldTable};
 
+use execution;
+use fairness;
+use idempotency;
+use payload;
+
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "Properties")]
-struct PropertiesDef {
+pub(crate) struct PropertiesDef {
     pub content_type: Option<types::ShortString>,
     pub content_encoding: Option<types::ShortString>,
     pub headers: Option<types::FieldTable>,
@@ -35,16 +41,44 @@ struct MessageDef {
     pub data: Vec<u8>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Delivery(#[serde(with = "MessageDef")] pub Message);
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    #[serde(with = "MessageDef")]
+    message: Message,
+    queue: String,
+}
+
+/// Omits the payload, which may hold sensitive data: see `job::redact_payload` to log it.
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Delivery")
+            .field("delivery_tag", &self.message.delivery_tag)
+            .field("exchange", &self.message.exchange)
+            .field("routing_key", &self.message.routing_key)
+            .field("redelivered", &self.message.redelivered)
+            .field("properties", &self.message.properties)
+            .field("data", &format_args!("<{} bytes>", self.message.data.len()))
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
 
 impl Delivery {
+    pub fn new(message: Message, queue: String) -> Self {
+        Delivery { message, queue }
+    }
+
     pub fn tag(&self) -> u64 {
-        self.0.delivery_tag
+        self.message.delivery_tag
+    }
+
+    /// Return the name of the queue this delivery was consumed from.
+    pub fn queue(&self) -> &str {
+        &self.queue
     }
 
     pub fn task(&self) -> &str {
-        self.0
+        self.message
             .properties
             .headers
             .as_ref()
@@ -56,53 +90,157 @@ impl Delivery {
     }
 
     pub fn task_id(&self) -> &str {
-        self.0
+        self.message
             .properties
             .correlation_id
             .as_ref()
             .map_or("", String::as_ref)
     }
 
+    /// Return the fairness key of this delivery's job, if any.
+    pub fn fairness_key(&self) -> Option<&str> {
+        self.message
+            .properties
+            .headers
+            .as_ref()
+            .and_then(|hdrs| match hdrs.get(fairness::HEADER) {
+                Some(&AMQPValue::LongString(ref key)) => Some(key.as_ref()),
+                _ => None,
+            })
+    }
+
+    /// Return the ordering key of this delivery's job, if any.
+    pub fn ordering_key(&self) -> Option<&str> {
+        self.message
+            .properties
+            .headers
+            .as_ref()
+            .and_then(|hdrs| match hdrs.get(fairness::ORDERING_HEADER) {
+                Some(&AMQPValue::LongString(ref key)) => Some(key.as_ref()),
+                _ => None,
+            })
+    }
+
+    /// Return the idempotency key of this delivery's job, if any.
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.message
+            .properties
+            .headers
+            .as_ref()
+            .and_then(|hdrs| match hdrs.get(idempotency::HEADER) {
+                Some(&AMQPValue::LongString(ref key)) => Some(key.as_ref()),
+                _ => None,
+            })
+    }
+
+    /// Return the ID of the group this delivery's job belongs to, if any.
+    pub fn group(&self) -> Option<&str> {
+        self.message
+            .properties
+            .headers
+            .as_ref()
+            .and_then(|hdrs| match hdrs.get("group") {
+                Some(&AMQPValue::LongString(ref group)) => Some(group.as_ref()),
+                _ => None,
+            })
+    }
+
+    /// Return the reference of this delivery's payload in a `PayloadStore`, if any.
+    pub fn payload_ref(&self) -> Option<&str> {
+        self.message
+            .properties
+            .headers
+            .as_ref()
+            .and_then(|hdrs| match hdrs.get(payload::HEADER) {
+                Some(&AMQPValue::LongString(ref reference)) => Some(reference.as_ref()),
+                _ => None,
+            })
+    }
+
+    /// Return a copy of this delivery with the given payload.
+    pub fn with_data(&self, data: Vec<u8>) -> Delivery {
+        let mut delivery = self.clone();
+        delivery.message.data = data;
+        delivery
+    }
+
+    /// Return the deadline of this delivery's job, if any.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        execution::deadline(&self.message.properties)
+    }
+
     pub fn exchange(&self) -> &str {
-        &self.0.exchange
+        &self.message.exchange
     }
 
     pub fn routing_key(&self) -> &str {
-        &self.0.routing_key
+        &self.message.routing_key
     }
 
     pub fn data(&self) -> &[u8] {
-        &self.0.data
+        &self.message.data
     }
 
     pub fn properties(&self) -> &Properties {
-        &self.0.properties
+        &self.message.properties
     }
 
     pub fn timeout(&self) -> (Option<Duration>, Option<Duration>) {
-        self.0
+        self.message
             .properties
             .headers
             .as_ref()
             .map(|hdrs| match hdrs.get("timelimit") {
                 Some(&AMQPValue::FieldArray(ref vec)) if vec.len() == 2 => {
-                    let soft_limit = match vec[0] {
-                        AMQPValue::Timestamp(s) => Some(Duration::from_secs(s)),
-                        _ => None,
-                    };
-                    let hard_limit = match vec[1] {
-                        AMQPValue::Timestamp(s) => Some(Duration::from_secs(s)),
-                        _ => None,
-                    };
-                    (soft_limit, hard_limit)
+                    (seconds(&vec[0]), seconds(&vec[1]))
                 }
                 _ => (None, None),
             })
             .unwrap_or((None, None))
     }
 
+    /// Return the number of the current attempt at executing this job, starting at 1.
+    ///
+    /// Previous attempts are counted from the `retries` & `x-batch-attempts` headers set by
+    /// the workers and from the `x-death` header set by RabbitMQ when the job was rejected to a
+    /// dead-letter exchange, so that attempts aren't lost when jobs are retried through another
+    /// queue or by another worker. A job redelivered by the broker, e.g. after a worker crashed,
+    /// counts as another attempt, but not towards its retries, see `Delivery::should_retry`.
+    pub fn attempt(&self) -> u32 {
+        self.failed_attempts() + self.message.redelivered as u32 + 1
+    }
+
+    /// Return the number of previous attempts at executing this job which failed, from its
+    /// headers.
+    fn failed_attempts(&self) -> u32 {
+        let headers = match self.message.properties.headers {
+            Some(ref headers) => headers,
+            None => return 0,
+        };
+        let batch_attempts = match headers.get("x-batch-attempts") {
+            Some(value) => count(value),
+            None => 0,
+        };
+        let deaths = match headers.get("x-death") {
+            Some(&AMQPValue::FieldArray(ref deaths)) => deaths
+                .iter()
+                .map(|death| match *death {
+                    AMQPValue::FieldTable(ref death) => match death.get("reason") {
+                        Some(&AMQPValue::LongString(ref reason)) if reason == "rejected" => {
+                            death.get("count").map_or(1, count)
+                        }
+                        _ => 0,
+                    },
+                    _ => 0,
+                })
+                .sum(),
+            _ => 0,
+        };
+        *[self.retries(), batch_attempts, deaths].iter().max().unwrap()
+    }
+
     pub fn retries(&self) -> u32 {
-        self.0
+        self.message
             .properties
             .headers
             .as_ref()
@@ -113,19 +251,139 @@ impl Delivery {
             .unwrap_or(0)
     }
 
+    /// Record the failure of the current attempt in the `retries` header, returning the number
+    /// of failed attempts.
+    ///
+    /// Redeliveries aren't counted: a job is requeued without being attempted when its worker
+    /// shuts down gracefully, and RabbitMQ doesn't tell these redeliveries apart from others.
     pub fn incr_retries(&mut self) -> u32 {
-        let incrd_retries = self.retries() + 1;
-        let mut headers = self.0
+        let incrd_retries = self.failed_attempts() + 1;
+        let mut headers = self.message
             .properties
             .headers
             .take()
             .unwrap_or_else(FieldTable::new);
         headers.insert("retries".to_string(), AMQPValue::LongUInt(incrd_retries));
-        self.0.properties.headers = Some(headers);
+        self.message.properties.headers = Some(headers);
         incrd_retries
     }
 
+    /// Return `true` if the job can be retried should the current attempt fail.
+    pub fn can_retry(&self, max_retries: u32) -> bool {
+        self.failed_attempts() + 1 < max_retries
+    }
+
+    /// Record the failure of the current attempt, returning `true` if the job should be retried.
     pub fn should_retry(&mut self, max_retries: u32) -> bool {
         self.incr_retries() < max_retries
     }
 }
+
+/// Interpret an AMQP value as a count, e.g. of attempts.
+fn count(value: &AMQPValue) -> u32 {
+    match *value {
+        AMQPValue::ShortShortUInt(n) => u32::from(n),
+        AMQPValue::ShortUInt(n) => u32::from(n),
+        AMQPValue::LongUInt(n) => n,
+        AMQPValue::ShortShortInt(n) if n >= 0 => n as u32,
+        AMQPValue::ShortInt(n) if n >= 0 => n as u32,
+        AMQPValue::LongInt(n) if n >= 0 => n as u32,
+        AMQPValue::LongLongInt(n) if n >= 0 => n as u32,
+        _ => 0,
+    }
+}
+
+/// Interpret an AMQP value as a number of seconds.
+///
+/// Other producers (e.g. Celery) send time limits as plain integers or floats rather than
+/// timestamps.
+fn seconds(value: &AMQPValue) -> Option<Duration> {
+    match *value {
+        AMQPValue::Timestamp(s) => Some(Duration::from_secs(s)),
+        AMQPValue::ShortShortUInt(s) => Some(Duration::from_secs(u64::from(s))),
+        AMQPValue::ShortUInt(s) => Some(Duration::from_secs(u64::from(s))),
+        AMQPValue::LongUInt(s) => Some(Duration::from_secs(u64::from(s))),
+        AMQPValue::ShortShortInt(s) if s >= 0 => Some(Duration::from_secs(s as u64)),
+        AMQPValue::ShortInt(s) if s >= 0 => Some(Duration::from_secs(s as u64)),
+        AMQPValue::LongInt(s) if s >= 0 => Some(Duration::from_secs(s as u64)),
+        AMQPValue::LongLongInt(s) if s >= 0 => Some(Duration::from_secs(s as u64)),
+        AMQPValue::Float(s) if s >= 0.0 => Some(Duration::from_millis((s * 1000.0) as u64)),
+        AMQPValue::Double(s) if s >= 0.0 => Some(Duration::from_millis((s * 1000.0) as u64)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(headers: FieldTable, redelivered: bool) -> Delivery {
+        let message = Message {
+            delivery_tag: 1,
+            exchange: "batch.tests".to_string(),
+            routing_key: "attempts".to_string(),
+            redelivered,
+            properties: Properties {
+                headers: Some(headers),
+                ..Default::default()
+            },
+            data: Vec::new(),
+        };
+        Delivery::new(message, "attempts".to_string())
+    }
+
+    fn death(reason: &str, count: i64) -> AMQPValue {
+        let mut death = FieldTable::new();
+        death.insert("reason".to_string(), AMQPValue::LongString(reason.to_string()));
+        death.insert("count".to_string(), AMQPValue::LongLongInt(count));
+        AMQPValue::FieldTable(death)
+    }
+
+    #[test]
+    fn attempts_are_counted_from_headers() {
+        assert_eq!(delivery(FieldTable::new(), false).attempt(), 1);
+        assert_eq!(delivery(FieldTable::new(), true).attempt(), 2);
+
+        let mut headers = FieldTable::new();
+        headers.insert("retries".to_string(), AMQPValue::LongUInt(2));
+        assert_eq!(delivery(headers, false).attempt(), 3);
+
+        let mut headers = FieldTable::new();
+        let deaths = vec![death("rejected", 3), death("expired", 3)];
+        headers.insert("x-death".to_string(), AMQPValue::FieldArray(deaths));
+        let mut delivery = delivery(headers, false);
+        assert_eq!(delivery.attempt(), 4);
+        assert!(delivery.should_retry(5));
+        assert_eq!(delivery.retries(), 4);
+        assert!(!delivery.should_retry(4));
+    }
+
+    #[test]
+    fn redeliveries_dont_count_towards_retries() {
+        let mut redelivered = delivery(FieldTable::new(), true);
+        assert_eq!(redelivered.attempt(), 2);
+        assert!(redelivered.can_retry(2));
+        assert!(redelivered.should_retry(2));
+        assert_eq!(redelivered.retries(), 1);
+
+        let mut headers = FieldTable::new();
+        headers.insert("retries".to_string(), AMQPValue::LongUInt(1));
+        let mut redelivered = delivery(headers, true);
+        assert!(!redelivered.should_retry(2));
+    }
+
+    #[test]
+    fn deadlines_are_read_from_headers() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        assert_eq!(delivery(FieldTable::new(), false).deadline(), None);
+
+        let deadline = UNIX_EPOCH + Duration::from_millis(1_500_000_000_250);
+        let mut headers = FieldTable::new();
+        headers.insert(
+            execution::DEADLINE_HEADER.to_string(),
+            execution::deadline_header(deadline),
+        );
+        assert_eq!(delivery(headers, false).deadline(), Some(deadline));
+    }
+}
@@ -1,14 +1,22 @@
 mod common;
+mod connection;
 mod consumer;
 mod delivery;
 mod publisher;
 mod stream;
 mod types;
 
+pub use self::common::declare_with_handle;
+pub(crate) use self::common::{default_connection_name, hostname};
+pub use self::connection::{Connection, ConnectionBuilder};
+pub(crate) use self::connection::redact_url;
 pub use self::consumer::{Consumer, ConsumerHandle};
 pub use self::delivery::Delivery;
+pub(crate) use self::delivery::PropertiesDef;
 pub use self::publisher::Publisher;
-pub use self::types::{exchange, queue, Exchange, ExchangeBuilder, Queue, QueueBuilder};
+#[cfg(feature = "raw-channel")]
+pub use self::stream::Stream;
+pub use self::types::{exchange, queue, Exchange, ExchangeBuilder, Queue, QueueBuilder, Reconcile};
 
 #[cfg(test)]
 mod tests {
@@ -38,12 +46,12 @@ mod tests {
         ];
         let expected = VecDeque::from(vec!["job-1", "job-2", "job-3", "job-4", "job-5"]);
 
-        let conn_url = "amqp://localhost/%2f";
+        let conn = Connection::default();
         let exchanges = vec![exchange(ex).build()];
         let queues = vec![queue("tests.default").bind(ex, rk).build()];
         let handle = Handle::current();
         let task =
-            Publisher::new_with_handle(conn_url, exchanges.clone(), queues.clone(), handle.clone())
+            Publisher::new_with_handle(&conn, exchanges.clone(), queues.clone(), handle.clone())
                 .and_then(move |publisher| {
                     info!("Publishing messages");
                     let tasks = jobs.into_iter().map(move |(job, priority)| {
@@ -68,7 +76,15 @@ mod tests {
                 })
                 .and_then(move |_| {
                     info!("Published all messages");
-                    Consumer::new_with_handle(conn_url, exchanges, queues, 1, handle)
+                    Consumer::new_with_handle(
+                        &conn,
+                        exchanges,
+                        queues,
+                        1,
+                        false,
+                        &default_connection_name(),
+                        handle,
+                    )
                 })
                 .and_then(move |consumer| {
                     info!("Starting recursive loop fn");
@@ -126,7 +142,7 @@ mod tests {
         ];
         let expected = VecDeque::from(vec!["job-2", "job-4", "job-1", "job-5", "job-3"]);
 
-        let conn_url = "amqp://localhost/%2f";
+        let conn = Connection::default();
         let exchanges = vec![exchange(ex).build()];
         let queues = vec![
             queue("tests.priorities")
@@ -136,7 +152,7 @@ mod tests {
         ];
         let handle = Handle::current();
         let task =
-            Publisher::new_with_handle(conn_url, exchanges.clone(), queues.clone(), handle.clone())
+            Publisher::new_with_handle(&conn, exchanges.clone(), queues.clone(), handle.clone())
                 .and_then(move |publisher| {
                     info!("Publishing messages");
                     let tasks = jobs.into_iter().map(move |(job, priority)| {
@@ -161,7 +177,15 @@ mod tests {
                 })
                 .and_then(move |_| {
                     info!("Published all messages");
-                    Consumer::new_with_handle(conn_url, exchanges, queues, 1, handle)
+                    Consumer::new_with_handle(
+                        &conn,
+                        exchanges,
+                        queues,
+                        1,
+                        false,
+                        &default_connection_name(),
+                        handle,
+                    )
                 })
                 .and_then(move |consumer| {
                     info!("Starting recursive loop fn");
@@ -1,24 +1,89 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::io;
 use std::result::Result as StdResult;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::{future, Future};
-use lapin::channel::{BasicProperties, BasicPublishOptions, Channel};
+use lapin::channel::{BasicProperties, BasicPublishOptions, Channel, QueueBindOptions,
+                     QueueDeclareOptions, QueuePurgeOptions};
 use lapin::client::Client;
+use lapin::types::{AMQPValue, FieldTable};
 use tokio_reactor::Handle;
 
 use error::{Error, ErrorKind};
+use job::Priority;
 use rabbitmq::common::{connect, declare_exchanges, declare_queues, HeartbeatHandle};
+use rabbitmq::connection::Connection;
+use rabbitmq::consumer::Consumer;
 use rabbitmq::stream::Stream;
-use rabbitmq::types::{Exchange, Queue};
+use rabbitmq::types::{queue, Exchange, Queue};
+
+/// The minimum delay between two attempts of a `Publisher` to connect to the broker.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A day, in milliseconds.
+const DAY: u32 = 24 * 3_600_000;
+
+/// The delays, in milliseconds, of the jobs sent with `Publisher::send_delayed`.
+///
+/// Delays are rounded up to one of these, or to whole days beyond the last one, so that jobs
+/// published with arbitrary delays share a bounded number of queues.
+const DELAY_BUCKETS: &[u32] = &[
+    1_000,
+    5_000,
+    10_000,
+    30_000,
+    60_000,
+    5 * 60_000,
+    10 * 60_000,
+    30 * 60_000,
+    3_600_000,
+    2 * 3_600_000,
+    6 * 3_600_000,
+    12 * 3_600_000,
+    DAY,
+];
 
 /// An AMQP based publisher for the Batch distributed job queue.
+///
+/// The publisher connects to the broker again, declaring its exchanges & queues, when it is used
+/// after its connection was lost. Operations fail with `ErrorKind::Connection` while it is
+/// disconnected.
 #[derive(Clone)]
 pub struct Publisher {
+    connection: Connection,
+    exchanges: Arc<Vec<Exchange>>,
+    queues: Arc<Vec<Queue>>,
+    handle: Handle,
+    state: Arc<Mutex<State>>,
+    max_priorities: Arc<HashMap<(String, String), u8>>,
+    unprioritized: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+/// The connection of a `Publisher` to the broker.
+#[derive(Clone)]
+struct Link {
+    client: Client<Stream>,
     channel: Channel<Stream>,
     heartbeat_handle: Arc<HeartbeatHandle>,
 }
 
+impl Link {
+    /// Return true if both links share the same connection.
+    fn same(&self, other: &Link) -> bool {
+        Arc::ptr_eq(&self.heartbeat_handle, &other.heartbeat_handle)
+    }
+}
+
+enum State {
+    Connected(Link),
+    Connecting,
+    /// The last attempt to connect failed at the given instant, if any.
+    Disconnected(Option<Instant>),
+}
+
 impl fmt::Debug for Publisher {
     fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
         write!(f, "Publisher {{ }}")
@@ -26,9 +91,39 @@ impl fmt::Debug for Publisher {
 }
 
 impl Publisher {
+    /// Return the channel used by this `Publisher`, connecting to the broker if needed.
+    #[cfg(feature = "raw-channel")]
+    pub fn channel(&self) -> Box<Future<Item = Channel<Stream>, Error = Error> + Send> {
+        Box::new(self.link().map(|link| link.channel))
+    }
+
+    /// Create a `Publisher` instance that connects to the broker when it is first used.
+    pub fn new<E, Q>(
+        connection: &Connection,
+        exchanges_iter: E,
+        queues_iter: Q,
+        handle: Handle,
+    ) -> Self
+    where
+        E: IntoIterator<Item = Exchange>,
+        Q: IntoIterator<Item = Queue>,
+    {
+        let exchanges = exchanges_iter.into_iter().collect::<Vec<_>>();
+        let queues = queues_iter.into_iter().collect::<Vec<_>>();
+        Publisher {
+            connection: connection.clone(),
+            max_priorities: Arc::new(max_priorities(&queues)),
+            unprioritized: Arc::new(Mutex::new(unprioritized(&queues))),
+            exchanges: Arc::new(exchanges),
+            queues: Arc::new(queues),
+            handle,
+            state: Arc::new(Mutex::new(State::Disconnected(None))),
+        }
+    }
+
     /// Create a `Publisher` instance from a RabbitMQ URI and an explicit tokio handle.
     pub fn new_with_handle<E, Q>(
-        connection_url: &str,
+        connection: &Connection,
         exchanges_iter: E,
         queues_iter: Q,
         handle: Handle,
@@ -37,62 +132,406 @@ impl Publisher {
         E: IntoIterator<Item = Exchange> + Send,
         Q: IntoIterator<Item = Queue> + Send,
     {
-        let exchanges = exchanges_iter.into_iter().collect::<Vec<_>>();
-        let queues = queues_iter.into_iter().collect::<Vec<_>>();
+        let publisher = Publisher::new(connection, exchanges_iter, queues_iter, handle);
+        Box::new(publisher.connect().map(move |_| publisher))
+    }
+
+    /// Connect to the broker & declare the exchanges & queues of this publisher, unless it is
+    /// connected already.
+    pub fn connect(&self) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(self.link().map(|_| ()))
+    }
 
-        let task = connect(connection_url, handle)
+    /// Return the connection of this publisher, establishing a new one if it was lost.
+    fn link(&self) -> Box<Future<Item = Link, Error = Error> + Send> {
+        {
+            let mut state = self.state.lock().unwrap();
+            match *state {
+                State::Connected(ref link) if !link.heartbeat_handle.is_lost() => {
+                    return Box::new(future::ok(link.clone()));
+                }
+                State::Connected(_) => warn!("The publisher lost its connection, reconnecting"),
+                State::Connecting => {
+                    return Box::new(future::err(not_connected("The publisher is connecting")));
+                }
+                State::Disconnected(Some(failed_at)) if failed_at.elapsed() < RECONNECT_DELAY => {
+                    return Box::new(future::err(not_connected("The broker is unreachable")));
+                }
+                State::Disconnected(_) => (),
+            }
+            *state = State::Connecting;
+        }
+        let exchanges = self.exchanges.to_vec();
+        let queues = self.queues.to_vec();
+        let state = Arc::clone(&self.state);
+        let task = connect(&self.connection, self.handle.clone())
             .and_then(|(client, heartbeat_handle)| {
                 trace!("Creating publisher's RabbitMQ channel");
                 client
                     .create_channel()
                     .map(move |channel| {
                         trace!("Created publisher's RabbitMQ channel");
-                        (channel, heartbeat_handle)
+                        (client, channel, heartbeat_handle)
                     })
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+                    .map_err(|e| ErrorKind::Connection(e).into())
             })
-            .and_then(move |(channel, heartbeat_handle)| {
+            .and_then(move |(client, channel, heartbeat_handle)| {
                 trace!("Declaring publisher's RabbitMQ exchanges");
-                let channel_ = channel.clone();
-                declare_exchanges(exchanges, channel_)
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
-                    .map(|_| (channel, heartbeat_handle))
+                declare_exchanges(exchanges, client.clone(), channel)
+                    .map(|channel| (client, channel, heartbeat_handle))
             })
-            .and_then(move |(channel, heartbeat_handle)| {
+            .and_then(move |(client, channel, heartbeat_handle)| {
                 trace!("Declaring publisher's RabbitMQ queues");
-                let channel_ = channel.clone();
-                declare_queues(queues, channel_)
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
-                    .map(|_| (channel, heartbeat_handle))
+                declare_queues(queues, client.clone(), channel)
+                    .map(|channel| (client, channel, heartbeat_handle))
             })
-            .map(move |(channel, heartbeat_handle)| Publisher {
-                channel,
-                heartbeat_handle: Arc::new(heartbeat_handle),
+            .then(move |result| {
+                let mut state = state.lock().unwrap();
+                match result {
+                    Ok((client, channel, heartbeat_handle)) => {
+                        let link = Link {
+                            client,
+                            channel,
+                            heartbeat_handle: Arc::new(heartbeat_handle),
+                        };
+                        *state = State::Connected(link.clone());
+                        Ok(link)
+                    }
+                    Err(e) => {
+                        *state = State::Disconnected(Some(Instant::now()));
+                        Err(e)
+                    }
+                }
             });
         Box::new(task)
     }
 
+    /// Forget the given connection after an operation failed on it, so that the next one
+    /// reconnects: the broker closes the channel on errors.
+    fn disconnect(&self, link: &Link) {
+        let mut state = self.state.lock().unwrap();
+        let current = match *state {
+            State::Connected(ref current) => current.same(link),
+            _ => false,
+        };
+        if current {
+            *state = State::Disconnected(None);
+        }
+    }
+
+    /// Declare the given exchanges on the publisher's channel.
+    pub fn declare_exchanges<E>(
+        &self,
+        exchanges_iter: E,
+    ) -> Box<Future<Item = (), Error = Error> + Send>
+    where
+        E: IntoIterator<Item = Exchange>,
+    {
+        let exchanges = exchanges_iter.into_iter().collect::<Vec<_>>();
+        let publisher = self.clone();
+        let task = self.link().and_then(move |link| {
+            declare_exchanges(exchanges, link.client.clone(), link.channel.clone()).then(
+                move |result| {
+                    if result.is_err() {
+                        publisher.disconnect(&link);
+                    }
+                    result.map(|_| ())
+                },
+            )
+        });
+        Box::new(task)
+    }
+
+    /// Declare the given queues on the publisher's channel.
+    pub fn declare_queues<Q>(&self, queues_iter: Q) -> Box<Future<Item = (), Error = Error> + Send>
+    where
+        Q: IntoIterator<Item = Queue>,
+    {
+        let queues = queues_iter.into_iter().collect::<Vec<_>>();
+        let publisher = self.clone();
+        let task = self.link().and_then(move |link| {
+            declare_queues(queues, link.client.clone(), link.channel.clone()).then(
+                move |result| {
+                    if result.is_err() {
+                        publisher.disconnect(&link);
+                    }
+                    result.map(|_| ())
+                },
+            )
+        });
+        Box::new(task)
+    }
+
+    /// Run the given operation on a new channel of the publisher's connection, closed once the
+    /// operation completes, so that the broker closing the channel on an error doesn't affect the
+    /// channel jobs are published on.
+    fn on_new_channel<F>(&self, operation: F) -> Box<Future<Item = (), Error = Error> + Send>
+    where
+        F: FnOnce(Client<Stream>, Channel<Stream>) -> Box<Future<Item = (), Error = Error> + Send>
+            + Send
+            + 'static,
+    {
+        let task = self.link().and_then(move |link| {
+            link.client
+                .create_channel()
+                .map_err(|e| ErrorKind::Connection(e).into())
+                .and_then(move |channel| {
+                    operation(link.client.clone(), channel.clone()).then(move |result| {
+                        // The broker already closed the channel if the operation failed.
+                        let close = match result {
+                            Ok(_) => future::Either::A(channel.close(200, "Bye").then(|_| Ok(()))),
+                            Err(_) => future::Either::B(future::ok(())),
+                        };
+                        close.then(move |_: StdResult<(), ()>| result)
+                    })
+                })
+        });
+        Box::new(task)
+    }
+
+    /// Remove all the jobs waiting in the given queue.
+    ///
+    /// Returns a `Future` that completes once the queue is purged. Jobs delivered to consumers
+    /// but not acknowledged yet aren't removed. The queue is purged on its own channel, so that
+    /// purging a queue that doesn't exist doesn't break the publisher.
+    pub fn purge(&self, queue: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Purging queue {}", queue);
+        let queue = queue.to_string();
+        self.on_new_channel(move |_, channel| {
+            let task = channel
+                .queue_purge(&queue, QueuePurgeOptions::default())
+                .map_err(|e| ErrorKind::Rabbitmq(e).into());
+            Box::new(task)
+        })
+    }
+
+    /// Create a `Consumer` on its own channel of this publisher's connection, connecting to the
+    /// broker if needed.
+    pub fn consumer(
+        &self,
+        exchanges: Vec<Exchange>,
+        queues: Vec<Queue>,
+        prefetch_count: u16,
+        name: &str,
+    ) -> Box<Future<Item = Consumer, Error = Error> + Send> {
+        let name = name.to_string();
+        let task = self.link().and_then(move |link| {
+            Consumer::on_connection(
+                link.client,
+                link.heartbeat_handle,
+                exchanges,
+                queues,
+                prefetch_count,
+                false,
+                &name,
+            )
+        });
+        Box::new(task)
+    }
+
+    /// Declare the given exchange and publish a message to it, on their own channel.
+    ///
+    /// This is used for the messages broadcast to workers, e.g. cancellations, so that a conflict
+    /// with an existing exchange doesn't break the publisher.
+    pub fn broadcast(
+        &self,
+        exchange: Exchange,
+        data: &[u8],
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let data = data.to_vec();
+        self.on_new_channel(move |client, channel| {
+            let name = exchange.name().to_string();
+            let task = declare_exchanges(vec![exchange], client, channel).and_then(
+                move |channel| {
+                    channel
+                        .basic_publish(
+                            &name,
+                            "",
+                            &data,
+                            BasicPublishOptions::default(),
+                            BasicProperties::default(),
+                        )
+                        .map(|_| ())
+                        .map_err(|e| ErrorKind::Rabbitmq(e).into())
+                },
+            );
+            Box::new(task)
+        })
+    }
+
     /// Send a job to the broker.
     ///
     /// Returns a `Future` that completes once the job is sent to the broker.
     pub fn send(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        serialized: &[u8],
+        options: &BasicPublishOptions,
+        mut properties: BasicProperties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let key = (exchange.to_string(), routing_key.to_string());
+        if let (Some(priority), Some(&max)) = (properties.priority, self.max_priorities.get(&key)) {
+            if priority > max {
+                trace!("Capping priority {} to the queue's maximum: {}", priority, max);
+                properties.priority = Some(max);
+            }
+        }
+        if properties.priority.map_or(false, |p| p != Priority::Normal.to_u8()) {
+            // Only warn once per exchange & routing key pair.
+            if self.unprioritized.lock().unwrap().remove(&key) {
+                warn!(
+                    "Job published with a priority to exchange {:?} on routing key {:?}, which is bound to a queue without priorities: the priority will be ignored",
+                    exchange, routing_key
+                );
+            }
+        }
+        let (exchange, routing_key) = key;
+        let serialized = serialized.to_vec();
+        let options = options.clone();
+        let publisher = self.clone();
+        let task = self.link().and_then(move |link| {
+            link.channel
+                .basic_publish(&exchange, &routing_key, &serialized, options, properties)
+                .map(|_| ())
+                .map_err(move |e| {
+                    publisher.disconnect(&link);
+                    if link.heartbeat_handle.is_lost() || is_disconnection(&e) {
+                        ErrorKind::Connection(e).into()
+                    } else {
+                        ErrorKind::Rabbitmq(e).into()
+                    }
+                })
+        });
+        Box::new(task)
+    }
+
+    /// Send a job to the broker, to be routed once the given delay has elapsed.
+    ///
+    /// The delay is rounded up to the next of a fixed set of delays, from a second to a day, or to
+    /// whole days beyond a day. The job is published to a queue holding all the jobs with the same
+    /// destination & rounded delay, from which `RabbitMQ` dead-letters them to their destination
+    /// when they expire. This queue is deleted when it isn't used anymore.
+    pub fn send_delayed(
         &self,
         exchange: &str,
         routing_key: &str,
         serialized: &[u8],
         options: &BasicPublishOptions,
         properties: BasicProperties,
+        delay: Duration,
     ) -> Box<Future<Item = (), Error = Error> + Send> {
-        let task = self.channel
-            .basic_publish(
-                exchange,
-                routing_key,
-                serialized,
-                options.clone(),
-                properties,
-            )
-            .and_then(move |_| future::ok(()))
-            .map_err(|e| ErrorKind::Rabbitmq(e).into());
+        let millis = match delay_bucket(delay) {
+            Some(millis) => millis,
+            None => return self.send(exchange, routing_key, serialized, options, properties),
+        };
+        let name = format!("batch.delayed.{}.{}.{}", exchange, routing_key, millis);
+        let mut builder = queue(&name).durable(true);
+        {
+            let arguments = builder.arguments_mut();
+            arguments.insert("x-message-ttl".to_string(), AMQPValue::LongUInt(millis));
+            arguments.insert(
+                "x-expires".to_string(),
+                AMQPValue::LongUInt(millis.saturating_add(60_000)),
+            );
+            arguments.insert(
+                "x-dead-letter-exchange".to_string(),
+                AMQPValue::LongString(exchange.to_string()),
+            );
+            arguments.insert(
+                "x-dead-letter-routing-key".to_string(),
+                AMQPValue::LongString(routing_key.to_string()),
+            );
+        }
+        let publisher = self.clone();
+        let serialized = serialized.to_vec();
+        let options = options.clone();
+        let task = self.declare_queues(vec![builder.build()])
+            .and_then(move |_| publisher.send("", &name, &serialized, &options, properties));
         Box::new(task)
     }
 }
+
+/// Return an `ErrorKind::Connection` error with the given message.
+fn not_connected(message: &str) -> Error {
+    ErrorKind::Connection(io::Error::new(io::ErrorKind::NotConnected, message)).into()
+}
+
+/// Return true if the error is from the connection to the broker being closed or reset.
+fn is_disconnection(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
+}
+
+/// Return the exchange & routing key pairs bound to queues that don't support priorities.
+fn unprioritized(queues: &[Queue]) -> HashSet<(String, String)> {
+    queues
+        .iter()
+        .filter(|queue| queue.max_priority().is_none())
+        .flat_map(|queue| queue.bindings())
+        .map(|binding| (binding.exchange().to_string(), binding.routing_key().to_string()))
+        .collect()
+}
+
+/// Map each exchange & routing key pair to the highest maximum priority of the queues bound to it.
+fn max_priorities(queues: &[Queue]) -> HashMap<(String, String), u8> {
+    let mut max_priorities = HashMap::new();
+    for queue in queues {
+        let max_priority = match queue.max_priority() {
+            Some(max_priority) => max_priority,
+            None => continue,
+        };
+        for binding in queue.bindings() {
+            let key = (binding.exchange().to_string(), binding.routing_key().to_string());
+            let entry = max_priorities.entry(key).or_insert(max_priority);
+            if *entry < max_priority {
+                *entry = max_priority;
+            }
+        }
+    }
+    max_priorities
+}
+
+/// Return the delay, in milliseconds, a job sent with the given delay waits for, or `None` if it
+/// should be sent right away.
+fn delay_bucket(delay: Duration) -> Option<u32> {
+    let millis = delay
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(delay.subsec_nanos() / 1_000_000));
+    if millis == 0 {
+        return None;
+    }
+    let bucket = match DELAY_BUCKETS.iter().find(|&&bucket| u64::from(bucket) >= millis) {
+        Some(&bucket) => bucket,
+        None => {
+            let day = u64::from(DAY);
+            let days = millis / day + u64::from(millis % day != 0);
+            days.min(u64::from(u32::max_value() / DAY)) as u32 * DAY
+        }
+    };
+    Some(bucket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_are_rounded_up_to_buckets() {
+        assert_eq!(delay_bucket(Duration::from_millis(0)), None);
+        assert_eq!(delay_bucket(Duration::from_millis(1)), Some(1_000));
+        assert_eq!(delay_bucket(Duration::from_secs(1)), Some(1_000));
+        assert_eq!(delay_bucket(Duration::from_secs(61)), Some(300_000));
+        assert_eq!(delay_bucket(Duration::from_secs(86_400)), Some(DAY));
+        assert_eq!(delay_bucket(Duration::from_secs(86_401)), Some(2 * DAY));
+        assert_eq!(delay_bucket(Duration::from_secs(u64::max_value())), Some(49 * DAY));
+    }
+}
@@ -1,14 +1,28 @@
+use std::fmt;
 use std::io::{self, Read, Write};
+use std::result::Result as StdResult;
 
 use bytes::{Buf, BufMut};
 use futures::Poll;
 use tokio_io::{AsyncRead, AsyncWrite};
 
+/// The connection to the broker, over plain TCP or TLS.
 pub enum Stream {
+    /// A plain TCP connection, used with `amqp://` URLs.
     Raw(::tokio_tcp::TcpStream),
+    /// A TLS connection, used with `amqps://` URLs.
     Tls(::tokio_tls::TlsStream<::tokio_tcp::TcpStream>),
 }
 
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        match *self {
+            Stream::Raw(_) => write!(f, "Stream::Raw {{ }}"),
+            Stream::Tls(_) => write!(f, "Stream::Tls {{ }}"),
+        }
+    }
+}
+
 impl Read for Stream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
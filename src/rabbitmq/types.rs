@@ -1,9 +1,19 @@
 use std::cmp;
 use std::collections::BTreeSet;
+use std::time::Duration;
 
+use futures::{Future, Stream};
 use lapin::channel::{ExchangeDeclareOptions, QueueDeclareOptions};
 use lapin::types::{AMQPValue, FieldTable};
 
+use client::Client;
+use error::Error;
+use incoming::{self, Incoming};
+use job::Job;
+use naming::interpolate;
+use peek::{self, PeekedJob};
+use query::Query;
+
 /// A binding from a queue to an exchange, or from an exchange to an exchange.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Binding {
@@ -21,14 +31,39 @@ impl Binding {
     }
 }
 
+/// What to do when a queue or an exchange already exists with different options or arguments.
+///
+/// The broker refuses to declare it again with `PRECONDITION_FAILED`, which is reported as an
+/// [`ErrorKind::DeclareConflict`](enum.ErrorKind.html#variant.DeclareConflict) by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reconcile {
+    /// Fail with an `ErrorKind::DeclareConflict`.
+    Fail,
+    /// Delete the existing queue or exchange and declare it again.
+    ///
+    /// The jobs waiting in a deleted queue are lost, and the bindings other applications added
+    /// to it are removed: only use this when the queue can be drained, e.g. during deployments.
+    DeleteAndRecreate,
+    /// Keep using the existing queue or exchange as is, logging a warning.
+    Passive,
+}
+
+impl Default for Reconcile {
+    fn default() -> Self {
+        Reconcile::Fail
+    }
+}
+
 /// A `RabbitMQ` exchange.
 #[derive(Clone, Debug)]
 pub struct Exchange {
     name: String,
     kind: String,
     bindings: BTreeSet<Binding>,
+    sources: BTreeSet<Binding>,
     options: ExchangeDeclareOptions,
     arguments: FieldTable,
+    reconcile: Reconcile,
 }
 
 impl Default for Exchange {
@@ -37,8 +72,10 @@ impl Default for Exchange {
             name: "".into(),
             kind: "direct".into(),
             bindings: BTreeSet::new(),
+            sources: BTreeSet::new(),
             options: ExchangeDeclareOptions::default(),
             arguments: FieldTable::new(),
+            reconcile: Reconcile::default(),
         }
     }
 }
@@ -92,6 +129,19 @@ impl Exchange {
         &self.bindings
     }
 
+    /// Return the bindings from other exchanges to this `Exchange`.
+    pub(crate) fn sources(&self) -> &BTreeSet<Binding> {
+        &self.sources
+    }
+
+    /// Return the exchange receiving the messages that can't be routed, if any.
+    pub fn alternate_exchange(&self) -> Option<&str> {
+        match self.arguments.get("alternate-exchange") {
+            Some(&AMQPValue::LongString(ref exchange)) => Some(exchange),
+            _ => None,
+        }
+    }
+
     /// Return the options of this `Exchange`.
     pub fn options(&self) -> &ExchangeDeclareOptions {
         &self.options
@@ -101,6 +151,11 @@ impl Exchange {
     pub fn arguments(&self) -> &FieldTable {
         &self.arguments
     }
+
+    /// Return what to do if this `Exchange` already exists with different options or arguments.
+    pub fn reconcile(&self) -> Reconcile {
+        self.reconcile
+    }
 }
 
 /// A builder for `RabbitMQ` `Exchange`.
@@ -110,8 +165,10 @@ impl Exchange {
 pub struct ExchangeBuilder {
     name: String,
     bindings: BTreeSet<Binding>,
+    sources: BTreeSet<Binding>,
     options: ExchangeDeclareOptions,
     arguments: FieldTable,
+    reconcile: Reconcile,
 }
 
 impl ExchangeBuilder {
@@ -126,10 +183,12 @@ impl ExchangeBuilder {
     /// ```
     fn new(name: &str) -> ExchangeBuilder {
         ExchangeBuilder {
-            name: name.into(),
+            name: interpolate(name),
             bindings: BTreeSet::new(),
+            sources: BTreeSet::new(),
             options: ExchangeDeclareOptions::default(),
             arguments: FieldTable::new(),
+            reconcile: Reconcile::default(),
         }
     }
 
@@ -148,12 +207,56 @@ impl ExchangeBuilder {
     /// ```
     pub fn bind(mut self, exchange: &str, routing_key: &str) -> Self {
         self.bindings.insert(Binding {
-            exchange: exchange.into(),
-            routing_key: routing_key.into(),
+            exchange: interpolate(exchange),
+            routing_key: interpolate(routing_key),
         });
         self
     }
 
+    /// Binds another exchange to this exchange via a routing key.
+    ///
+    /// All of the messages posted to the given exchange associated to the given routing key
+    /// are automatically sent to this exchange, which allows several exchanges to fan in to a
+    /// single one. The given exchange must already exist, or be declared before this one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let builder = Exchange::builder("batch.example")
+    ///     .bind_exchange("batch.upstream", "hello-world");
+    /// ```
+    pub fn bind_exchange(mut self, exchange: &str, routing_key: &str) -> Self {
+        self.sources.insert(Binding {
+            exchange: interpolate(exchange),
+            routing_key: interpolate(routing_key),
+        });
+        self
+    }
+
+    /// Set the exchange receiving the messages that can't be routed to any queue. Chainable.
+    ///
+    /// Without an alternate exchange, messages published with a routing key that matches no
+    /// binding are silently dropped by `RabbitMQ`. Binding a catch-all queue to the alternate
+    /// exchange allows them to be inspected instead.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let builder = Exchange::builder("batch.example")
+    ///     .alternate_exchange("batch.unroutable");
+    /// ```
+    pub fn alternate_exchange(mut self, exchange: &str) -> Self {
+        self.arguments.insert(
+            "alternate-exchange".to_string(),
+            AMQPValue::LongString(interpolate(exchange)),
+        );
+        self
+    }
+
     /// Return a reference the declare options for this exchange.
     ///
     /// # Example
@@ -230,6 +333,27 @@ impl ExchangeBuilder {
         &mut self.arguments
     }
 
+    /// Set an argument of the exchange, e.g. one used by a plugin. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// extern crate batch;
+    /// extern crate lapin_futures;
+    ///
+    /// use lapin_futures::types::AMQPValue;
+    /// use batch::Exchange;
+    ///
+    /// # fn main() {
+    /// let builder = Exchange::builder("batch.example")
+    ///     .argument("x-custom-argument", AMQPValue::Boolean(true));
+    /// # }
+    /// ```
+    pub fn argument(mut self, name: &str, value: AMQPValue) -> Self {
+        self.arguments.insert(name.to_string(), value);
+        self
+    }
+
     /// Set the durable option. Chainable.
     ///
     /// # Example
@@ -320,14 +444,32 @@ impl ExchangeBuilder {
         self
     }
 
+    /// Set what to do if the exchange already exists with different options or arguments.
+    /// Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::{Exchange, Reconcile};
+    ///
+    /// let builder = Exchange::builder("batch.example")
+    ///     .reconcile(Reconcile::DeleteAndRecreate);
+    /// ```
+    pub fn reconcile(mut self, reconcile: Reconcile) -> Self {
+        self.reconcile = reconcile;
+        self
+    }
+
     /// Build a new `Exchange` instance from this builder data.
     pub(crate) fn build(self) -> Exchange {
         Exchange {
             name: self.name,
             kind: "direct".into(),
             bindings: self.bindings,
+            sources: self.sources,
             options: self.options,
             arguments: self.arguments,
+            reconcile: self.reconcile,
         }
     }
 }
@@ -344,6 +486,9 @@ pub struct Queue {
     bindings: BTreeSet<Binding>,
     options: QueueDeclareOptions,
     arguments: FieldTable,
+    default_timeout: Option<Duration>,
+    default_retries: Option<u32>,
+    reconcile: Reconcile,
 }
 
 impl cmp::PartialEq for Queue {
@@ -385,6 +530,47 @@ impl Queue {
         &self.name
     }
 
+    /// Create a `Query` publishing the given job directly to this `Queue`.
+    ///
+    /// See [`QueueBuilder::with`](struct.QueueBuilder.html#method.with).
+    pub fn with<T>(&self, job: T) -> Query<T>
+    where
+        T: Job + Send + 'static,
+    {
+        Query::new(job).exchange("").routing_key(&self.name)
+    }
+
+    /// Consume the jobs of type `T` published to this `Queue`.
+    ///
+    /// See [`QueueBuilder::consume`](struct.QueueBuilder.html#method.consume).
+    pub fn consume<T>(
+        &self,
+        client: &Client,
+    ) -> Box<Stream<Item = Incoming<T>, Error = Error> + Send>
+    where
+        T: Job + Send + 'static,
+    {
+        incoming::consume(client, self.clone())
+    }
+
+    /// Remove all the jobs waiting in this `Queue`.
+    ///
+    /// See [`QueueBuilder::purge`](struct.QueueBuilder.html#method.purge).
+    pub fn purge(&self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
+        client.purge(&self.name)
+    }
+
+    /// Return the first jobs waiting in this `Queue`, without consuming them.
+    ///
+    /// See [`QueueBuilder::peek`](struct.QueueBuilder.html#method.peek).
+    pub fn peek(
+        &self,
+        client: &Client,
+        count: u16,
+    ) -> Box<Future<Item = Vec<PeekedJob>, Error = Error> + Send> {
+        peek::peek(client, &self.name, count)
+    }
+
     /// Return the bindings associated to this `Queue`.
     pub(crate) fn bindings(&self) -> &BTreeSet<Binding> {
         &self.bindings
@@ -399,6 +585,45 @@ impl Queue {
     pub fn arguments(&self) -> &FieldTable {
         &self.arguments
     }
+
+    /// Return the timeout applied to jobs consumed from this `Queue` that don't set their own.
+    pub fn default_timeout(&self) -> Option<Duration> {
+        self.default_timeout
+    }
+
+    /// Return the number of retries applied to jobs consumed from this `Queue` that don't set
+    /// their own.
+    pub fn default_retries(&self) -> Option<u32> {
+        self.default_retries
+    }
+
+    /// Return what to do if this `Queue` already exists with different options or arguments.
+    pub fn reconcile(&self) -> Reconcile {
+        self.reconcile
+    }
+
+    /// Return the exchange & routing key the jobs rejected from this `Queue` are sent to.
+    ///
+    /// The routing key is `None` when jobs keep their original routing key.
+    pub(crate) fn dead_letter(&self) -> Option<(&str, Option<&str>)> {
+        let exchange = match self.arguments.get("x-dead-letter-exchange") {
+            Some(&AMQPValue::LongString(ref exchange)) => exchange.as_ref(),
+            _ => return None,
+        };
+        let routing_key = match self.arguments.get("x-dead-letter-routing-key") {
+            Some(&AMQPValue::LongString(ref routing_key)) => Some(routing_key.as_ref()),
+            _ => None,
+        };
+        Some((exchange, routing_key))
+    }
+
+    /// Return the maximum priority supported by this `Queue`, if priorities are enabled.
+    pub fn max_priority(&self) -> Option<u8> {
+        match self.arguments.get("x-max-priority") {
+            Some(&AMQPValue::ShortShortUInt(max)) => Some(max),
+            _ => None,
+        }
+    }
 }
 
 /// A builder for `RabbitMQ` `Queue`.
@@ -410,15 +635,21 @@ pub struct QueueBuilder {
     bindings: BTreeSet<Binding>,
     options: QueueDeclareOptions,
     arguments: FieldTable,
+    default_timeout: Option<Duration>,
+    default_retries: Option<u32>,
+    reconcile: Reconcile,
 }
 
 impl QueueBuilder {
     fn new(name: &str) -> QueueBuilder {
         QueueBuilder {
-            name: name.into(),
+            name: interpolate(name),
             bindings: BTreeSet::new(),
             options: QueueDeclareOptions::default(),
             arguments: FieldTable::new(),
+            default_timeout: None,
+            default_retries: None,
+            reconcile: Reconcile::default(),
         }
     }
 
@@ -498,6 +729,27 @@ impl QueueBuilder {
         &mut self.arguments
     }
 
+    /// Set an argument of the queue, e.g. `x-queue-mode` or one used by a plugin. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// extern crate batch;
+    /// extern crate lapin_futures;
+    ///
+    /// use lapin_futures::types::AMQPValue;
+    /// use batch::Queue;
+    ///
+    /// # fn main() {
+    /// let builder = Queue::builder("batch.example")
+    ///     .argument("x-queue-mode", AMQPValue::LongString("lazy".to_string()));
+    /// # }
+    /// ```
+    pub fn argument(mut self, name: &str, value: AMQPValue) -> Self {
+        self.arguments.insert(name.to_string(), value);
+        self
+    }
+
     /// Bind this queue to an exchange via a routing key.
     ///
     /// # Example
@@ -512,8 +764,8 @@ impl QueueBuilder {
     /// ```
     pub fn bind(mut self, exchange: &str, routing_key: &str) -> Self {
         self.bindings.insert(Binding {
-            exchange: exchange.into(),
-            routing_key: routing_key.into(),
+            exchange: interpolate(exchange),
+            routing_key: interpolate(routing_key),
         });
         self
     }
@@ -608,8 +860,27 @@ impl QueueBuilder {
         self
     }
 
+    /// Set what to do if the queue already exists with different options or arguments.
+    /// Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::{Queue, Reconcile};
+    ///
+    /// let builder = Queue::builder("video-transcoding")
+    ///     .reconcile(Reconcile::Passive);
+    /// ```
+    pub fn reconcile(mut self, reconcile: Reconcile) -> Self {
+        self.reconcile = reconcile;
+        self
+    }
+
     /// Enable priorities on this queue.
     ///
+    /// The maximum priority is set to 4, which covers the named priorities, from
+    /// `Priority::Trivial` to `Priority::Critical`.
+    ///
     /// # Example
     ///
     /// ```
@@ -618,9 +889,280 @@ impl QueueBuilder {
     /// Queue::builder("video-transcoding")
     ///     .enable_priorities();
     /// ```
-    pub fn enable_priorities(mut self) -> Self {
-        self.arguments
-            .insert("x-max-priority".to_string(), AMQPValue::ShortShortUInt(4));
+    pub fn enable_priorities(self) -> Self {
+        self.max_priority(4)
+    }
+
+    /// Only deliver the jobs of this queue to one of its consumers at a time.
+    ///
+    /// The other consumers stay on standby, and the broker delivers the jobs to one of them when
+    /// the active consumer stops. Combined with a parallelism of 1, this processes jobs strictly
+    /// in order while keeping workers ready to take over. Requires `RabbitMQ` 3.8 or later.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("ledger-entries")
+    ///     .single_active_consumer();
+    /// ```
+    pub fn single_active_consumer(mut self) -> Self {
+        self.arguments.insert(
+            "x-single-active-consumer".to_string(),
+            AMQPValue::Boolean(true),
+        );
+        self
+    }
+
+    /// Enable priorities on this queue, up to the given maximum priority.
+    ///
+    /// Jobs published with a higher priority are treated as if they had the maximum priority.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .max_priority(10);
+    /// ```
+    pub fn max_priority(mut self, max_priority: u8) -> Self {
+        self.arguments.insert(
+            "x-max-priority".to_string(),
+            AMQPValue::ShortShortUInt(max_priority),
+        );
+        self
+    }
+
+    /// Send the jobs rejected permanently from this queue to the given exchange. Chainable.
+    ///
+    /// Jobs are dead-lettered with the given routing key, or with their original routing key if
+    /// it is empty. Workers publish the dead-lettered jobs themselves, with headers describing
+    /// the failure: `x-batch-error`, `x-batch-backtrace` (when captured), `x-batch-output` (see
+    /// `WorkerBuilder::attach_output`), `x-batch-exit-status`, `x-batch-attempts`,
+    /// `x-batch-hostname`, `x-batch-started-at` and `x-batch-failed-at`, along with the exchange
+    /// & routing key the job was published with, in `x-batch-exchange` & `x-batch-routing-key`.
+    /// See [`Replayer`](struct.Replayer.html) to publish them again.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let builder = Queue::builder("video-transcoding")
+    ///     .dead_letter("batch.failures", "video-transcoding");
+    /// ```
+    pub fn dead_letter(mut self, exchange: &str, routing_key: &str) -> Self {
+        self.arguments.insert(
+            "x-dead-letter-exchange".to_string(),
+            AMQPValue::LongString(interpolate(exchange)),
+        );
+        if !routing_key.is_empty() {
+            self.arguments.insert(
+                "x-dead-letter-routing-key".to_string(),
+                AMQPValue::LongString(interpolate(routing_key)),
+            );
+        }
+        self
+    }
+
+    /// Create a `Query` publishing the given job directly to this queue.
+    ///
+    /// The job is published to the default exchange, using the name of the queue as routing key,
+    /// which doesn't require declaring any exchange or binding.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::queue;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "thumbnails"]
+    /// struct GenerateThumbnail {
+    ///     path: String,
+    /// }
+    ///
+    /// # fn main() {
+    /// let thumbnails = queue("thumbnails");
+    /// let path = "/tmp/ferris.png".to_string();
+    /// let query = thumbnails.with(GenerateThumbnail { path });
+    /// # }
+    /// ```
+    pub fn with<T>(&self, job: T) -> Query<T>
+    where
+        T: Job + Send + 'static,
+    {
+        Query::new(job).exchange("").routing_key(&self.name)
+    }
+
+    /// Consume the jobs of type `T` published to this queue, declaring it first.
+    ///
+    /// This is a lightweight alternative to a `Worker` for processes handling a single kind of
+    /// job: jobs are executed by the caller, in the process of the caller, and must be
+    /// acknowledged or rejected explicitly. Jobs of another type, or that can't be decoded, are
+    /// rejected and skipped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// extern crate futures;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    /// extern crate tokio;
+    ///
+    /// use batch::{queue, Client};
+    /// use futures::{Future, Stream};
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job_routing_key = "thumbnails"]
+    /// struct GenerateThumbnail {
+    ///     path: String,
+    /// }
+    ///
+    /// # fn main() {
+    /// let task = Client::builder()
+    ///     .build()
+    ///     .and_then(|client| {
+    ///         queue("thumbnails")
+    ///             .consume::<GenerateThumbnail>(&client)
+    ///             .for_each(|incoming| {
+    ///                 println!("Generating thumbnail of {}", incoming.job().path);
+    ///                 incoming.ack()
+    ///             })
+    ///     })
+    ///     .map_err(|e| eprintln!("An error occured: {}", e));
+    /// # if false {
+    /// tokio::run(task);
+    /// # }
+    /// # }
+    /// ```
+    pub fn consume<T>(
+        self,
+        client: &Client,
+    ) -> Box<Stream<Item = Incoming<T>, Error = Error> + Send>
+    where
+        T: Job + Send + 'static,
+    {
+        incoming::consume(client, self.build())
+    }
+
+    /// Remove all the jobs waiting in this queue, e.g. to reset its state between tests.
+    ///
+    /// Jobs delivered to workers but not acknowledged yet aren't removed, and are put back in
+    /// the queue if the worker doesn't acknowledge them. The queue must have been declared.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// extern crate batch;
+    /// extern crate futures;
+    /// extern crate tokio;
+    ///
+    /// use batch::{queue, Client};
+    /// use futures::Future;
+    ///
+    /// # fn main() {
+    /// let task = Client::builder()
+    ///     .build()
+    ///     .and_then(|client| queue("thumbnails").purge(&client))
+    ///     .map_err(|e| eprintln!("Couldn't purge queue: {}", e));
+    /// # if false {
+    /// tokio::run(task);
+    /// # }
+    /// # }
+    /// ```
+    pub fn purge(&self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
+        client.purge(&self.name)
+    }
+
+    /// Return the first `count` jobs waiting in this queue, without consuming them, e.g. to see
+    /// which jobs are stuck at its head.
+    ///
+    /// The jobs are delivered to the `Client` and put back in the queue right away, at their
+    /// position, so they count as redelivered: their next attempt counts one more. Fewer jobs
+    /// are returned if the queue holds fewer, or if other consumers receive them first. The
+    /// queue must have been declared.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// extern crate batch;
+    /// extern crate futures;
+    /// extern crate tokio;
+    ///
+    /// use batch::{queue, Client};
+    /// use futures::Future;
+    ///
+    /// # fn main() {
+    /// let task = Client::builder()
+    ///     .build()
+    ///     .and_then(|client| queue("thumbnails").peek(&client, 10))
+    ///     .map(|jobs| {
+    ///         for job in jobs {
+    ///             let execution = job.execution();
+    ///             println!("[{}] {}: {}", execution.id(), execution.name(), job.preview(80));
+    ///         }
+    ///     })
+    ///     .map_err(|e| eprintln!("Couldn't peek queue: {}", e));
+    /// # if false {
+    /// tokio::run(task);
+    /// # }
+    /// # }
+    /// ```
+    pub fn peek(
+        &self,
+        client: &Client,
+        count: u16,
+    ) -> Box<Future<Item = Vec<PeekedJob>, Error = Error> + Send> {
+        peek::peek(client, &self.name, count)
+    }
+
+    /// Set the timeout of the jobs consumed from this queue. Chainable.
+    ///
+    /// The timeout only applies to jobs that don't set their own, see the `job_timeout`
+    /// attribute of the `Job` derive.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    /// use std::time::Duration;
+    ///
+    /// let builder = Queue::builder("video-transcoding")
+    ///     .default_timeout(Duration::from_secs(5 * 60));
+    /// ```
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.default_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the number of retries of the jobs consumed from this queue. Chainable.
+    ///
+    /// The number of retries only applies to jobs that don't set their own, see the
+    /// `job_retries` attribute of the `Job` derive.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let builder = Queue::builder("video-transcoding")
+    ///     .default_retries(5);
+    /// ```
+    pub fn default_retries(mut self, retries: u32) -> Self {
+        self.default_retries = Some(retries);
         self
     }
 
@@ -631,6 +1173,9 @@ impl QueueBuilder {
             bindings: self.bindings,
             options: self.options,
             arguments: self.arguments,
+            default_timeout: self.default_timeout,
+            default_retries: self.default_retries,
+            reconcile: self.reconcile,
         }
     }
 }
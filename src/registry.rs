@@ -0,0 +1,124 @@
+//! Jobs registered once for all the workers of a program, see `register_job!`.
+
+use std::any::Any;
+use std::fmt;
+
+use inventory;
+
+use job::{Job, Perform};
+use worker::WorkerBuilder;
+
+/// A job registered with [`register_job!`](macro.register_job.html), handled by the workers
+/// built with [`Worker::auto`](struct.Worker.html#method.auto).
+///
+/// This is only available with the `registry` feature enabled.
+pub struct Registration {
+    name: fn() -> &'static str,
+    register: fn(&mut Any) -> bool,
+}
+
+inventory::collect!(Registration);
+
+impl Registration {
+    #[doc(hidden)]
+    pub const fn job<T>() -> Self
+    where
+        T: Job + Perform + 'static,
+        T::Context: 'static,
+    {
+        Registration {
+            name: T::name,
+            register: register::<T>,
+        }
+    }
+
+    /// Return the name of the registered job.
+    pub fn name(&self) -> &'static str {
+        (self.name)()
+    }
+
+    /// Register the job on the given builder, returning `false` if it doesn't have the job's
+    /// context type.
+    pub(crate) fn register<Ctx: 'static>(&self, builder: &mut WorkerBuilder<Ctx>) -> bool {
+        (self.register)(builder)
+    }
+}
+
+impl fmt::Debug for Registration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registration")
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+fn register<T>(builder: &mut Any) -> bool
+where
+    T: Job + Perform + 'static,
+    T::Context: 'static,
+{
+    match builder.downcast_mut::<WorkerBuilder<T::Context>>() {
+        Some(builder) => {
+            builder.register_job::<T>();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Return the jobs registered with `register_job!` in the program.
+pub(crate) fn registrations() -> inventory::iter<Registration> {
+    inventory::iter::<Registration>
+}
+
+/// Register a job for all the workers built with [`Worker::auto`] in the program.
+///
+/// The job is registered on the workers whose context has the type of the job's context, as if
+/// with [`WorkerBuilder::job`], wherever this is invoked in the program or its dependencies.
+///
+/// This is only available with the `registry` feature enabled.
+///
+/// [`Worker::auto`]: struct.Worker.html#method.auto
+/// [`WorkerBuilder::job`]: struct.WorkerBuilder.html#method.job
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::{Job, Perform, Worker};
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_routing_key = "hello-world"]
+/// struct SayHello {
+///     to: String,
+/// }
+///
+/// impl Perform for SayHello {
+///     type Context = ();
+///
+///     fn perform(&self, _ctx: Self::Context) {
+///         println!("Hello {}", self.to);
+///     }
+/// }
+///
+/// register_job!(SayHello);
+///
+/// # fn main() {
+/// let builder = Worker::auto(());
+/// assert!(builder.manifest().contains(SayHello::name()));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! register_job {
+    ($job:ty) => {
+        $crate::inventory::submit! {
+            $crate::Registration::job::<$job>()
+        }
+    };
+}
@@ -0,0 +1,129 @@
+//! Routing of jobs to different clients.
+
+use futures::Future;
+
+use client::Client;
+use error::Error;
+use handle::JobHandle;
+use job::Job;
+use query::Query;
+
+/// Sends jobs through different `Client`s depending on their name.
+///
+/// Each route maps a job name, or a pattern ending with `*` matching every job name starting
+/// with the given prefix, to a client. Jobs are sent through the client of the route matching
+/// their name, preferring exact names then the longest patterns, and through the default client
+/// if no route matches. Clients can connect to different brokers, e.g. to isolate bulk jobs from
+/// latency-sensitive ones.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// extern crate futures;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+/// extern crate tokio;
+///
+/// use batch::{job, Client, Router};
+/// use futures::Future;
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_name = "reports.generate"]
+/// #[job_routing_key = "reports"]
+/// struct GenerateReport;
+///
+/// # fn main() {
+/// let task = Client::builder()
+///     .connection_url("amqp://realtime.example.com/%2f")
+///     .build()
+///     .join(
+///         Client::builder()
+///             .connection_url("amqp://bulk.example.com/%2f")
+///             .build(),
+///     )
+///     .and_then(|(realtime, bulk)| {
+///         let router = Router::new(realtime).route("reports.*", bulk);
+///         router.send(job(GenerateReport))
+///     })
+///     .map(|handle| println!("Sent report job {}", handle.id()))
+///     .map_err(|e| eprintln!("Couldn't send job: {}", e));
+/// # if false {
+/// tokio::run(task);
+/// # }
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Router {
+    default: Client,
+    routes: Vec<(String, Client)>,
+}
+
+impl Router {
+    /// Create a new `Router`, sending the jobs that don't match any route through the given
+    /// client.
+    pub fn new(default: Client) -> Self {
+        Router {
+            default,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Send the jobs whose name matches the given pattern through the given client. Chainable.
+    pub fn route(mut self, pattern: &str, client: Client) -> Self {
+        self.routes.push((pattern.to_string(), client));
+        self
+    }
+
+    /// Return the client the job with the given name is sent through.
+    pub fn client(&self, job_name: &str) -> &Client {
+        let mut best: Option<(usize, &Client)> = None;
+        for &(ref pattern, ref client) in &self.routes {
+            let score = match score(pattern, job_name) {
+                Some(score) => score,
+                None => continue,
+            };
+            if best.map_or(true, |(best, _)| score > best) {
+                best = Some((score, client));
+            }
+        }
+        best.map_or(&self.default, |(_, client)| client)
+    }
+
+    /// Send a job through the client matching its name.
+    pub fn send<T>(&self, query: Query<T>) -> Box<Future<Item = JobHandle, Error = Error> + Send>
+    where
+        T: Job + Send + 'static,
+    {
+        query.send(self.client(T::name()))
+    }
+}
+
+/// Return how closely a pattern matches a job name, if it does.
+pub(crate) fn score(pattern: &str, job_name: &str) -> Option<usize> {
+    if pattern == job_name {
+        Some(usize::max_value())
+    } else if pattern.ends_with('*') && job_name.starts_with(&pattern[..pattern.len() - 1]) {
+        Some(pattern.len())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_names_win_over_longer_patterns() {
+        assert_eq!(score("reports.generate", "reports.generate"), Some(usize::max_value()));
+        assert_eq!(score("reports.*", "reports.generate"), Some(9));
+        assert!(score("reports.gen*", "reports.generate") > score("reports.*", "reports.generate"));
+        assert_eq!(score("*", "emails.send"), Some(1));
+        assert_eq!(score("reports.*", "emails.send"), None);
+        assert_eq!(score("reports", "reports.generate"), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! Routing of the jobs decided by the client when they are published.
+
+use std::fmt;
+
+use lapin::channel::BasicProperties;
+use lapin::types::AMQPValue;
+
+/// The exchange & routing key a job is published with.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Route {
+    exchange: String,
+    routing_key: String,
+}
+
+impl Route {
+    /// Create a new `Route` publishing jobs to the given exchange with the given routing key.
+    pub fn new(exchange: &str, routing_key: &str) -> Self {
+        Route {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+        }
+    }
+
+    /// Return the exchange of this route.
+    pub fn exchange(&self) -> &str {
+        &self.exchange
+    }
+
+    /// Return the routing key of this route.
+    pub fn routing_key(&self) -> &str {
+        &self.routing_key
+    }
+}
+
+/// The properties of a job being published, given to a `RoutingStrategy`.
+#[derive(Debug)]
+pub struct Properties<'a> {
+    exchange: &'a str,
+    routing_key: &'a str,
+    properties: &'a BasicProperties,
+}
+
+impl<'a> Properties<'a> {
+    pub(crate) fn new(
+        exchange: &'a str,
+        routing_key: &'a str,
+        properties: &'a BasicProperties,
+    ) -> Self {
+        Properties {
+            exchange,
+            routing_key,
+            properties,
+        }
+    }
+
+    /// Return the name of the job.
+    pub fn job(&self) -> Option<&'a str> {
+        self.header("task")
+    }
+
+    /// Return the ID of the job.
+    pub fn id(&self) -> Option<&'a str> {
+        self.properties.correlation_id.as_ref().map(String::as_str)
+    }
+
+    /// Return the value of the given header of the job, e.g. one set with
+    /// [`Query::header`](struct.Query.html#method.header).
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        match self.properties.headers.as_ref()?.get(name) {
+            Some(&AMQPValue::LongString(ref value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Return the route the job was given by its definition or its query.
+    pub fn route(&self) -> Route {
+        Route::new(self.exchange, self.routing_key)
+    }
+}
+
+/// A strategy choosing the route of each job when it is published, see
+/// [`ClientBuilder::routing_strategy`](struct.ClientBuilder.html#method.routing_strategy).
+///
+/// Strategies can spread the jobs over several queues depending on their tenant, their size, etc.
+/// The exchanges & queues they route jobs to must be declared by the workers.
+///
+/// # Example
+///
+/// ```
+/// use batch::{Properties, Route, RoutingStrategy};
+///
+/// /// Routes the jobs of each tenant to one of 4 shards of their queue.
+/// #[derive(Debug)]
+/// struct ShardByTenant;
+///
+/// impl RoutingStrategy for ShardByTenant {
+///     fn route(&self, properties: &Properties, _payload: &[u8]) -> Route {
+///         let route = properties.route();
+///         match properties.header("tenant_id") {
+///             Some(tenant) => {
+///                 let shard = tenant.bytes().map(u32::from).sum::<u32>() % 4;
+///                 let routing_key = format!("{}.{}", route.routing_key(), shard);
+///                 Route::new(route.exchange(), &routing_key)
+///             }
+///             None => route,
+///         }
+///     }
+/// }
+/// ```
+pub trait RoutingStrategy: fmt::Debug + Send + Sync {
+    /// Return the route of the job with the given properties & payload.
+    ///
+    /// The payload is the serialized job, before it is uploaded to a `PayloadStore`.
+    fn route(&self, properties: &Properties, payload: &[u8]) -> Route;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lapin::types::FieldTable;
+
+    #[test]
+    fn properties_expose_the_job() {
+        let mut headers = FieldTable::new();
+        headers.insert("task".to_string(), AMQPValue::LongString("send-email".to_string()));
+        headers.insert("attempt".to_string(), AMQPValue::LongUInt(1));
+        let properties = BasicProperties {
+            correlation_id: Some("42".to_string()),
+            headers: Some(headers),
+            ..BasicProperties::default()
+        };
+        let properties = Properties::new("emails", "send", &properties);
+        assert_eq!(properties.job(), Some("send-email"));
+        assert_eq!(properties.id(), Some("42"));
+        assert_eq!(properties.header("attempt"), None);
+        assert_eq!(properties.route(), Route::new("emails", "send"));
+    }
+}
@@ -0,0 +1,281 @@
+//! Restrictions of the system calls available to the processes executing jobs.
+
+use std::io;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+use libc;
+
+/// The restrictions applied to the processes executing a job, set with the `job_sandbox`
+/// attribute when deriving `Job`.
+///
+/// Sandboxes are applied with a seccomp filter by the executor process itself, before it
+/// executes its job, and are only supported on Linux (x86-64 & AArch64). Elsewhere, and for the
+/// jobs executed in the worker process, they are ignored with a warning. Forbidden system calls
+/// fail with `EACCES`, which the job's handler sees as a regular I/O error.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::{Job, Sandbox};
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job_routing_key = "thumbnails"]
+/// #[job_sandbox = "no_network"]
+/// struct ResizeImage {
+///     path: String,
+/// }
+///
+/// # fn main() {
+/// assert_eq!(ResizeImage::sandbox(), Sandbox::new().deny_network());
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sandbox {
+    #[serde(default)]
+    deny_network: bool,
+}
+
+impl Sandbox {
+    /// Create a new `Sandbox` restricting nothing.
+    pub fn new() -> Self {
+        Sandbox::default()
+    }
+
+    /// Forbid the creation of sockets other than Unix domain sockets.
+    ///
+    /// `io_uring` is forbidden as well, since it can create sockets without `socket(2)`.
+    pub fn deny_network(mut self) -> Self {
+        self.deny_network = true;
+        self
+    }
+
+    /// Return `true` if this sandbox restricts something.
+    pub fn is_enabled(&self) -> bool {
+        *self != Sandbox::default()
+    }
+
+    /// Apply this sandbox to the current process and all its threads.
+    ///
+    /// This can't be undone: the process and its children are also prevented from gaining new
+    /// privileges, e.g. by executing setuid programs.
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        self.program().install()
+    }
+
+    /// Return the seccomp filter applying this sandbox.
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn program(&self) -> seccomp::Program {
+        let mut program = seccomp::Program::new();
+        if self.deny_network {
+            program.deny_unless_arg(libc::SYS_socket, 0, libc::AF_UNIX as u32);
+            for &syscall in seccomp::IO_URING {
+                program.deny(syscall);
+            }
+        }
+        program
+    }
+
+    /// Apply this sandbox to the current process and all its threads.
+    #[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if self.is_enabled() {
+            warn!("Sandboxes are only supported on Linux (x86-64 & AArch64), ignoring it");
+        }
+        Ok(())
+    }
+}
+
+/// Seccomp filters, in classic BPF.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod seccomp {
+    use std::io;
+
+    use libc;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    // Opcodes of the classic BPF instructions, see `linux/filter.h`.
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_JGE_K: u16 = 0x35;
+    const BPF_RET_K: u16 = 0x06;
+
+    // See `linux/seccomp.h`.
+    const SECCOMP_SET_MODE_FILTER: u32 = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: u32 = 1;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+
+    /// Offsets of the fields of `struct seccomp_data`, the input of seccomp filters.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+    const ARGS_OFFSET: u32 = 16;
+
+    /// System call numbers from this bit on belong to the x32 ABI, which would bypass filters.
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// `io_uring_setup`, `io_uring_enter` & `io_uring_register`, which have the same numbers on
+    /// all architectures.
+    pub const IO_URING: &[i64] = &[425, 426, 427];
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Debug)]
+    struct SockFprog {
+        len: u16,
+        filter: *const SockFilter,
+    }
+
+    /// A seccomp filter allowing every system call but the denied ones.
+    #[derive(Debug)]
+    pub struct Program {
+        instructions: Vec<SockFilter>,
+    }
+
+    impl Program {
+        /// Create a filter denying the system calls of other architectures & ABIs.
+        pub fn new() -> Self {
+            let deny = deny(libc::EACCES as u32);
+            Program {
+                instructions: vec![
+                    statement(BPF_LD_W_ABS, ARCH_OFFSET),
+                    jump(BPF_JEQ_K, AUDIT_ARCH, 1, 0),
+                    deny,
+                    statement(BPF_LD_W_ABS, NR_OFFSET),
+                    jump(BPF_JGE_K, X32_SYSCALL_BIT, 0, 1),
+                    deny,
+                ],
+            }
+        }
+
+        /// Deny the given system call unless the lower 32 bits of its argument at `index` are
+        /// `value`.
+        pub fn deny_unless_arg(&mut self, syscall: i64, index: u32, value: u32) {
+            self.instructions.extend_from_slice(&[
+                statement(BPF_LD_W_ABS, NR_OFFSET),
+                jump(BPF_JEQ_K, syscall as u32, 0, 3),
+                // Arguments are 64 bits wide, and the supported architectures are little-endian.
+                statement(BPF_LD_W_ABS, ARGS_OFFSET + 8 * index),
+                jump(BPF_JEQ_K, value, 1, 0),
+                deny(libc::EACCES as u32),
+            ]);
+        }
+
+        /// Deny the given system call.
+        pub fn deny(&mut self, syscall: i64) {
+            self.instructions.extend_from_slice(&[
+                statement(BPF_LD_W_ABS, NR_OFFSET),
+                jump(BPF_JEQ_K, syscall as u32, 0, 1),
+                deny(libc::EACCES as u32),
+            ]);
+        }
+
+        /// Return `true` if this filter checks for the given system call.
+        #[cfg(test)]
+        pub fn checks(&self, syscall: i64) -> bool {
+            self.instructions
+                .iter()
+                .any(|instruction| instruction.code == BPF_JEQ_K && instruction.k == syscall as u32)
+        }
+
+        /// Install this filter on all the threads of the current process.
+        pub fn install(mut self) -> io::Result<()> {
+            self.instructions.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+            let program = SockFprog {
+                len: self.instructions.len() as u16,
+                filter: self.instructions.as_ptr(),
+            };
+            unsafe {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let result = libc::syscall(
+                    libc::SYS_seccomp,
+                    SECCOMP_SET_MODE_FILTER,
+                    SECCOMP_FILTER_FLAG_TSYNC,
+                    &program as *const SockFprog,
+                );
+                if result != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn statement(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    fn deny(errno: u32) -> SockFilter {
+        statement(BPF_RET_K, SECCOMP_RET_ERRNO | errno)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn denied_calls_return_an_error() {
+            let mut program = Program::new();
+            program.deny_unless_arg(41, 0, 1);
+            let instructions = &program.instructions[6..];
+            assert_eq!(instructions.len(), 5);
+            // The jump skips the argument check when the system call doesn't match.
+            assert_eq!((instructions[1].k, instructions[1].jf), (41, 3));
+            assert_eq!(instructions[2].k, ARGS_OFFSET);
+            assert_eq!(instructions[4].k, SECCOMP_RET_ERRNO | libc::EACCES as u32);
+
+            let mut program = Program::new();
+            program.deny(425);
+            let instructions = &program.instructions[6..];
+            assert_eq!((instructions[1].k, instructions[1].jf), (425, 1));
+            assert_eq!(instructions[2].k, SECCOMP_RET_ERRNO | libc::EACCES as u32);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denying_the_network_denies_io_uring() {
+        let program = Sandbox::new().deny_network().program();
+        assert!(program.checks(libc::SYS_socket));
+        assert!(program.checks(libc::SYS_io_uring_setup));
+        assert!(program.checks(libc::SYS_io_uring_enter));
+        assert!(!Sandbox::new().program().checks(libc::SYS_io_uring_setup));
+    }
+}
@@ -0,0 +1,267 @@
+//! Graceful shutdown of workers.
+//!
+//! A worker stops fetching new jobs once it is quiet, either on request of a `ShutdownHandle` or
+//! by itself, e.g. once it received as many jobs as requested or was idle for long enough. It
+//! then waits for its running jobs to complete, interrupting them once its shutdown timeout has
+//! elapsed if it was terminated.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::task::AtomicTask;
+use futures::{future, Async, Future, Poll};
+
+use clock::Clock;
+use error;
+
+/// A handle to gracefully shut a `Worker` down.
+///
+/// See [`Worker::shutdown_handle`](struct.Worker.html#method.shutdown_handle).
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    inner: Arc<Shutdown>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(inner: Arc<Shutdown>) -> Self {
+        ShutdownHandle { inner }
+    }
+
+    /// Stop fetching new jobs, and let the running ones complete.
+    ///
+    /// The future returned by `Worker::run` resolves once all the running jobs have completed.
+    /// Prefetched jobs that weren't started yet are delivered again once the worker disconnects.
+    pub fn quiet(&self) {
+        info!("Shutting down, not fetching new jobs anymore");
+        self.inner.quiet();
+    }
+
+    /// Stop fetching new jobs, and interrupt the running ones if they don't complete in time.
+    ///
+    /// Jobs still running once the shutdown timeout has elapsed are killed and put back in their
+    /// queue, to be executed again by another worker. See
+    /// [`WorkerBuilder::shutdown_timeout`](struct.WorkerBuilder.html#method.shutdown_timeout).
+    pub fn terminate(&self) {
+        info!(
+            "Shutting down, interrupting jobs still running in {:?}",
+            self.inner.timeout
+        );
+        self.inner.terminate();
+    }
+}
+
+/// Shutdown state shared by a `Worker` and its `ShutdownHandle`s.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    timeout: Duration,
+    clock: Arc<Clock>,
+    quiet: AtomicBool,
+    deadline: Mutex<Option<Instant>>,
+    running: AtomicUsize,
+    active_at: Mutex<Instant>,
+    remaining_jobs: Mutex<Option<usize>>,
+    consumer: AtomicTask,
+    drained: AtomicTask,
+}
+
+impl Shutdown {
+    pub fn new(timeout: Duration, clock: Arc<Clock>) -> Self {
+        let now = clock.now();
+        Shutdown {
+            timeout,
+            clock,
+            quiet: AtomicBool::new(false),
+            deadline: Mutex::new(None),
+            running: AtomicUsize::new(0),
+            active_at: Mutex::new(now),
+            remaining_jobs: Mutex::new(None),
+            consumer: AtomicTask::new(),
+            drained: AtomicTask::new(),
+        }
+    }
+
+    pub fn quiet(&self) {
+        self.quiet.store(true, Ordering::SeqCst);
+        self.consumer.notify();
+    }
+
+    pub fn terminate(&self) {
+        {
+            let mut deadline = self.deadline.lock().unwrap();
+            if deadline.is_none() {
+                *deadline = Some(self.clock.now() + self.timeout);
+            }
+        }
+        self.quiet();
+    }
+
+    /// Wake the current task up once the worker is quiet.
+    pub fn register_consumer(&self) {
+        self.consumer.register();
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.quiet.load(Ordering::SeqCst)
+    }
+
+    /// Return `true` once running jobs should be interrupted.
+    pub fn should_interrupt(&self) -> bool {
+        self.deadline
+            .lock()
+            .unwrap()
+            .map_or(false, |deadline| self.clock.now() >= deadline)
+    }
+
+    pub fn start_job(&self) {
+        self.running.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn finish_job(&self) {
+        self.touch();
+        if self.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.drained.notify();
+        }
+    }
+
+    /// Stop consuming once the given number of jobs were received, see `Worker::run_n`.
+    pub fn limit_jobs(&self, max_jobs: usize) {
+        *self.remaining_jobs.lock().unwrap() = Some(max_jobs);
+        if max_jobs == 0 {
+            self.quiet();
+        }
+    }
+
+    /// Count a job received by a consumer, returning `false` if it exceeds the limit of the
+    /// worker and must be requeued.
+    pub fn take_job(&self) -> bool {
+        let mut remaining_jobs = self.remaining_jobs.lock().unwrap();
+        match *remaining_jobs {
+            Some(0) => false,
+            Some(ref mut remaining) => {
+                *remaining -= 1;
+                if *remaining == 0 {
+                    info!("Received the maximum number of jobs, stopping the worker");
+                    self.quiet();
+                }
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Record activity of the worker, i.e. a delivery or a completed job.
+    pub fn touch(&self) {
+        *self.active_at.lock().unwrap() = self.clock.now();
+    }
+
+    /// Return the time elapsed since the last activity of the worker, or `None` if jobs are
+    /// running.
+    pub fn idle_time(&self) -> Option<Duration> {
+        if self.running.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        Some(self.clock.now() - *self.active_at.lock().unwrap())
+    }
+}
+
+/// Resolves once all the running jobs have completed.
+pub(crate) struct Drained(pub Arc<Shutdown>);
+
+impl Future for Drained {
+    type Item = ();
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.drained.register();
+        if self.0.running.load(Ordering::SeqCst) == 0 {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+
+/// Stop consuming once the worker was idle for `idle_window`, see `Worker::run_until_drained`.
+pub(crate) fn drain(
+    shutdown: &Arc<Shutdown>,
+    idle_window: Duration,
+    clock: &Arc<Clock>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let shutdown = Arc::clone(shutdown);
+    let clock = Arc::clone(clock);
+    let task = future::loop_fn((), move |_| {
+        if shutdown.is_quiet() {
+            return future::Either::A(future::ok(future::Loop::Break(())));
+        }
+        let wait = match shutdown.idle_time() {
+            Some(idle) if idle >= idle_window => {
+                info!("No job received for {:?}, stopping the drained worker", idle);
+                shutdown.quiet();
+                return future::Either::A(future::ok(future::Loop::Break(())));
+            }
+            Some(idle) => idle_window - idle,
+            None => idle_window,
+        };
+        let task = clock.delay(clock.now() + wait).map(future::Loop::Continue);
+        future::Either::B(task)
+    });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::MockClock;
+
+    #[test]
+    fn shutdown_interrupts_jobs_after_timeout() {
+        let clock = MockClock::new();
+        let shutdown = Shutdown::new(Duration::from_secs(30), Arc::new(clock.clone()));
+        assert!(!shutdown.is_quiet());
+        shutdown.quiet();
+        assert!(shutdown.is_quiet());
+        assert!(!shutdown.should_interrupt());
+        shutdown.terminate();
+        assert!(!shutdown.should_interrupt());
+        clock.advance(Duration::from_secs(30));
+        assert!(shutdown.should_interrupt());
+
+        let mut drained = Drained(Arc::new(shutdown));
+        drained.0.start_job();
+        let poll = future::lazy(|| Ok::<_, ()>(drained.poll().unwrap())).wait();
+        assert_eq!(poll, Ok(Async::NotReady));
+        drained.0.finish_job();
+        let poll = future::lazy(|| Ok::<_, ()>(drained.poll().unwrap())).wait();
+        assert_eq!(poll, Ok(Async::Ready(())));
+    }
+
+    #[test]
+    fn idle_time_restarts_with_activity() {
+        let clock = MockClock::new();
+        let shutdown = Shutdown::new(Duration::from_secs(30), Arc::new(clock.clone()));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(shutdown.idle_time(), Some(Duration::from_secs(5)));
+        shutdown.start_job();
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(shutdown.idle_time(), None);
+        shutdown.finish_job();
+        assert_eq!(shutdown.idle_time(), Some(Duration::from_secs(0)));
+        clock.advance(Duration::from_secs(2));
+        shutdown.touch();
+        assert_eq!(shutdown.idle_time(), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn job_limit_stops_consumption() {
+        let shutdown = Shutdown::new(Duration::from_secs(30), Arc::new(MockClock::new()));
+        assert!(shutdown.take_job());
+        shutdown.limit_jobs(2);
+        assert!(shutdown.take_job());
+        assert!(!shutdown.is_quiet());
+        assert!(shutdown.take_job());
+        assert!(shutdown.is_quiet());
+        assert!(!shutdown.take_job());
+    }
+}
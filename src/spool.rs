@@ -0,0 +1,168 @@
+//! Local spool of jobs that couldn't be published.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::Future;
+use lapin::channel::{BasicProperties, BasicPublishOptions};
+
+use blocking::Blocking;
+use de;
+use error::{Error, ErrorKind, Result};
+use rabbitmq::PropertiesDef;
+use ser;
+
+/// A job waiting in the spool to be published.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub exchange: String,
+    pub routing_key: String,
+    pub mandatory: bool,
+    pub immediate: bool,
+    #[serde(with = "PropertiesDef")]
+    pub properties: BasicProperties,
+    pub data: Vec<u8>,
+}
+
+impl Entry {
+    /// Return the options the job was originally published with.
+    pub fn options(&self) -> BasicPublishOptions {
+        BasicPublishOptions {
+            mandatory: self.mandatory,
+            immediate: self.immediate,
+            ..Default::default()
+        }
+    }
+}
+
+/// An append-only file storing one serialized `Entry` per line.
+#[derive(Debug)]
+pub(crate) struct Spool {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Spool {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Spool {
+            path: path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Append a job to the spool, making sure it is written to disk before returning.
+    pub fn push(&self, entry: &Entry) -> Result<()> {
+        let mut line = ser::to_vec(entry).map_err(ErrorKind::Serialization)?;
+        line.push(b'\n');
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(ErrorKind::Io)?;
+        file.write_all(&line).map_err(ErrorKind::Io)?;
+        file.sync_data().map_err(ErrorKind::Io)?;
+        Ok(())
+    }
+
+    /// Remove all the jobs from the spool and return them.
+    ///
+    /// Lines that can't be deserialized (e.g. written partially before a crash) are skipped.
+    pub fn take(&self) -> Result<Vec<Entry>> {
+        let _guard = self.lock.lock().unwrap();
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(ErrorKind::Io(e).into()),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(ErrorKind::Io)?;
+            match de::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => warn!("Skipping invalid entry in spool {:?}: {}", self.path, e),
+            }
+        }
+        fs::remove_file(&self.path).map_err(ErrorKind::Io)?;
+        Ok(entries)
+    }
+}
+
+/// A `Spool` written & read on its own thread, so that waiting for the disk doesn't block the
+/// reactor.
+#[derive(Clone, Debug)]
+pub(crate) struct SpoolHandle {
+    spool: Arc<Spool>,
+    blocking: Blocking,
+}
+
+impl SpoolHandle {
+    pub fn new(spool: Spool) -> Self {
+        SpoolHandle {
+            spool: Arc::new(spool),
+            blocking: Blocking::new("batch-spool"),
+        }
+    }
+
+    /// Append a job to the spool, resolving once it is written to disk.
+    pub fn push(&self, entry: Entry) -> Box<Future<Item = (), Error = Error> + Send> {
+        let spool = Arc::clone(&self.spool);
+        self.blocking.run(move || spool.push(&entry))
+    }
+
+    /// Remove all the jobs from the spool, resolving to them.
+    pub fn take(&self) -> Box<Future<Item = Vec<Entry>, Error = Error> + Send> {
+        let spool = Arc::clone(&self.spool);
+        self.blocking.run(move || spool.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn push_then_take() {
+        let path = env::temp_dir().join(format!("batch-spool-test-{}", process::id()));
+        let spool = Spool::new(&path);
+        assert!(spool.take().unwrap().is_empty());
+        for routing_key in &["first", "second"] {
+            let entry = Entry {
+                exchange: "".into(),
+                routing_key: routing_key.to_string(),
+                mandatory: false,
+                immediate: false,
+                properties: BasicProperties::default(),
+                data: b"{}".to_vec(),
+            };
+            spool.push(&entry).unwrap();
+        }
+        let entries = spool.take().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].routing_key, "first");
+        assert_eq!(entries[1].routing_key, "second");
+        assert!(spool.take().unwrap().is_empty());
+    }
+
+    #[test]
+    fn push_then_take_off_the_reactor() {
+        let path = env::temp_dir().join(format!("batch-spool-handle-test-{}", process::id()));
+        let spool = SpoolHandle::new(Spool::new(&path));
+        let entry = Entry {
+            exchange: "".into(),
+            routing_key: "first".into(),
+            mandatory: false,
+            immediate: false,
+            properties: BasicProperties::default(),
+            data: b"{}".to_vec(),
+        };
+        spool.push(entry).wait().unwrap();
+        let entries = spool.take().wait().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].routing_key, "first");
+    }
+}
@@ -0,0 +1,212 @@
+//! Tracking of the state of jobs, from their publication to their completion.
+//!
+//! Clients and workers configured with a `StatusTracker` record each transition of the jobs they
+//! send & execute, so that applications can tell their users how far along their job is.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::Future;
+
+use blocking::Blocking;
+use error::{Error, Result};
+
+/// The state of a job, see [`StatusTracker`](trait.StatusTracker.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// The job was published, or requeued to be retried, and waits for a worker.
+    Pending,
+    /// A worker is executing the job.
+    Running,
+    /// The job completed successfully.
+    Succeeded,
+    /// The job failed and won't be retried.
+    Failed,
+}
+
+impl JobState {
+    /// Return `true` if the job won't change state anymore.
+    pub fn is_finished(&self) -> bool {
+        match *self {
+            JobState::Succeeded | JobState::Failed => true,
+            JobState::Pending | JobState::Running => false,
+        }
+    }
+}
+
+/// A store of the state of jobs, by ID.
+///
+/// See [`ClientBuilder::status_tracker`] & [`WorkerBuilder::status_tracker`]. Clients record that
+/// the jobs they send are pending, and workers record when they start executing them and how they
+/// complete. The tracker must be shared by the clients & workers, e.g. backed by Redis or by a
+/// database table. Errors are logged, and never fail the jobs.
+///
+/// [`ClientBuilder::status_tracker`]: struct.ClientBuilder.html#method.status_tracker
+/// [`WorkerBuilder::status_tracker`]: struct.WorkerBuilder.html#method.status_tracker
+///
+/// # Example
+///
+/// ```
+/// use batch::{Error, JobState, StatusTracker};
+/// use std::collections::HashMap;
+/// use std::sync::Mutex;
+///
+/// // A tracker is usually a Redis hash, or a database table:
+/// // INSERT INTO job_states (id, state) VALUES ($1, $2)
+/// //     ON CONFLICT (id) DO UPDATE SET state = $2
+/// #[derive(Debug, Default)]
+/// struct Table(Mutex<HashMap<String, String>>);
+///
+/// impl StatusTracker for Table {
+///     fn set(&self, job_id: &str, state: JobState) -> Result<(), Error> {
+///         let state = format!("{:?}", state);
+///         self.0.lock().unwrap().insert(job_id.to_string(), state);
+///         Ok(())
+///     }
+///
+///     fn get(&self, job_id: &str) -> Result<Option<JobState>, Error> {
+///         let state = match self.0.lock().unwrap().get(job_id).map(String::as_str) {
+///             Some("Pending") => Some(JobState::Pending),
+///             Some("Running") => Some(JobState::Running),
+///             Some("Succeeded") => Some(JobState::Succeeded),
+///             Some("Failed") => Some(JobState::Failed),
+///             _ => None,
+///         };
+///         Ok(state)
+///     }
+/// }
+/// ```
+pub trait StatusTracker: fmt::Debug + Send + Sync {
+    /// Record the new state of the job with the given ID.
+    fn set(&self, job_id: &str, state: JobState) -> Result<()>;
+
+    /// Return the state of the job with the given ID, if it is known.
+    fn get(&self, job_id: &str) -> Result<Option<JobState>>;
+}
+
+/// A `StatusTracker` remembering the state of the most recent jobs in memory.
+///
+/// States are lost when the process stops, and aren't shared with other processes: this is only
+/// useful when the clients & workers run in the same process, e.g. in tests.
+///
+/// # Example
+///
+/// ```
+/// use batch::{JobState, MemoryStatusTracker, StatusTracker};
+///
+/// let tracker = MemoryStatusTracker::new(2);
+/// tracker.set("a", JobState::Pending).unwrap();
+/// tracker.set("b", JobState::Running).unwrap();
+/// tracker.set("a", JobState::Succeeded).unwrap();
+/// tracker.set("c", JobState::Pending).unwrap();
+/// assert_eq!(tracker.get("a").unwrap(), None);
+/// assert_eq!(tracker.get("b").unwrap(), Some(JobState::Running));
+/// ```
+#[derive(Debug)]
+pub struct MemoryStatusTracker {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    states: HashMap<String, JobState>,
+    order: VecDeque<String>,
+}
+
+impl MemoryStatusTracker {
+    /// Create a new `MemoryStatusTracker` remembering the state of up to `capacity` jobs.
+    pub fn new(capacity: usize) -> Self {
+        MemoryStatusTracker {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+}
+
+impl Default for MemoryStatusTracker {
+    /// Create a new `MemoryStatusTracker` remembering the state of up to 10,000 jobs.
+    fn default() -> Self {
+        MemoryStatusTracker::new(10_000)
+    }
+}
+
+impl StatusTracker for MemoryStatusTracker {
+    fn set(&self, job_id: &str, state: JobState) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.states.insert(job_id.to_string(), state).is_none() {
+            inner.order.push_back(job_id.to_string());
+        }
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.states.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, job_id: &str) -> Result<Option<JobState>> {
+        Ok(self.inner.lock().unwrap().states.get(job_id).cloned())
+    }
+}
+
+/// A `StatusTracker` called on its own thread, so that a slow tracker doesn't block the reactor.
+///
+/// States are recorded in the order they were tracked.
+#[derive(Clone, Debug)]
+pub(crate) struct TrackerHandle {
+    tracker: Arc<StatusTracker>,
+    blocking: Blocking,
+}
+
+impl TrackerHandle {
+    pub fn new(tracker: Arc<StatusTracker>) -> Self {
+        TrackerHandle {
+            tracker,
+            blocking: Blocking::new("batch-status"),
+        }
+    }
+
+    /// Record the new state of the job with the given ID, resolving once it is recorded.
+    ///
+    /// Errors are logged, and never fail the returned `Future`.
+    pub fn set(
+        &self,
+        job_id: &str,
+        state: JobState,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let (tracker, job_id) = (Arc::clone(&self.tracker), job_id.to_string());
+        self.blocking.run(move || {
+            record(&*tracker, &job_id, state);
+            Ok(())
+        })
+    }
+
+    /// Record the new state of the job with the given ID, without waiting for it.
+    pub fn record(&self, job_id: &str, state: JobState) {
+        let (tracker, job_id) = (Arc::clone(&self.tracker), job_id.to_string());
+        self.blocking.spawn(move || record(&*tracker, &job_id, state));
+    }
+
+    /// Return the state of the job with the given ID, if it is known.
+    pub fn get(&self, job_id: &str) -> Box<Future<Item = Option<JobState>, Error = Error> + Send> {
+        let (tracker, job_id) = (Arc::clone(&self.tracker), job_id.to_string());
+        self.blocking.run(move || tracker.get(&job_id))
+    }
+}
+
+/// Record the new state of a job in the given tracker, if any, without waiting for it.
+pub(crate) fn track(tracker: Option<&TrackerHandle>, job_id: &str, state: JobState) {
+    if let Some(tracker) = tracker {
+        tracker.record(job_id, state);
+    }
+}
+
+/// Record the new state of a job in the given tracker, logging errors.
+fn record(tracker: &StatusTracker, job_id: &str, state: JobState) {
+    if let Err(e) = tracker.set(job_id, state) {
+        error!("[{}] Couldn't record job state {:?}: {}", job_id, state, e);
+    }
+}
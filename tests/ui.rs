@@ -0,0 +1,21 @@
+//! Tests of the code generated by `#[derive(Job)]`, and of the errors it reports.
+//!
+//! The expected errors depend on the diagnostics of the compiler they were generated with, so they
+//! are only checked when `BATCH_UI_TESTS` is set, which CI does with the compiler pinned in
+//! `.travis.yml`. Run with `TRYBUILD=overwrite` to update them after changing them on purpose, or
+//! after upgrading that compiler.
+
+#![cfg(feature = "codegen")]
+
+extern crate trybuild;
+
+use std::env;
+
+#[test]
+fn ui() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/pass/*.rs");
+    if env::var_os("BATCH_UI_TESTS").is_some() {
+        cases.compile_fail("tests/ui/fail/*.rs");
+    }
+}
@@ -0,0 +1,13 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate serde;
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_routing_key = "uploads"]
+struct Upload<'a> {
+    name: &'a str,
+    data: &'a [u8],
+}
+
+fn main() {}
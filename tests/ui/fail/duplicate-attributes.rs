@@ -0,0 +1,13 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate serde;
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_routing_key = "emails"]
+#[job_timeout = "60"]
+#[job_routing_key = "mailer"]
+#[job_timeout = "120"]
+struct SendConfirmationEmail;
+
+fn main() {}
@@ -0,0 +1,12 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate serde;
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_routing_key = "search"]
+struct Index<D> {
+    document: D,
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate serde;
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_routing_key = "emails"]
+#[job_timeout = "soon"]
+#[job_retries = "-1"]
+#[job_priority = "hihg"]
+#[job_sandbox = "no_netwrok"]
+#[job_env(SMTP_HOST)]
+struct SendConfirmationEmail;
+
+fn main() {}
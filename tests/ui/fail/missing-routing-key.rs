@@ -0,0 +1,10 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate serde;
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_name = "batch-rs:send-confirmation-email"]
+struct SendConfirmationEmail;
+
+fn main() {}
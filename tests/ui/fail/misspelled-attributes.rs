@@ -0,0 +1,14 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate serde;
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_routing_key = "emails"]
+#[job_retry = "3"]
+#[with_priority = "high"]
+#[job_tiemout = "60"]
+#[job_unknown = "value"]
+struct SendConfirmationEmail;
+
+fn main() {}
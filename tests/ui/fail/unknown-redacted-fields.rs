@@ -0,0 +1,14 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate serde;
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_routing_key = "accounts"]
+#[job_redact = "pasword, api_key"]
+struct CreateAccount {
+    email: String,
+    password: String,
+}
+
+fn main() {}
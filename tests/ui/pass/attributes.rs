@@ -0,0 +1,58 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate serde;
+
+use batch::{Job, Priority, Sandbox};
+use std::time::Duration;
+
+/// Send a password reset email.
+#[derive(Serialize, Deserialize, Job)]
+#[job_name = "batch-rs:send-password-reset-email"]
+#[job_aliases = "batch-rs:password-reset, batch-rs:reset-password"]
+#[job_exchange = "batch.example"]
+#[job_routing_key = "emails"]
+#[job_timeout = "120"]
+#[job_retries = "0"]
+#[job_priority = "High"]
+#[job_env = "SMTP_HOST, SMTP_PASSWORD"]
+#[job_redact = "token"]
+#[job_sandbox = "no_network"]
+struct SendPasswordResetEmail {
+    to: String,
+    token: String,
+}
+
+/// Sign in to a third-party service.
+#[derive(Serialize, Deserialize, Job)]
+#[serde(rename_all = "camelCase")]
+#[job_routing_key = "sessions"]
+#[job_redact = "api_token, password"]
+struct SignIn {
+    api_token: String,
+    #[serde(rename = "secret")]
+    password: String,
+}
+
+#[cfg(any())]
+#[derive(Serialize, Deserialize, Job)]
+struct Disabled;
+
+fn main() {
+    assert_eq!(SendPasswordResetEmail::name(), "batch-rs:send-password-reset-email");
+    assert_eq!(
+        SendPasswordResetEmail::aliases(),
+        &["batch-rs:password-reset", "batch-rs:reset-password"]
+    );
+    assert_eq!(SendPasswordResetEmail::exchange(), "batch.example");
+    assert_eq!(SendPasswordResetEmail::routing_key(), "emails");
+    assert_eq!(SendPasswordResetEmail::timeout(), Some(Duration::from_secs(120)));
+    assert_eq!(SendPasswordResetEmail::retries(), 0);
+    assert_eq!(SendPasswordResetEmail::priority(), Priority::High);
+    assert_eq!(SendPasswordResetEmail::environment(), &["SMTP_HOST", "SMTP_PASSWORD"]);
+    assert_eq!(SendPasswordResetEmail::redacted_fields(), &["token"]);
+    assert_eq!(SendPasswordResetEmail::sandbox(), Sandbox::new().deny_network());
+    assert_eq!(SignIn::redacted_fields(), &["apiToken", "secret"]);
+}
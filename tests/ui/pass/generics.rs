@@ -0,0 +1,36 @@
+#[macro_use]
+extern crate batch;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate serde;
+
+use batch::Job;
+use std::marker::PhantomData;
+
+#[derive(Default)]
+struct Elasticsearch;
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_name = "search:reindex"]
+#[job_routing_key = "search"]
+struct Reindex<B> {
+    since: u64,
+    #[serde(skip)]
+    backend: PhantomData<B>,
+}
+
+#[derive(Serialize, Deserialize, Job)]
+#[job_name = "search:index"]
+#[job_routing_key = "search"]
+struct Index<D> {
+    document: D,
+}
+
+fn main() {
+    assert_eq!(Reindex::<Elasticsearch>::routing_key(), "search");
+    assert_eq!(Index::<String>::routing_key(), "search");
+    // All the instantiations share the name of the job.
+    assert_eq!(Index::<String>::name(), "search:index");
+    assert_eq!(Index::<u64>::name(), Index::<String>::name());
+}